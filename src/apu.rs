@@ -0,0 +1,1110 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Addresses of the sound registers
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR12: u16 = 0xFF12;
+pub const NR13: u16 = 0xFF13;
+pub const NR14: u16 = 0xFF14;
+// 0xFF15 would be a sweep register for channel 2, which doesn't have one
+const NR20: u16 = 0xFF15;
+pub const NR21: u16 = 0xFF16;
+pub const NR22: u16 = 0xFF17;
+pub const NR23: u16 = 0xFF18;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR32: u16 = 0xFF1C;
+pub const NR33: u16 = 0xFF1D;
+pub const NR34: u16 = 0xFF1E;
+// the same goes for 0xFF1F and channel 4
+const NR40: u16 = 0xFF1F;
+pub const NR41: u16 = 0xFF20;
+pub const NR42: u16 = 0xFF21;
+pub const NR43: u16 = 0xFF22;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+// nothing is connected between NR52 and wave RAM
+const UNUSED_START: u16 = 0xFF27;
+const UNUSED_END: u16 = 0xFF2F;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+// CGB only, the live output of channels 1 and 2, and of 3 and 4
+pub const PCM12: u16 = 0xFF76;
+pub const PCM34: u16 = 0xFF77;
+
+// Bits of NR52
+const POWER: u8 = 0b1000_0000;
+const NR52_UNUSED_BITS: u8 = 0b0111_0000;
+
+// Bits of NRx4
+const TRIGGER: u8 = 0b1000_0000;
+const LENGTH_ENABLE: u8 = 0b0100_0000;
+const FREQUENCY_HIGH: u8 = 0b0000_0111;
+
+// NRx1 holds the length to load in its low bits
+const LENGTH_LOAD: u8 = 0b0011_1111;
+
+// Bits of NR30 and NR32
+const WAVE_DAC_ENABLE: u8 = 0b1000_0000;
+const WAVE_VOLUME: u8 = 0b0110_0000;
+
+// Bits of NR43
+const NOISE_SHORT_MODE: u8 = 0b0000_1000;
+const NOISE_DIVISOR: u8 = 0b0000_0111;
+
+// Bits of NR10
+const SWEEP_PERIOD: u8 = 0b0111_0000;
+const SWEEP_NEGATE: u8 = 0b0000_1000;
+const SWEEP_SHIFT: u8 = 0b0000_0111;
+
+// Bits of NRx2, the DAC is on as long as any of the top five bits are set
+const ENVELOPE_INCREASE: u8 = 0b0000_1000;
+const ENVELOPE_PERIOD: u8 = 0b0000_0111;
+const DAC_ENABLE: u8 = 0b1111_1000;
+
+// the frame sequencer is clocked by the falling edge of this bit of the DIV
+// counter, which makes it tick at 512Hz
+const FRAME_SEQUENCER_BIT: u16 = 1 << 12;
+
+const MAX_LENGTH: u16 = 64;
+const MAX_WAVE_LENGTH: u16 = 256;
+const MAX_FREQUENCY: u16 = 2047;
+
+const WAVE_RAM_SIZE: usize = (WAVE_RAM_END - WAVE_RAM_START + 1) as usize;
+const WAVE_SAMPLES: u8 = WAVE_RAM_SIZE as u8 * 2;
+
+// the noise channel's base periods, picked by the low bits of NR43
+const NOISE_DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+const LFSR_SEED: u16 = 0x7FFF;
+
+// which of the 8 steps of a square wave are high for each duty setting
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// channels are mixed down by the number of channels hardware has rather
+// than the number implemented, so the mix won't get louder as more are added
+const CHANNEL_COUNT: f32 = 4.0;
+
+// Counts a channel down to silence when enabled, clocked by the frame
+// sequencer. Loading it sets how many clocks are left out of the maximum
+#[derive(Clone, Default)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+}
+
+impl LengthCounter {
+    fn load(self: &mut Self, max: u16, value: u16) {
+        self.counter = max - value;
+    }
+
+    fn trigger(self: &mut Self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    // returns true once the counter runs out and the channel should stop
+    fn clock(self: &mut Self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.counter);
+    }
+
+    fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.counter = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+// The volume envelope shared by the square and noise channels, which also
+// holds their DAC enable bits
+#[derive(Clone, Default)]
+struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn dac_enabled(self: &Self) -> bool {
+        self.register & DAC_ENABLE != 0
+    }
+
+    fn trigger(self: &mut Self) {
+        self.volume = self.register >> 4;
+        self.timer = self.register & ENVELOPE_PERIOD;
+    }
+
+    fn clock(self: &mut Self) {
+        let period = self.register & ENVELOPE_PERIOD;
+        if period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = period;
+            if self.register & ENVELOPE_INCREASE != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.register & ENVELOPE_INCREASE == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_u8(self.register);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.register = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// One of the two square wave channels, only channel 1 uses the sweep
+#[derive(Clone, Default)]
+struct SquareChannel {
+    enabled: bool,
+    sweep: u8,
+    duty_length: u8,
+    envelope: Envelope,
+    frequency: u16,
+    length: LengthCounter,
+    // cycles left until the wave moves on to its next step
+    frequency_timer: u16,
+    duty_position: u8,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+}
+
+impl SquareChannel {
+    fn dac_enabled(self: &Self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn period(self: &Self) -> u16 {
+        (MAX_FREQUENCY + 1 - self.frequency) * 4
+    }
+
+    fn step(self: &mut Self) {
+        if self.frequency_timer <= 1 {
+            self.frequency_timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        } else {
+            self.frequency_timer -= 1;
+        }
+    }
+
+    // the digital output, from 0 to 15
+    fn output(self: &Self) -> u8 {
+        let pattern = DUTY_PATTERNS[(self.duty_length >> 6) as usize];
+        if self.enabled && pattern & (0x80 >> self.duty_position) != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn write_length(self: &mut Self, value: u8) {
+        self.duty_length = value;
+        self.length.load(MAX_LENGTH, (value & LENGTH_LOAD) as u16);
+    }
+
+    fn write_envelope(self: &mut Self, value: u8) {
+        self.envelope.register = value;
+        // turning the DAC off silences the channel until it's triggered again
+        if !self.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_control(self: &mut Self, value: u8) {
+        self.frequency = (self.frequency & 0xFF) | (((value & FREQUENCY_HIGH) as u16) << 8);
+        self.length.enabled = value & LENGTH_ENABLE != 0;
+        if value & TRIGGER != 0 {
+            self.trigger();
+        }
+    }
+
+    fn trigger(self: &mut Self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger(MAX_LENGTH);
+        self.frequency_timer = self.period();
+        self.envelope.trigger();
+
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = self.sweep_period();
+        self.sweep_enabled = self.sweep & (SWEEP_PERIOD | SWEEP_SHIFT) != 0;
+        if self.sweep & SWEEP_SHIFT != 0 {
+            self.next_sweep_frequency();
+        }
+    }
+
+    fn clock_length(self: &mut Self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // a sweep period of 0 is treated as 8
+    fn sweep_period(self: &Self) -> u8 {
+        match (self.sweep & SWEEP_PERIOD) >> 4 {
+            0 => 8,
+            period => period,
+        }
+    }
+
+    fn clock_sweep(self: &mut Self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.sweep_timer = self.sweep_period();
+        if !self.sweep_enabled || self.sweep & SWEEP_PERIOD == 0 {
+            return;
+        }
+
+        let frequency = self.next_sweep_frequency();
+        if frequency <= MAX_FREQUENCY && self.sweep & SWEEP_SHIFT != 0 {
+            self.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // the new frequency is checked for overflow straight away
+            self.next_sweep_frequency();
+        }
+    }
+
+    // the frequency the next sweep step moves to, disabling the channel if
+    // it would overflow
+    fn next_sweep_frequency(self: &mut Self) -> u16 {
+        let delta = self.shadow_frequency >> (self.sweep & SWEEP_SHIFT);
+        let frequency = if self.sweep & SWEEP_NEGATE != 0 {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+        if frequency > MAX_FREQUENCY {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.sweep);
+        writer.write_u8(self.duty_length);
+        self.envelope.save_state(writer);
+        writer.write_u16(self.frequency);
+        self.length.save_state(writer);
+        writer.write_u16(self.frequency_timer);
+        writer.write_u8(self.duty_position);
+        writer.write_bool(self.sweep_enabled);
+        writer.write_u8(self.sweep_timer);
+        writer.write_u16(self.shadow_frequency);
+    }
+
+    fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.sweep = reader.read_u8()?;
+        self.duty_length = reader.read_u8()?;
+        self.envelope.load_state(reader)?;
+        self.frequency = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.frequency_timer = reader.read_u16()?;
+        self.duty_position = reader.read_u8()? % 8;
+        self.sweep_enabled = reader.read_bool()?;
+        self.sweep_timer = reader.read_u8()?;
+        self.shadow_frequency = reader.read_u16()?;
+        if self.frequency > MAX_FREQUENCY {
+            return Err(StateError::InvalidValue);
+        }
+        Ok(())
+    }
+}
+
+// Channel 3, which plays back the 32 4-bit samples held in wave RAM
+#[derive(Clone, Default)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length: LengthCounter,
+    volume_code: u8,
+    frequency: u16,
+    frequency_timer: u16,
+    // which of the 32 samples is playing
+    position: u8,
+    ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    // the wave channel steps twice as fast as the square channels
+    fn period(self: &Self) -> u16 {
+        (MAX_FREQUENCY + 1 - self.frequency) * 2
+    }
+
+    fn step(self: &mut Self) {
+        if self.frequency_timer <= 1 {
+            self.frequency_timer = self.period();
+            self.position = (self.position + 1) % WAVE_SAMPLES;
+        } else {
+            self.frequency_timer -= 1;
+        }
+    }
+
+    // the sample at the current position, high nibble first, shifted down by
+    // the volume setting where 0 mutes the channel
+    fn output(self: &Self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.ram[(self.position / 2) as usize];
+        let sample = if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        match self.volume_code {
+            0 => 0,
+            code => sample >> (code - 1),
+        }
+    }
+
+    fn write_dac(self: &mut Self, value: u8) {
+        self.dac_enabled = value & WAVE_DAC_ENABLE != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn write_control(self: &mut Self, value: u8) {
+        self.frequency = (self.frequency & 0xFF) | (((value & FREQUENCY_HIGH) as u16) << 8);
+        self.length.enabled = value & LENGTH_ENABLE != 0;
+        if value & TRIGGER != 0 {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(MAX_WAVE_LENGTH);
+            self.frequency_timer = self.period();
+            self.position = 0;
+        }
+    }
+
+    fn clock_length(self: &mut Self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        self.length.save_state(writer);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.frequency_timer);
+        writer.write_u8(self.position);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.volume_code = reader.read_u8()? & 0x03;
+        self.frequency = reader.read_u16()?;
+        self.frequency_timer = reader.read_u16()?;
+        self.position = reader.read_u8()? % WAVE_SAMPLES;
+        reader.read_bytes(&mut self.ram)?;
+        if self.frequency > MAX_FREQUENCY {
+            return Err(StateError::InvalidValue);
+        }
+        Ok(())
+    }
+}
+
+// Channel 4, which plays pseudo-random noise from a linear feedback shift
+// register
+#[derive(Clone, Default)]
+struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    // the clock shift, width and divisor bits of NR43
+    polynomial: u8,
+    frequency_timer: u16,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    fn dac_enabled(self: &Self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn period(self: &Self) -> u16 {
+        let divisor = NOISE_DIVISORS[(self.polynomial & NOISE_DIVISOR) as usize];
+        divisor << (self.polynomial >> 4)
+    }
+
+    fn step(self: &mut Self) {
+        if self.frequency_timer <= 1 {
+            self.frequency_timer = self.period();
+            self.clock_lfsr();
+        } else {
+            self.frequency_timer -= 1;
+        }
+    }
+
+    // the xor of the lowest two bits is shifted in at the top, and also into
+    // bit 6 in 7-bit mode so the sequence repeats sooner
+    fn clock_lfsr(self: &mut Self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.polynomial & NOISE_SHORT_MODE != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    // the channel is high while the lowest bit of the LFSR is clear
+    fn output(self: &Self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn write_envelope(self: &mut Self, value: u8) {
+        self.envelope.register = value;
+        if !self.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_control(self: &mut Self, value: u8) {
+        self.length.enabled = value & LENGTH_ENABLE != 0;
+        if value & TRIGGER != 0 {
+            self.enabled = self.dac_enabled();
+            self.length.trigger(MAX_LENGTH);
+            self.frequency_timer = self.period();
+            self.envelope.trigger();
+            self.lfsr = LFSR_SEED;
+        }
+    }
+
+    fn clock_length(self: &mut Self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.polynomial);
+        writer.write_u16(self.frequency_timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.polynomial = reader.read_u8()?;
+        self.frequency_timer = reader.read_u16()?;
+        self.lfsr = reader.read_u16()? & LFSR_SEED;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Apu {
+    powered: bool,
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    // master volume for each side, and which channels go to which side
+    volume: u8,
+    panning: u8,
+    // which of its 8 steps the frame sequencer takes next
+    frame_step: u8,
+    // whether the DIV bit clocking the frame sequencer was set last step
+    frame_sequencer_bit: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            powered: false,
+            square1: SquareChannel::default(),
+            square2: SquareChannel::default(),
+            wave: WaveChannel::default(),
+            noise: NoiseChannel::default(),
+            volume: 0,
+            panning: 0,
+            frame_step: 0,
+            frame_sequencer_bit: false,
+        }
+    }
+
+    // the registers as the boot ROM leaves them. Its chime on channel 1 has
+    // faded out by the time the cartridge starts, so the channel is still
+    // on, as NR52 shows, but at volume 0
+    pub fn set_post_boot_state(self: &mut Self) {
+        self.write(NR52, POWER);
+        for (address, value) in [
+            (NR10, 0x80),
+            (NR11, 0xBF),
+            (NR12, 0xF3),
+            (NR13, 0xFF),
+            (NR14, 0x87),
+            (NR21, 0x3F),
+            (NR23, 0xFF),
+            (NR24, 0x3F),
+            (NR30, 0x7F),
+            (NR31, 0xFF),
+            (NR32, 0x9F),
+            (NR33, 0xFF),
+            (NR34, 0x3F),
+            (NR41, 0xFF),
+            (NR50, 0x77),
+            (NR51, 0xF3),
+        ] {
+            self.write(address, value);
+        }
+        self.square1.envelope.volume = 0;
+    }
+
+    pub fn read(self: &Self, address: u16) -> u8 {
+        // bits that are write-only or unused read back as 1
+        match address {
+            NR10 => 0x80 | self.square1.sweep,
+            NR11 => LENGTH_LOAD | self.square1.duty_length,
+            NR12 => self.square1.envelope.register,
+            NR14 => 0xBF | (self.square1.length.enabled as u8) << 6,
+            NR21 => LENGTH_LOAD | self.square2.duty_length,
+            NR22 => self.square2.envelope.register,
+            NR24 => 0xBF | (self.square2.length.enabled as u8) << 6,
+            NR30 => !WAVE_DAC_ENABLE | (self.wave.dac_enabled as u8) << 7,
+            NR32 => !WAVE_VOLUME | self.wave.volume_code << 5,
+            NR34 => 0xBF | (self.wave.length.enabled as u8) << 6,
+            NR42 => self.noise.envelope.register,
+            NR43 => self.noise.polynomial,
+            NR44 => 0xBF | (self.noise.length.enabled as u8) << 6,
+            NR50 => self.volume,
+            NR51 => self.panning,
+            NR52 => {
+                let power = if self.powered { POWER } else { 0 };
+                let active = self
+                    .active_channels()
+                    .iter()
+                    .enumerate()
+                    .fold(0, |bits, (index, &active)| bits | (active as u8) << index);
+                power | NR52_UNUSED_BITS | active
+            }
+            NR13 | NR20 | NR23 | NR31 | NR33 | NR40 | NR41 | UNUSED_START..=UNUSED_END => 0xFF,
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.ram[(address - WAVE_RAM_START) as usize],
+            // the lower numbered channel is in the low nibble
+            PCM12 => {
+                let outputs = self.channel_outputs();
+                outputs[1] << 4 | outputs[0]
+            }
+            PCM34 => {
+                let outputs = self.channel_outputs();
+                outputs[3] << 4 | outputs[2]
+            }
+            _ => panic!("{:#06X} is not a sound register", address),
+        }
+    }
+
+    pub fn write(self: &mut Self, address: u16, value: u8) {
+        // wave RAM is always writable, everything else but NR52 ignores
+        // writes while the APU is off
+        if !self.powered && address != NR52 && !(WAVE_RAM_START..=WAVE_RAM_END).contains(&address) {
+            return;
+        }
+
+        match address {
+            NR10 => self.square1.sweep = value & 0x7F,
+            NR11 => self.square1.write_length(value),
+            NR12 => self.square1.write_envelope(value),
+            NR13 => self.square1.frequency = (self.square1.frequency & 0x700) | value as u16,
+            NR14 => self.square1.write_control(value),
+            NR20 | NR40 | UNUSED_START..=UNUSED_END => {}
+            NR21 => self.square2.write_length(value),
+            NR22 => self.square2.write_envelope(value),
+            NR23 => self.square2.frequency = (self.square2.frequency & 0x700) | value as u16,
+            NR24 => self.square2.write_control(value),
+            NR30 => self.wave.write_dac(value),
+            NR31 => self.wave.length.load(MAX_WAVE_LENGTH, value as u16),
+            NR32 => self.wave.volume_code = (value & WAVE_VOLUME) >> 5,
+            NR33 => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            NR34 => self.wave.write_control(value),
+            NR41 => self
+                .noise
+                .length
+                .load(MAX_LENGTH, (value & LENGTH_LOAD) as u16),
+            NR42 => self.noise.write_envelope(value),
+            NR43 => self.noise.polynomial = value,
+            NR44 => self.noise.write_control(value),
+            NR50 => self.volume = value,
+            NR51 => self.panning = value,
+            NR52 => {
+                let powered = value & POWER != 0;
+                // powering off clears every register, but leaves wave RAM
+                if self.powered && !powered {
+                    let mut apu = Apu::new();
+                    apu.wave.ram = self.wave.ram;
+                    apu.frame_sequencer_bit = self.frame_sequencer_bit;
+                    *self = apu;
+                }
+                if !self.powered && powered {
+                    self.frame_step = 0;
+                }
+                self.powered = powered;
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                self.wave.ram[(address - WAVE_RAM_START) as usize] = value
+            }
+            // read only
+            PCM12 | PCM34 => {}
+            _ => panic!("{:#06X} is not a sound register", address),
+        }
+    }
+
+    // whether each of channels 1 to 4 is playing, which is what the low bits
+    // of NR52 report. A channel stops when its length runs out, its sweep
+    // overflows or its DAC is turned off
+    pub fn active_channels(self: &Self) -> [bool; 4] {
+        [
+            self.square1.enabled,
+            self.square2.enabled,
+            self.wave.enabled,
+            self.noise.enabled,
+        ]
+    }
+
+    // the digital output of each channel before it reaches its DAC, from 0
+    // to 15
+    pub fn channel_outputs(self: &Self) -> [u8; 4] {
+        [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ]
+    }
+
+    // runs the channels for the given number of cycles. The frame sequencer
+    // follows the DIV counter, so a write to DIV can clock it early
+    pub fn step(self: &mut Self, cycles: u8, div_counter: u16) {
+        let bit = div_counter & FRAME_SEQUENCER_BIT != 0;
+        let falling_edge = self.frame_sequencer_bit && !bit;
+        self.frame_sequencer_bit = bit;
+        if !self.powered {
+            return;
+        }
+
+        for _ in 0..cycles {
+            self.square1.step();
+            self.square2.step();
+            self.wave.step();
+            self.noise.step();
+        }
+        if falling_edge {
+            self.clock_frame_sequencer();
+        }
+    }
+
+    // lengths are clocked on every other step, the sweep every fourth and
+    // envelopes on the last
+    fn clock_frame_sequencer(self: &mut Self) {
+        if self.frame_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step % 4 == 2 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // the current left and right output, each between -1 and 1. A front-end
+    // calls this once per output sample, after stepping however many cycles
+    // a sample at its rate lasts
+    pub fn sample(self: &mut Self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0);
+        }
+
+        let channels = [
+            Self::dac_output(self.square1.dac_enabled(), self.square1.output()),
+            Self::dac_output(self.square2.dac_enabled(), self.square2.output()),
+            Self::dac_output(self.wave.dac_enabled, self.wave.output()),
+            Self::dac_output(self.noise.dac_enabled(), self.noise.output()),
+        ];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (index, output) in channels.iter().enumerate() {
+            // the high nibble of NR51 routes channels left, the low nibble right
+            if self.panning & (0x10 << index) != 0 {
+                left += output;
+            }
+            if self.panning & (0x01 << index) != 0 {
+                right += output;
+            }
+        }
+
+        let left_volume = (((self.volume >> 4) & 0x07) + 1) as f32 / 8.0;
+        let right_volume = ((self.volume & 0x07) + 1) as f32 / 8.0;
+        (
+            left / CHANNEL_COUNT * left_volume,
+            right / CHANNEL_COUNT * right_volume,
+        )
+    }
+
+    // the DAC maps the digital 0 to 15 onto -1 to 1, and outputs nothing
+    // while it's off
+    fn dac_output(enabled: bool, output: u8) -> f32 {
+        if !enabled {
+            return 0.0;
+        }
+        output as f32 / 7.5 - 1.0
+    }
+
+    pub fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_bool(self.powered);
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.panning);
+        writer.write_u8(self.frame_step);
+        writer.write_bool(self.frame_sequencer_bit);
+    }
+
+    pub fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.powered = reader.read_bool()?;
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.volume = reader.read_u8()?;
+        self.panning = reader.read_u8()?;
+        self.frame_step = reader.read_u8()? % 8;
+        self.frame_sequencer_bit = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(NR52, POWER);
+        apu.write(NR50, 0x77);
+        apu.write(NR51, 0xFF);
+        apu
+    }
+
+    #[test]
+    fn test_read_masks() {
+        // the bits that always read back as 1, whatever was written
+        let masks = [
+            (NR10, 0x80),
+            (NR11, 0x3F),
+            (NR12, 0x00),
+            (NR13, 0xFF),
+            (NR14, 0xBF),
+            (NR20, 0xFF),
+            (NR21, 0x3F),
+            (NR22, 0x00),
+            (NR23, 0xFF),
+            (NR24, 0xBF),
+            (NR30, 0x7F),
+            (NR31, 0xFF),
+            (NR32, 0x9F),
+            (NR33, 0xFF),
+            (NR34, 0xBF),
+            (NR40, 0xFF),
+            (NR41, 0xFF),
+            (NR42, 0x00),
+            (NR43, 0x00),
+            (NR44, 0xBF),
+            (NR50, 0x00),
+            (NR51, 0x00),
+        ];
+        let mut apu = powered_apu();
+        for (address, mask) in masks {
+            apu.write(address, 0x00);
+            assert_eq!(apu.read(address), mask, "{:#06X}", address);
+            apu.write(address, 0xFF);
+            assert_eq!(apu.read(address), 0xFF, "{:#06X}", address);
+        }
+        for address in UNUSED_START..=UNUSED_END {
+            apu.write(address, 0x00);
+            assert_eq!(apu.read(address), 0xFF, "{:#06X}", address);
+        }
+
+        let mut apu = Apu::new();
+        apu.write(NR52, POWER);
+        assert_eq!(apu.read(NR52), 0xF0);
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+    }
+
+    #[test]
+    fn test_pcm_registers_pack_channel_outputs() {
+        let mut apu = powered_apu();
+        // 50% duty starts high, so each channel outputs its volume
+        apu.write(NR11, 0b1000_0000);
+        apu.write(NR12, 0xA0);
+        apu.write(NR14, TRIGGER);
+        apu.write(NR21, 0b1000_0000);
+        apu.write(NR22, 0x50);
+        apu.write(NR24, TRIGGER);
+        apu.write(NR30, WAVE_DAC_ENABLE);
+        apu.write(WAVE_RAM_START, 0xC0);
+        apu.write(NR32, 0b0010_0000);
+        apu.write(NR34, TRIGGER);
+
+        assert_eq!(apu.channel_outputs(), [0x0A, 0x05, 0x0C, 0x00]);
+        assert_eq!(apu.read(PCM12), 0x5A);
+        assert_eq!(apu.read(PCM34), 0x0C);
+
+        apu.write(PCM12, 0x00);
+        assert_eq!(apu.read(PCM12), 0x5A);
+    }
+
+    #[test]
+    fn test_post_boot_state_is_silent() {
+        let mut apu = Apu::new();
+        apu.set_post_boot_state();
+        assert_eq!(apu.read(NR52), 0xF1);
+        assert_eq!(apu.read(NR12), 0xF3);
+
+        // channel 1's DAC is on, so the mix sits at its level for a digital
+        // 0 rather than at 0, but it holds there instead of playing a tone
+        let first = apu.sample();
+        for _ in 0..4096 {
+            apu.step(1, 0);
+            assert_eq!(apu.channel_outputs(), [0; 4]);
+            assert_eq!(apu.sample(), first);
+        }
+    }
+
+    #[test]
+    fn test_square_wave_follows_duty_cycle() {
+        let mut apu = powered_apu();
+        // 25% duty, full volume with no envelope
+        apu.write(NR21, 0b0100_0000);
+        apu.write(NR22, 0xF0);
+        // each step of the wave lasts (2048 - 2047) * 4 = 4 cycles
+        apu.write(NR23, 0xFF);
+        apu.write(NR24, TRIGGER | 0x07);
+
+        let samples: Vec<f32> = (0..64)
+            .map(|_| {
+                apu.step(1, 0);
+                apu.sample().0
+            })
+            .collect();
+
+        // the wave repeats every 8 steps of 4 cycles, and is high for 2 of
+        // those steps in a single run
+        assert_eq!(samples[..32], samples[32..]);
+        let high = samples.iter().filter(|&&sample| sample > 0.0).count();
+        assert_eq!(high, 16);
+        let rising_edges = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] > 0.0)
+            .count();
+        assert_eq!(rising_edges, 2);
+    }
+
+    #[test]
+    fn test_length_counter_silences_channel() {
+        let mut apu = powered_apu();
+        apu.write(NR22, 0xF0);
+        // 2 length clocks left
+        apu.write(NR21, 62);
+        apu.write(NR24, TRIGGER | LENGTH_ENABLE);
+        assert_eq!(apu.read(NR52) & 0b10, 0b10);
+
+        // each falling edge of the DIV bit steps the frame sequencer, which
+        // clocks lengths on every other step
+        for _ in 0..3 {
+            apu.step(1, FRAME_SEQUENCER_BIT);
+            apu.step(1, 0);
+        }
+        assert_eq!(apu.read(NR52) & 0b10, 0);
+    }
+
+    #[test]
+    fn test_sweep_overflow_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write(NR12, 0xF0);
+        // shifting by 1 adds half the frequency, which overflows at once
+        apu.write(NR10, 0b0001_0001);
+        apu.write(NR13, 0xFF);
+        apu.write(NR14, TRIGGER | 0x07);
+        assert_eq!(apu.read(NR52) & 0b01, 0);
+
+        apu.write(NR13, 0x00);
+        apu.write(NR14, TRIGGER | 0x02);
+        assert_eq!(apu.read(NR52) & 0b01, 0b01);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.write(NR22, 0xF0);
+        apu.write(WAVE_RAM_START, 0x12);
+        apu.write(NR52, 0);
+
+        assert_eq!(apu.read(NR22), 0);
+        assert_eq!(apu.read(NR52), NR52_UNUSED_BITS);
+        apu.write(NR22, 0xF0);
+        assert_eq!(apu.read(NR22), 0);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x12);
+    }
+
+    #[test]
+    fn test_wave_channel_plays_wave_ram() {
+        let mut apu = powered_apu();
+        // the samples count up from 0 to 15 and back down
+        let pattern: Vec<u8> = (0..16)
+            .chain((0..16).rev())
+            .collect::<Vec<u8>>()
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect();
+        for (offset, &byte) in pattern.iter().enumerate() {
+            apu.write(WAVE_RAM_START + offset as u16, byte);
+        }
+        apu.write(NR30, WAVE_DAC_ENABLE);
+        apu.write(NR32, 0b0010_0000);
+        // each sample lasts (2048 - 2047) * 2 = 2 cycles
+        apu.write(NR33, 0xFF);
+        apu.write(NR34, TRIGGER | 0x07);
+
+        let mut outputs = Vec::new();
+        for _ in 0..32 {
+            outputs.push(apu.wave.output());
+            apu.step(2, 0);
+        }
+        let expected: Vec<u8> = (0..16).chain((0..16).rev()).collect();
+        assert_eq!(outputs, expected);
+
+        // a volume of 50% shifts every sample down once
+        apu.write(NR32, 0b0100_0000);
+        apu.write(NR34, TRIGGER | 0x07);
+        let mut outputs = Vec::new();
+        for _ in 0..16 {
+            outputs.push(apu.wave.output());
+            apu.step(2, 0);
+        }
+        let expected: Vec<u8> = (0..16).map(|sample| sample >> 1).collect();
+        assert_eq!(outputs, expected);
+    }
+
+    #[test]
+    fn test_noise_lfsr_sequence() {
+        let mut apu = powered_apu();
+        apu.write(NR42, 0xF0);
+        // a divisor of 16 shifted by 2 clocks the LFSR every 64 cycles
+        apu.write(NR43, 0b0010_0001);
+        apu.write(NR44, TRIGGER);
+
+        apu.step(63, 0);
+        assert_eq!(apu.noise.lfsr, LFSR_SEED);
+        apu.step(1, 0);
+        assert_eq!(apu.noise.lfsr, 0x3FFF);
+
+        // the seed shifts out until only bit 0 is left, and the first set
+        // feedback bit is shifted in at the top on the next clock
+        for _ in 0..13 {
+            apu.noise.clock_lfsr();
+        }
+        assert_eq!(apu.noise.lfsr, 0x0001);
+        assert_eq!(apu.noise.output(), 0);
+        apu.noise.clock_lfsr();
+        assert_eq!(apu.noise.lfsr, 0x4000);
+        assert_eq!(apu.noise.output(), 15);
+    }
+
+    #[test]
+    fn test_noise_sequence_lengths() {
+        for (polynomial, length) in [(0, 32767), (NOISE_SHORT_MODE, 127)] {
+            let mut apu = powered_apu();
+            apu.write(NR42, 0xF0);
+            apu.write(NR43, polynomial);
+            apu.write(NR44, TRIGGER);
+
+            // the 7-bit mode takes a few clocks to enter its loop
+            for _ in 0..8 {
+                apu.noise.clock_lfsr();
+            }
+            let start = apu.noise.lfsr;
+            let mut clocks = 0;
+            loop {
+                apu.noise.clock_lfsr();
+                clocks += 1;
+                if apu.noise.lfsr == start {
+                    break;
+                }
+            }
+            assert_eq!(clocks, length);
+        }
+    }
+
+    #[test]
+    fn test_all_channels_are_mixed() {
+        let mut apu = powered_apu();
+        apu.write(NR30, WAVE_DAC_ENABLE);
+        apu.write(NR32, 0b0010_0000);
+        for offset in 0..16 {
+            apu.write(WAVE_RAM_START + offset, 0xFF);
+        }
+        apu.write(NR34, TRIGGER);
+        let (left, right) = apu.sample();
+        // only the wave channel has its DAC on, playing at full volume
+        assert_eq!((left, right), (0.25, 0.25));
+
+        // routing it to the right only silences the left side
+        apu.write(NR51, 0b0000_0100);
+        assert_eq!(apu.sample(), (0.0, 0.25));
+        assert_eq!(apu.read(NR52), POWER | NR52_UNUSED_BITS | 0b0100);
+    }
+}
@@ -0,0 +1,123 @@
+use alloc::collections::VecDeque;
+
+// Turns samples taken at the emulated clock's rate into the much lower rate
+// a host audio device plays at, by averaging every source sample that falls
+// within each output sample
+pub struct AudioBuffer {
+    source_rate: u32,
+    target_rate: u32,
+    // the running totals for the output sample being built
+    left: f32,
+    right: f32,
+    count: u32,
+    // counts up by the target rate for every source sample, and an output
+    // sample is finished each time it passes the source rate
+    phase: u32,
+    // finished samples, interleaved left then right
+    output: VecDeque<f32>,
+}
+
+impl AudioBuffer {
+    pub fn new(source_rate: u32, target_rate: u32) -> Self {
+        assert!(
+            target_rate > 0 && target_rate <= source_rate,
+            "can't resample {}Hz to {}Hz",
+            source_rate,
+            target_rate
+        );
+        AudioBuffer {
+            source_rate,
+            target_rate,
+            left: 0.0,
+            right: 0.0,
+            count: 0,
+            phase: 0,
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn target_rate(self: &Self) -> u32 {
+        self.target_rate
+    }
+
+    pub fn push(self: &mut Self, l: f32, r: f32) {
+        self.left += l;
+        self.right += r;
+        self.count += 1;
+
+        self.phase += self.target_rate;
+        if self.phase >= self.source_rate {
+            self.phase -= self.source_rate;
+            self.output.push_back(self.left / self.count as f32);
+            self.output.push_back(self.right / self.count as f32);
+            self.left = 0.0;
+            self.right = 0.0;
+            self.count = 0;
+        }
+    }
+
+    // the number of finished values waiting, two for every stereo sample
+    pub fn len(self: &Self) -> usize {
+        self.output.len()
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.output.is_empty()
+    }
+
+    // moves as many interleaved values as fit into out, returning how many
+    // were written
+    pub fn drain(self: &mut Self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.output.len());
+        for (slot, value) in out.iter_mut().zip(self.output.drain(..count)) {
+            *slot = value;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_output_follows_rate_ratio() {
+        let source_rate = 1_048_576;
+        let mut buffer = AudioBuffer::new(source_rate, 48000);
+        for _ in 0..source_rate {
+            buffer.push(0.5, -0.5);
+        }
+
+        let mut out = vec![0.0; 2 * 48000 + 16];
+        let written = buffer.drain(&mut out);
+        assert!((2 * 48000 - 2..=2 * 48000).contains(&written));
+        assert_eq!(out[0], 0.5);
+        assert_eq!(out[1], -0.5);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_samples_are_averaged() {
+        let mut buffer = AudioBuffer::new(4, 1);
+        for sample in [0.0, 1.0, 1.0, 0.0] {
+            buffer.push(sample, -sample);
+        }
+
+        let mut out = [0.0; 4];
+        assert_eq!(buffer.drain(&mut out), 2);
+        assert_eq!(out[..2], [0.5, -0.5]);
+    }
+
+    #[test]
+    fn test_drain_leaves_what_does_not_fit() {
+        let mut buffer = AudioBuffer::new(2, 1);
+        for _ in 0..6 {
+            buffer.push(0.25, 0.25);
+        }
+
+        let mut out = [0.0; 4];
+        assert_eq!(buffer.drain(&mut out), 4);
+        assert_eq!(buffer.len(), 2);
+    }
+}
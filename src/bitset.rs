@@ -0,0 +1,42 @@
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+const BITS_PER_WORD: usize = 64;
+
+// A fixed size set of bits. Bits can be set through a shared reference so
+// reads, which only borrow memory immutably, can still be recorded
+pub struct BitSet {
+    words: Vec<Cell<u64>>,
+}
+
+impl BitSet {
+    pub fn new(size: usize) -> Self {
+        BitSet {
+            words: (0..size.div_ceil(BITS_PER_WORD))
+                .map(|_| Cell::new(0))
+                .collect(),
+        }
+    }
+
+    pub fn insert(self: &Self, index: usize) {
+        let word = &self.words[index / BITS_PER_WORD];
+        word.set(word.get() | 1 << (index % BITS_PER_WORD));
+    }
+
+    pub fn contains(self: &Self, index: usize) -> bool {
+        self.words[index / BITS_PER_WORD].get() & 1 << (index % BITS_PER_WORD) != 0
+    }
+
+    pub fn count(self: &Self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.get().count_ones() as usize)
+            .sum()
+    }
+
+    pub fn clear(self: &Self) {
+        for word in &self.words {
+            word.set(0);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use alloc::string::String;
+use core::fmt;
+
+// Offsets for the fields in the cartridge header
+pub const HEADER_START: usize = 0x0134;
+pub const TITLE_START: usize = 0x0134;
+pub const CGB_FLAG: usize = 0x0143;
+pub const SGB_FLAG: usize = 0x0146;
+pub const CARTRIDGE_TYPE: usize = 0x0147;
+pub const ROM_SIZE: usize = 0x0148;
+pub const RAM_SIZE: usize = 0x0149;
+pub const HEADER_CHECKSUM: usize = 0x014D;
+pub const GLOBAL_CHECKSUM: usize = 0x014E;
+pub const HEADER_END: usize = 0x0150;
+
+// Every switchable ROM bank is 16KB
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+// Reasons a ROM can't be loaded
+#[derive(Debug)]
+pub enum RomLoadError {
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    // the ROM ends before the end of the cartridge header
+    TooSmall,
+    BadHeaderChecksum,
+    UnsupportedMapper(u8),
+}
+
+impl fmt::Display for RomLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            RomLoadError::Io(error) => write!(f, "{}", error),
+            RomLoadError::TooSmall => write!(f, "ROM is too small to hold a cartridge header"),
+            RomLoadError::BadHeaderChecksum => write!(f, "cartridge header checksum mismatch"),
+            RomLoadError::UnsupportedMapper(cartridge_type) => {
+                write!(f, "unsupported cartridge type {:#04X}", cartridge_type)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RomLoadError {}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for RomLoadError {
+    fn from(error: std::io::Error) -> Self {
+        RomLoadError::Io(error)
+    }
+}
+
+// the checksum the boot ROM verifies over the header bytes before 0x014D
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[HEADER_START..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |checksum, byte| {
+            checksum.wrapping_sub(*byte).wrapping_sub(1)
+        })
+}
+
+// the sum of every byte in the ROM apart from the checksum itself, which is
+// stored big-endian unlike everything else
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(address, _)| !(GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2).contains(address))
+        .fold(0u16, |checksum, (_, byte)| {
+            checksum.wrapping_add(*byte as u16)
+        })
+}
+
+// decodes the RAM size byte stored at 0x0149 in the header into a byte count
+pub fn ram_size(code: u8) -> usize {
+    match code {
+        0x01 => 0x800,
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
+}
+
+// Whether a cartridge makes use of the CGB, from the flag at 0x0143
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    DmgOnly,
+    Enhanced,
+    CgbOnly,
+}
+
+// A summary of what the cartridge header declares, for front-ends to show
+// when a ROM is loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeInfo {
+    pub title: String,
+    pub mapper: &'static str,
+    pub rom_size_kb: usize,
+    pub ram_size_kb: usize,
+    pub battery: bool,
+    pub rtc: bool,
+    pub rumble: bool,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+}
+
+// reads the summary out of a ROM's header, which must be at least
+// HEADER_END bytes long
+pub fn cartridge_info(rom: &[u8]) -> CartridgeInfo {
+    let cgb = match rom[CGB_FLAG] {
+        0xC0 => CgbSupport::CgbOnly,
+        0x80 => CgbSupport::Enhanced,
+        _ => CgbSupport::DmgOnly,
+    };
+    // CGB cartridges give the last byte of the title over to the CGB flag,
+    // and shorter titles are padded with zeroes
+    let title_end = match cgb {
+        CgbSupport::DmgOnly => CGB_FLAG + 1,
+        _ => CGB_FLAG,
+    };
+    let title = rom[TITLE_START..title_end]
+        .iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as char)
+        .filter(|character| character.is_ascii_graphic() || *character == ' ')
+        .collect::<String>()
+        .trim_end()
+        .into();
+
+    let cartridge_type = rom[CARTRIDGE_TYPE];
+    let mapper = match cartridge_type {
+        0x00 | 0x08 | 0x09 => "ROM only",
+        0x01..=0x03 => "MBC1",
+        0x05 | 0x06 => "MBC2",
+        0x0B..=0x0D => "MMM01",
+        0x0F..=0x13 => "MBC3",
+        0x19..=0x1E => "MBC5",
+        0x20 => "MBC6",
+        0x22 => "MBC7",
+        0xFC => "Pocket Camera",
+        0xFD => "TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1",
+        _ => "unknown",
+    };
+    let rom_size_code = rom[ROM_SIZE];
+
+    CartridgeInfo {
+        title,
+        mapper,
+        // 32KB doubled for every step of the size code
+        rom_size_kb: match rom_size_code {
+            0x00..=0x08 => 32 << rom_size_code,
+            _ => 0,
+        },
+        ram_size_kb: ram_size(rom[RAM_SIZE]) / 1024,
+        battery: matches!(
+            cartridge_type,
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+        ),
+        rtc: matches!(cartridge_type, 0x0F | 0x10 | 0xFE),
+        rumble: matches!(cartridge_type, 0x1C..=0x1E | 0x22),
+        cgb,
+        sgb: rom[SGB_FLAG] == 0x03,
+    }
+}
+
+// The memory bank controller built into the cartridge, which decides how
+// writes to the ROM area are interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mbc {
+    RomOnly,
+    Mbc1,
+    Mbc3,
+}
+
+impl Mbc {
+    // decodes the cartridge type byte stored at 0x0147 in the header
+    pub fn from_cartridge_type(cartridge_type: u8) -> Option<Mbc> {
+        match cartridge_type {
+            0x00 | 0x08 | 0x09 => Some(Mbc::RomOnly),
+            0x01..=0x03 => Some(Mbc::Mbc1),
+            0x0F..=0x13 => Some(Mbc::Mbc3),
+            _ => None,
+        }
+    }
+
+    // the bank number register only has a limited number of bits, and
+    // writing 0 selects bank 1 since bank 0 is always mapped at 0x0000
+    pub fn rom_bank_number(self: Self, data: u8) -> usize {
+        let bank = match self {
+            Mbc::RomOnly => 1,
+            Mbc::Mbc1 => data & 0x1F,
+            Mbc::Mbc3 => data & 0x7F,
+        };
+
+        match bank {
+            0 => 1,
+            _ => bank as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_checksums_match_header() {
+        let mut rom = vec![0; HEADER_END];
+        rom[HEADER_START..HEADER_START + 4].copy_from_slice(b"TEST");
+        rom[HEADER_CHECKSUM] = 0xA7;
+        rom[GLOBAL_CHECKSUM] = 0x01;
+        rom[GLOBAL_CHECKSUM + 1] = 0xE7;
+
+        assert_eq!(header_checksum(&rom), rom[HEADER_CHECKSUM]);
+        assert_eq!(
+            global_checksum(&rom),
+            u16::from_be_bytes([rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]])
+        );
+    }
+
+    #[test]
+    fn test_cartridge_info() {
+        let mut rom = vec![0; HEADER_END];
+        rom[TITLE_START..TITLE_START + 7].copy_from_slice(b"POKEMON");
+        rom[CGB_FLAG] = 0x80;
+        rom[SGB_FLAG] = 0x03;
+        // MBC5+RAM+BATTERY with 1MB of ROM and 32KB of RAM
+        rom[CARTRIDGE_TYPE] = 0x1B;
+        rom[ROM_SIZE] = 0x05;
+        rom[RAM_SIZE] = 0x03;
+
+        assert_eq!(
+            cartridge_info(&rom),
+            CartridgeInfo {
+                title: "POKEMON".into(),
+                mapper: "MBC5",
+                rom_size_kb: 1024,
+                ram_size_kb: 32,
+                battery: true,
+                rtc: false,
+                rumble: false,
+                cgb: CgbSupport::Enhanced,
+                sgb: true,
+            }
+        );
+    }
+}
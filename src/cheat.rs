@@ -0,0 +1,158 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+// Game Genie codes hide the address and compare value behind these
+const GAME_GENIE_ADDRESS_MASK: u16 = 0xF000;
+const GAME_GENIE_COMPARE_MASK: u8 = 0xBA;
+
+// A decoded cheat code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cheat {
+    // replaces reads of a ROM address, but only while the byte there matches
+    // compare when one is given
+    GameGenie {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    // rewrites a RAM address at the end of every frame
+    GameShark {
+        bank: u8,
+        address: u16,
+        value: u8,
+    },
+}
+
+// Reasons a cheat code can't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatParseError {
+    // the code doesn't look like either the ABC-DEF(-GHI) Game Genie format
+    // or the 8 digit Game Shark format
+    InvalidFormat,
+    InvalidDigit(char),
+}
+
+impl fmt::Display for CheatParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatParseError::InvalidFormat => write!(f, "not a Game Genie or Game Shark code"),
+            CheatParseError::InvalidDigit(digit) => write!(f, "{:?} is not a hex digit", digit),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CheatParseError {}
+
+impl Cheat {
+    pub fn parse(code: &str) -> Result<Self, CheatParseError> {
+        let groups: Vec<&str> = code.trim().split('-').collect();
+        match groups.as_slice() {
+            [game_shark] if game_shark.len() == 8 => Cheat::parse_game_shark(game_shark),
+            [first, second] | [first, second, _] if first.len() == 3 && second.len() == 3 => {
+                Cheat::parse_game_genie(&groups)
+            }
+            _ => Err(CheatParseError::InvalidFormat),
+        }
+    }
+
+    // ABC-DEF-GHI: AB is the new value, FCDE the address with F inverted,
+    // and G and I hold the scrambled compare value. H isn't used
+    fn parse_game_genie(groups: &[&str]) -> Result<Self, CheatParseError> {
+        if groups.get(2).is_some_and(|group| group.len() != 3) {
+            return Err(CheatParseError::InvalidFormat);
+        }
+
+        let digits = hex_digits(&groups.concat())?;
+        let value = (digits[0] << 4) | digits[1];
+        let address = ((digits[5] as u16) << 12
+            | (digits[2] as u16) << 8
+            | (digits[3] as u16) << 4
+            | digits[4] as u16)
+            ^ GAME_GENIE_ADDRESS_MASK;
+        let compare = match digits.len() {
+            9 => Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ GAME_GENIE_COMPARE_MASK),
+            _ => None,
+        };
+
+        Ok(Cheat::GameGenie {
+            address,
+            value,
+            compare,
+        })
+    }
+
+    // ABCDEFGH: AB is the RAM bank, CD the value, and GHEF the address
+    fn parse_game_shark(code: &str) -> Result<Self, CheatParseError> {
+        let digits = hex_digits(code)?;
+        let byte = |index: usize| (digits[index] << 4) | digits[index + 1];
+
+        Ok(Cheat::GameShark {
+            bank: byte(0),
+            value: byte(2),
+            address: (byte(6) as u16) << 8 | byte(4) as u16,
+        })
+    }
+}
+
+fn hex_digits(code: &str) -> Result<Vec<u8>, CheatParseError> {
+    code.chars()
+        .map(|digit| {
+            digit
+                .to_digit(16)
+                .map(|value| value as u8)
+                .ok_or(CheatParseError::InvalidDigit(digit))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_game_genie() {
+        assert_eq!(
+            Cheat::parse("3EA-17B-FAE"),
+            Ok(Cheat::GameGenie {
+                address: 0x4A17,
+                value: 0x3E,
+                compare: Some(0x05),
+            })
+        );
+        assert_eq!(
+            Cheat::parse("3EA-17B"),
+            Ok(Cheat::GameGenie {
+                address: 0x4A17,
+                value: 0x3E,
+                compare: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_game_shark() {
+        assert_eq!(
+            Cheat::parse("01FF34C2"),
+            Ok(Cheat::GameShark {
+                bank: 0x01,
+                address: 0xC234,
+                value: 0xFF,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_codes() {
+        assert_eq!(Cheat::parse("3EA-17"), Err(CheatParseError::InvalidFormat));
+        assert_eq!(
+            Cheat::parse("3EA-17B-FA"),
+            Err(CheatParseError::InvalidFormat)
+        );
+        assert_eq!(Cheat::parse("01FF34C"), Err(CheatParseError::InvalidFormat));
+        assert_eq!(
+            Cheat::parse("01FG34C2"),
+            Err(CheatParseError::InvalidDigit('G'))
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use core::time::Duration;
+
+// The master clock every component counts T-cycles of. In double speed mode
+// the CPU runs twice as fast, but the cycles the emulator hands out are
+// already scaled back to this clock
+pub const CLOCK_HZ: u64 = 4_194_304;
+
+// 154 lines of 456 dots each
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+pub fn cycles_per_frame() -> u32 {
+    CYCLES_PER_FRAME
+}
+
+// how long the given number of master clock cycles take on hardware
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let seconds = cycles / CLOCK_HZ;
+    let nanos = (cycles % CLOCK_HZ) * NANOS_PER_SECOND / CLOCK_HZ;
+    Duration::new(seconds, nanos as u32)
+}
+
+// the same for cycles counted by the CPU, which only take half as long once
+// it has switched to double speed
+pub fn cpu_cycles_to_duration(cycles: u64, double_speed: bool) -> Duration {
+    if double_speed {
+        cycles_to_duration(cycles) / 2
+    } else {
+        cycles_to_duration(cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycles_per_frame() {
+        assert_eq!(cycles_per_frame(), 70224);
+    }
+
+    #[test]
+    fn test_one_second_of_cycles() {
+        assert_eq!(cycles_to_duration(CLOCK_HZ), Duration::from_secs(1));
+        assert_eq!(
+            cpu_cycles_to_duration(2 * CLOCK_HZ, true),
+            Duration::from_secs(1)
+        );
+
+        // a frame is a little under 1/59.7th of a second
+        let frame = cycles_to_duration(CYCLES_PER_FRAME as u64);
+        assert_eq!(frame.as_micros(), 16742);
+    }
+}
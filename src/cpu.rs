@@ -1,10 +1,28 @@
-use crate::memory;
+use crate::error::EmuError;
+use crate::memory::{self, Interrupt};
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::symbols::SymbolTable;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use bitflags::bitflags;
-use std::collections::VecDeque;
+use core::fmt;
 
 const INITIAL_PC: u16 = 0x100;
 const INITIAL_SP: u16 = 0xFFFE;
-
+// LDH instructions address memory relative to the start of the I/O registers
+const HIGH_PAGE: u16 = 0xFF00;
+// opcodes that don't decode to any instruction and hang the CPU
+const ILLEGAL_OPCODES: [u8; 11] = [
+    0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+];
+// every fetch and every micro op takes one machine cycle
+const CYCLES_PER_MICRO_OP: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum EightBitRegister {
     A,
     B,
@@ -16,15 +34,348 @@ enum EightBitRegister {
     L,
     S,
     P,
+    // internal registers that hold operands read from memory
+    W,
+    Z,
+}
+
+impl EightBitRegister {
+    // every register, in the order save states number them
+    const ALL: [EightBitRegister; 12] = [
+        EightBitRegister::A,
+        EightBitRegister::B,
+        EightBitRegister::D,
+        EightBitRegister::H,
+        EightBitRegister::F,
+        EightBitRegister::C,
+        EightBitRegister::E,
+        EightBitRegister::L,
+        EightBitRegister::S,
+        EightBitRegister::P,
+        EightBitRegister::W,
+        EightBitRegister::Z,
+    ];
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum SixteenBitRegister {
+    Af,
     Bc,
     De,
     Hl,
     Sp,
 }
 
+impl SixteenBitRegister {
+    const ALL: [SixteenBitRegister; 5] = [
+        SixteenBitRegister::Af,
+        SixteenBitRegister::Bc,
+        SixteenBitRegister::De,
+        SixteenBitRegister::Hl,
+        SixteenBitRegister::Sp,
+    ];
+}
+
+// The addresses that the accumulator can be stored through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IndirectAddress {
+    Bc,
+    De,
+    HlIncrement,
+    HlDecrement,
+}
+
+// The flag test made by a conditional jump, call or return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    NotZero,
+    Zero,
+    NotCarry,
+    Carry,
+}
+
+// An operation applied to the byte at (HL), which is read on one cycle and
+// written back on the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadModifyWriteOp {
+    Increment,
+    Decrement,
+    ShiftLeftArithmetic,
+    ShiftRightArithmetic,
+    ShiftRightLogical,
+}
+
+impl ReadModifyWriteOp {
+    const ALL: [ReadModifyWriteOp; 5] = [
+        ReadModifyWriteOp::Increment,
+        ReadModifyWriteOp::Decrement,
+        ReadModifyWriteOp::ShiftLeftArithmetic,
+        ReadModifyWriteOp::ShiftRightArithmetic,
+        ReadModifyWriteOp::ShiftRightLogical,
+    ];
+}
+
+// The 8-bit arithmetic and logic operations, which all take A and an
+// operand and leave their result in A, except for CP which only sets flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AluOp {
+    Add,
+    AddWithCarry,
+    Subtract,
+    SubtractWithCarry,
+    And,
+    Xor,
+    Or,
+    Compare,
+}
+
+impl AluOp {
+    // bits 3-5 of the 0x80-0xBF block and of the immediate forms pick the
+    // operation, in the order the variants are declared
+    fn from_opcode(opcode: u8) -> Self {
+        match (opcode >> 3) & 0x07 {
+            0 => AluOp::Add,
+            1 => AluOp::AddWithCarry,
+            2 => AluOp::Subtract,
+            3 => AluOp::SubtractWithCarry,
+            4 => AluOp::And,
+            5 => AluOp::Xor,
+            6 => AluOp::Or,
+            _ => AluOp::Compare,
+        }
+    }
+}
+
+// An instruction after its opcode has been decoded, carrying the operands
+// needed to either execute or disassemble it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodedInstruction {
+    Nop,
+    Stop,
+    Halt,
+    LoadSixteenBitImmediate {
+        register: SixteenBitRegister,
+    },
+    StoreAccumulator {
+        address: IndirectAddress,
+    },
+    IncrementSixteenBit {
+        register: SixteenBitRegister,
+    },
+    DecrementSixteenBit {
+        register: SixteenBitRegister,
+    },
+    IncrementEightBit {
+        register: EightBitRegister,
+    },
+    Load {
+        destination: EightBitRegister,
+        source: EightBitRegister,
+    },
+    LoadImmediate {
+        destination: EightBitRegister,
+    },
+    StoreStackPointer,
+    SetCarryFlag,
+    ComplementCarryFlag,
+    StoreAccumulatorHighPage,
+    LoadAccumulatorHighPage,
+    StoreHlImmediate,
+    RelativeJump,
+    ConditionalRelativeJump {
+        condition: Condition,
+    },
+    Jump,
+    ConditionalJump {
+        condition: Condition,
+    },
+    Call,
+    ConditionalCall {
+        condition: Condition,
+    },
+    Return,
+    ConditionalReturn {
+        condition: Condition,
+    },
+    Alu {
+        operation: AluOp,
+        source: EightBitRegister,
+    },
+    Prefix,
+    ShiftLeftArithmetic {
+        register: EightBitRegister,
+    },
+    ShiftRightArithmetic {
+        register: EightBitRegister,
+    },
+    ShiftRightLogical {
+        register: EightBitRegister,
+    },
+    ReadModifyWrite {
+        operation: ReadModifyWriteOp,
+    },
+    Illegal {
+        opcode: u8,
+    },
+    Unimplemented {
+        opcode: u8,
+    },
+    UnimplementedPrefixed {
+        opcode: u8,
+    },
+}
+
+impl fmt::Display for EightBitRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EightBitRegister::A => "A",
+            EightBitRegister::B => "B",
+            EightBitRegister::D => "D",
+            EightBitRegister::H => "H",
+            EightBitRegister::F => "F",
+            EightBitRegister::C => "C",
+            EightBitRegister::E => "E",
+            EightBitRegister::L => "L",
+            EightBitRegister::S => "S",
+            EightBitRegister::P => "P",
+            EightBitRegister::W => "W",
+            EightBitRegister::Z => "Z",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for SixteenBitRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SixteenBitRegister::Af => "AF",
+            SixteenBitRegister::Bc => "BC",
+            SixteenBitRegister::De => "DE",
+            SixteenBitRegister::Hl => "HL",
+            SixteenBitRegister::Sp => "SP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for IndirectAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            IndirectAddress::Bc => "(BC)",
+            IndirectAddress::De => "(DE)",
+            IndirectAddress::HlIncrement => "(HL+)",
+            IndirectAddress::HlDecrement => "(HL-)",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Condition::NotZero => "NZ",
+            Condition::Zero => "Z",
+            Condition::NotCarry => "NC",
+            Condition::Carry => "C",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for ReadModifyWriteOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ReadModifyWriteOp::Increment => "INC",
+            ReadModifyWriteOp::Decrement => "DEC",
+            ReadModifyWriteOp::ShiftLeftArithmetic => "SLA",
+            ReadModifyWriteOp::ShiftRightArithmetic => "SRA",
+            ReadModifyWriteOp::ShiftRightLogical => "SRL",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for AluOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AluOp::Add => "ADD",
+            AluOp::AddWithCarry => "ADC",
+            AluOp::Subtract => "SUB",
+            AluOp::SubtractWithCarry => "SBC",
+            AluOp::And => "AND",
+            AluOp::Xor => "XOR",
+            AluOp::Or => "OR",
+            AluOp::Compare => "CP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// shows the mnemonic with placeholders for any operands, like "LD B,n8"
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Cpu::decode_opcode(*self as u8))
+    }
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodedInstruction::Nop => write!(f, "NOP"),
+            DecodedInstruction::Stop => write!(f, "STOP"),
+            DecodedInstruction::Halt => write!(f, "HALT"),
+            DecodedInstruction::LoadSixteenBitImmediate { register } => {
+                write!(f, "LD {},n16", register)
+            }
+            DecodedInstruction::StoreAccumulator { address } => write!(f, "LD {},A", address),
+            DecodedInstruction::IncrementSixteenBit { register } => write!(f, "INC {}", register),
+            DecodedInstruction::DecrementSixteenBit { register } => write!(f, "DEC {}", register),
+            DecodedInstruction::IncrementEightBit { register } => write!(f, "INC {}", register),
+            DecodedInstruction::Load {
+                destination,
+                source,
+            } => write!(f, "LD {},{}", destination, source),
+            DecodedInstruction::LoadImmediate { destination } => {
+                write!(f, "LD {},n8", destination)
+            }
+            DecodedInstruction::StoreStackPointer => write!(f, "LD (n16),SP"),
+            DecodedInstruction::SetCarryFlag => write!(f, "SCF"),
+            DecodedInstruction::ComplementCarryFlag => write!(f, "CCF"),
+            DecodedInstruction::StoreAccumulatorHighPage => write!(f, "LDH (n8),A"),
+            DecodedInstruction::LoadAccumulatorHighPage => write!(f, "LDH A,(n8)"),
+            DecodedInstruction::StoreHlImmediate => write!(f, "LD (HL),n8"),
+            DecodedInstruction::RelativeJump => write!(f, "JR e8"),
+            DecodedInstruction::ConditionalRelativeJump { condition } => {
+                write!(f, "JR {},e8", condition)
+            }
+            DecodedInstruction::Jump => write!(f, "JP n16"),
+            DecodedInstruction::ConditionalJump { condition } => write!(f, "JP {},n16", condition),
+            DecodedInstruction::Call => write!(f, "CALL n16"),
+            DecodedInstruction::ConditionalCall { condition } => {
+                write!(f, "CALL {},n16", condition)
+            }
+            DecodedInstruction::Return => write!(f, "RET"),
+            DecodedInstruction::ConditionalReturn { condition } => write!(f, "RET {}", condition),
+            DecodedInstruction::Alu { operation, source } => {
+                write!(f, "{} A,{}", operation, source)
+            }
+            DecodedInstruction::Prefix => write!(f, "PREFIX CB"),
+            DecodedInstruction::ShiftLeftArithmetic { register } => write!(f, "SLA {}", register),
+            DecodedInstruction::ShiftRightArithmetic { register } => write!(f, "SRA {}", register),
+            DecodedInstruction::ShiftRightLogical { register } => write!(f, "SRL {}", register),
+            DecodedInstruction::ReadModifyWrite { operation } => write!(f, "{} (HL)", operation),
+            DecodedInstruction::Illegal { opcode }
+            | DecodedInstruction::Unimplemented { opcode } => {
+                write!(f, "DB {:#04X}", opcode)
+            }
+            DecodedInstruction::UnimplementedPrefixed { opcode } => {
+                write!(f, "DB 0xCB,{:#04X}", opcode)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum MicroOp {
     LoadImmediate {
         destination: EightBitRegister,
@@ -37,11 +388,189 @@ enum MicroOp {
         register: SixteenBitRegister,
         value: u16,
     },
+    // writes a 16-bit value to the address held in WZ
+    StoreWordToMemory {
+        value: u16,
+    },
+    // a cycle spent without touching the bus
+    Internal,
+    // reads the byte at HL and leaves the result of the operation in Z
+    ReadModify {
+        operation: ReadModifyWriteOp,
+    },
+    // writes the byte left in Z by ReadModify back to HL
+    WriteBack,
+    // reads the second byte of a 0xCB prefixed instruction and executes it
+    ExecutePrefixed,
+    // accesses 0xFF00 plus the operand in Z
+    StoreToHighPage {
+        value: u8,
+    },
+    LoadFromHighPage {
+        destination: EightBitRegister,
+    },
+    // adds the signed operand in Z to the program counter
+    RelativeJump,
+    // sets the program counter to the address in WZ
+    Jump,
+    // decrements the stack pointer and writes the value to the stack
+    Push {
+        value: u8,
+    },
+    // reads the top of the stack and increments the stack pointer
+    Pop {
+        destination: EightBitRegister,
+    },
 }
 
-#[derive(FromPrimitive)]
+// save states store an enum without fields as its position in `all`
+fn write_variant<T: Copy + PartialEq>(writer: &mut StateWriter, all: &[T], value: T) {
+    let index = all.iter().position(|&variant| variant == value).unwrap();
+    writer.write_u8(index as u8);
+}
+
+fn read_variant<T: Copy>(reader: &mut StateReader, all: &[T]) -> Result<T, StateError> {
+    let index = reader.read_u8()? as usize;
+    all.get(index).copied().ok_or(StateError::InvalidValue)
+}
+
+impl MicroOp {
+    // a tag numbering the variant in declaration order, then its fields
+    fn save_state(self: &Self, writer: &mut StateWriter) {
+        match *self {
+            MicroOp::LoadImmediate { destination } => {
+                writer.write_u8(0);
+                write_variant(writer, &EightBitRegister::ALL, destination);
+            }
+            MicroOp::StoreToMemory { value, address } => {
+                writer.write_u8(1);
+                writer.write_u8(value);
+                writer.write_u16(address);
+            }
+            MicroOp::StoreToSixteenBitRegister { register, value } => {
+                writer.write_u8(2);
+                write_variant(writer, &SixteenBitRegister::ALL, register);
+                writer.write_u16(value);
+            }
+            MicroOp::StoreWordToMemory { value } => {
+                writer.write_u8(3);
+                writer.write_u16(value);
+            }
+            MicroOp::Internal => writer.write_u8(4),
+            MicroOp::ReadModify { operation } => {
+                writer.write_u8(5);
+                write_variant(writer, &ReadModifyWriteOp::ALL, operation);
+            }
+            MicroOp::WriteBack => writer.write_u8(6),
+            MicroOp::ExecutePrefixed => writer.write_u8(7),
+            MicroOp::StoreToHighPage { value } => {
+                writer.write_u8(8);
+                writer.write_u8(value);
+            }
+            MicroOp::LoadFromHighPage { destination } => {
+                writer.write_u8(9);
+                write_variant(writer, &EightBitRegister::ALL, destination);
+            }
+            MicroOp::RelativeJump => writer.write_u8(10),
+            MicroOp::Jump => writer.write_u8(11),
+            MicroOp::Push { value } => {
+                writer.write_u8(12);
+                writer.write_u8(value);
+            }
+            MicroOp::Pop { destination } => {
+                writer.write_u8(13);
+                write_variant(writer, &EightBitRegister::ALL, destination);
+            }
+        }
+    }
+
+    fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        Ok(match reader.read_u8()? {
+            0 => MicroOp::LoadImmediate {
+                destination: read_variant(reader, &EightBitRegister::ALL)?,
+            },
+            1 => MicroOp::StoreToMemory {
+                value: reader.read_u8()?,
+                address: reader.read_u16()?,
+            },
+            2 => MicroOp::StoreToSixteenBitRegister {
+                register: read_variant(reader, &SixteenBitRegister::ALL)?,
+                value: reader.read_u16()?,
+            },
+            3 => MicroOp::StoreWordToMemory {
+                value: reader.read_u16()?,
+            },
+            4 => MicroOp::Internal,
+            5 => MicroOp::ReadModify {
+                operation: read_variant(reader, &ReadModifyWriteOp::ALL)?,
+            },
+            6 => MicroOp::WriteBack,
+            7 => MicroOp::ExecutePrefixed,
+            8 => MicroOp::StoreToHighPage {
+                value: reader.read_u8()?,
+            },
+            9 => MicroOp::LoadFromHighPage {
+                destination: read_variant(reader, &EightBitRegister::ALL)?,
+            },
+            10 => MicroOp::RelativeJump,
+            11 => MicroOp::Jump,
+            12 => MicroOp::Push {
+                value: reader.read_u8()?,
+            },
+            13 => MicroOp::Pop {
+                destination: read_variant(reader, &EightBitRegister::ALL)?,
+            },
+            _ => return Err(StateError::InvalidValue),
+        })
+    }
+}
+
+// An instruction that was executed, kept around for post-mortem debugging
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u8,
+    pub disassembly: String,
+}
+
+// Everything that decides whether an interrupt gets serviced, gathered in
+// one place for working out why a handler isn't running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptState {
+    pub ime: bool,
+    pub ie: u8,
+    pub if_: u8,
+    pub halted: bool,
+    // the interrupts that are both requested and enabled
+    pub pending: u8,
+}
+
+impl fmt::Display for InterruptState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "IME:{} IE:{:02X} IF:{:02X} HALT:{} PENDING:{:02X}",
+            self.ime as u8, self.ie, self.if_, self.halted as u8, self.pending
+        )?;
+        let mut pending = Interrupt::ALL
+            .iter()
+            .filter(|interrupt| self.pending & interrupt.bit() != 0);
+        if let Some(first) = pending.next() {
+            write!(f, " ({:?}", first)?;
+            for interrupt in pending {
+                write!(f, ", {:?}", interrupt)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromPrimitive)]
 enum Instruction {
-    NOP = 0x00,
+    Nop = 0x00,
+    Stop = 0x10,
+    Halt = 0x76,
     // LD rr,nn instruction
     LoadBcTwoByteImmediate = 0x01,
     LoadDeTwoByteImmediate = 0x11,
@@ -52,11 +581,18 @@ enum Instruction {
     StoreDeA = 0x12,
     StoreHlPlusA = 0x22,
     StoreHlMinusA = 0x32,
+    // LD (nn),SP
+    StoreSp = 0x08,
     // Inc rr
     IncBc = 0x03,
     IncDe = 0x13,
     IncHl = 0x23,
     IncSp = 0x33,
+    // Dec rr
+    DecBc = 0x0B,
+    DecDe = 0x1B,
+    DecHl = 0x2B,
+    DecSp = 0x3B,
     // Inc r
     IncA = 0x3C,
     IncB = 0x04,
@@ -65,6 +601,47 @@ enum Instruction {
     IncE = 0x1C,
     IncH = 0x24,
     IncL = 0x2C,
+    // read-modify-write on (HL)
+    IncHlIndirect = 0x34,
+    DecHlIndirect = 0x35,
+    // LD (HL),n
+    StoreHlImmediate = 0x36,
+    // LD r,n instructions
+    LoadAImmediate = 0x3E,
+    LoadBImmediate = 0x06,
+    LoadCImmediate = 0x0E,
+    LoadDImmediate = 0x16,
+    LoadEImmediate = 0x1E,
+    LoadHImmediate = 0x26,
+    LoadLImmediate = 0x2E,
+    // High page loads
+    StoreHighPageA = 0xE0,
+    LoadAHighPage = 0xF0,
+    // Carry flag
+    SetCarryFlag = 0x37,
+    ComplementCarryFlag = 0x3F,
+    // Jumps
+    JumpRelative = 0x18,
+    JumpRelativeNz = 0x20,
+    JumpRelativeZ = 0x28,
+    JumpRelativeNc = 0x30,
+    JumpRelativeC = 0x38,
+    JumpImmediate = 0xC3,
+    JumpNz = 0xC2,
+    JumpZ = 0xCA,
+    JumpNc = 0xD2,
+    JumpC = 0xDA,
+    // Calls and returns
+    Call = 0xCD,
+    CallNz = 0xC4,
+    CallZ = 0xCC,
+    CallNc = 0xD4,
+    CallC = 0xDC,
+    Return = 0xC9,
+    ReturnNz = 0xC0,
+    ReturnZ = 0xC8,
+    ReturnNc = 0xD0,
+    ReturnC = 0xD8,
     // the LD B X instructions
     LoadBB = 0x40,
     LoadBC = 0x41,
@@ -177,6 +754,40 @@ enum Instruction {
     CpAH = 0xBC,
     CpAL = 0xBD,
     CpAA = 0xBF,
+    // Prefix for the extended instruction set
+    Prefix = 0xCB,
+}
+
+// Instructions that follow a 0xCB prefix byte
+#[derive(FromPrimitive)]
+enum PrefixedInstruction {
+    // SLA r instructions
+    SlaB = 0x20,
+    SlaC = 0x21,
+    SlaD = 0x22,
+    SlaE = 0x23,
+    SlaH = 0x24,
+    SlaL = 0x25,
+    SlaA = 0x27,
+    SlaHl = 0x26,
+    // SRA r instructions
+    SraB = 0x28,
+    SraC = 0x29,
+    SraD = 0x2A,
+    SraE = 0x2B,
+    SraH = 0x2C,
+    SraL = 0x2D,
+    SraA = 0x2F,
+    SraHl = 0x2E,
+    // SRL r instructions
+    SrlB = 0x38,
+    SrlC = 0x39,
+    SrlD = 0x3A,
+    SrlE = 0x3B,
+    SrlH = 0x3C,
+    SrlL = 0x3D,
+    SrlA = 0x3F,
+    SrlHl = 0x3E,
 }
 
 bitflags! {
@@ -189,499 +800,2574 @@ bitflags! {
     }
 }
 
+// called with the pc and opcode of each instruction before it executes
+type PreHook<'a> = Box<dyn FnMut(u16, u8) + 'a>;
+// called with the number of cycles each instruction took once it finishes
+type PostHook<'a> = Box<dyn FnMut(u8) + 'a>;
+// called with the stack pointer when the stack moves somewhere it shouldn't
+type StackGuard<'a> = Box<dyn FnMut(u16) + 'a>;
+
+// a save state's CPU section, read and checked in full before any of it is
+// applied
+pub struct CpuState {
+    // a, b, c, d, e, h, l, w and z in the order they're saved
+    registers: [u8; 9],
+    f: u8,
+    sp: u16,
+    pc: u16,
+    locked: bool,
+    halted: bool,
+    ime: bool,
+    micro_op_queue: VecDeque<MicroOp>,
+    memory: memory::MemoryState,
+}
+
 pub struct Cpu<'a> {
     // General purpose registers
     a: u8,
     b: u8,
     d: u8,
     h: u8,
-    f: u8,
     c: u8,
     e: u8,
     l: u8,
+    // temporary registers for operands that span multiple micro ops
+    w: u8,
+    z: u8,
     // the program counter
     sp: u16,
     // this is the f register
     flags: CpuFlags,
     // the stack pointer
     pc: u16,
+    // set once an illegal opcode runs, after which the CPU never fetches
+    // again, the same as the hardware hanging
+    locked: bool,
+    // set by HALT until an interrupt is pending, machine cycles keep passing
+    // in the meantime so the peripherals can raise one
+    halted: bool,
+    // the interrupt master enable, without which requested interrupts are
+    // never serviced
+    ime: bool,
     // stores the micro ops that we need to execute
     micro_op_queue: VecDeque<MicroOp>,
+    // the most recently executed instructions, oldest first. Up to twice the
+    // capacity is held so the older half can be dropped in one go, which
+    // keeps recording cheap while the entries stay in a single slice
+    trace: Vec<TraceEntry>,
+    trace_capacity: usize,
+    pre_hook: Option<PreHook<'a>>,
+    post_hook: Option<PostHook<'a>>,
+    stack_guard: Option<StackGuard<'a>>,
+    // labels the disassembler shows in place of addresses
+    symbols: SymbolTable,
     memory: &'a mut memory::Memory,
 }
 
 impl<'a> Cpu<'a> {
+    // a bare CPU with every register cleared, running as whatever model
+    // memory was built for
     pub fn new(memory: &'a mut memory::Memory) -> Self {
         Cpu {
             a: 0,
             b: 0,
             d: 0,
             h: 0,
-            f: 0,
             c: 0,
             e: 0,
             l: 0,
+            w: 0,
+            z: 0,
+            locked: false,
+            halted: false,
+            ime: false,
             micro_op_queue: VecDeque::new(),
+            trace: Vec::new(),
+            trace_capacity: 0,
+            pre_hook: None,
+            post_hook: None,
+            stack_guard: None,
+            symbols: SymbolTable::new(),
             flags: CpuFlags::empty(),
             sp: INITIAL_SP,
             pc: INITIAL_PC,
-            memory: memory,
+            memory,
         }
     }
 
-    fn get_instruction(self: &Self) -> Instruction {
-        let data = self.memory.get_data(self.pc);
-        num::FromPrimitive::from_u8(data).unwrap()
-    }
-
     fn clear_flags(self: &mut Self) {
         self.flags = CpuFlags::empty();
     }
 
-    pub fn execute_instruction(self: &mut Self) {
-        match self.micro_op_queue.is_empty() {
-            true => self.fetch_and_execute_instruction(),
-            false => self.execute_micro_op(),
-        }
+    // the low nibble of F doesn't exist in hardware and always reads as 0
+    pub fn get_f(self: &Self) -> u8 {
+        self.flags.bits()
     }
 
-    fn get_bc(self: &Self) -> u16 {
-        ((self.b as u16) << 8) + (self.c as u16)
+    pub fn set_f(self: &mut Self, value: u8) {
+        self.flags = CpuFlags::from_bits_truncate(value);
     }
 
-    fn get_de(self: &Self) -> u16 {
-        ((self.d as u16) << 8) + (self.e as u16)
+    pub fn memory(self: &Self) -> &memory::Memory {
+        self.memory
     }
 
-    fn get_hl(self: &Self) -> u16 {
-        ((self.h as u16) << 8) + (self.l as u16)
+    pub fn memory_mut(self: &mut Self) -> &mut memory::Memory {
+        self.memory
     }
 
-    fn get_sp(self: &Self) -> u16 {
-        self.sp
+    pub fn pc(self: &Self) -> u16 {
+        self.pc
     }
 
-    fn set_bc(self: &mut Self, value: u16) {
-        self.b = (value >> 8) as u8;
-        self.c = (value & 0xFF) as u8;
+    // the registers in the log format gameboy-doctor compares against,
+    // followed by the four bytes at the pc
+    pub fn doctor_line(self: &Self) -> String {
+        let pcmem = [0, 1, 2, 3].map(|offset| self.memory.peek(self.pc.wrapping_add(offset)));
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.a,
+            self.get_f(),
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.sp,
+            self.pc,
+            pcmem[0],
+            pcmem[1],
+            pcmem[2],
+            pcmem[3]
+        )
     }
 
-    fn set_de(self: &mut Self, value: u16) {
-        self.d = (value >> 8) as u8;
-        self.e = (value & 0xFF) as u8;
+    // keeps the last n executed instructions, a capacity of 0 turns tracing off
+    pub fn set_trace_capacity(self: &mut Self, n: usize) {
+        self.trace_capacity = n;
+        if self.trace.len() > n {
+            self.trace.drain(..self.trace.len() - n);
+        }
     }
 
-    fn set_hl(self: &mut Self, value: u16) {
-        self.h = (value >> 8) as u8;
-        self.l = (value & 0xFF) as u8;
+    // oldest first
+    pub fn recent_trace(self: &Self) -> &[TraceEntry] {
+        let start = self.trace.len().saturating_sub(self.trace_capacity);
+        &self.trace[start..]
     }
 
-    fn set_sp(self: &mut Self, value: u16) {
-        self.sp = value;
+    pub fn load_symbols(self: &mut Self, sym: &str) {
+        self.symbols.load(sym);
     }
 
-    fn execute_micro_op(self: &mut Self) {
-        let micro_op = self.micro_op_queue.pop_front().unwrap();
+    // disassembles the instruction at address with its operands filled in,
+    // naming addresses after their labels when there is one
+    pub fn disassemble(self: &Self, address: u16) -> String {
+        self.disassemble_instruction(address).1
+    }
 
-        match micro_op {
-            MicroOp::LoadImmediate { destination } => {
-                let value = self.memory.get_data(self.pc);
-                match destination {
-                    EightBitRegister::A => self.a = value,
-                    EightBitRegister::B => self.b = value,
-                    EightBitRegister::D => self.d = value,
-                    EightBitRegister::H => self.h = value,
-                    EightBitRegister::F => self.f = value,
-                    EightBitRegister::C => self.c = value,
-                    EightBitRegister::E => self.e = value,
-                    EightBitRegister::L => self.l = value,
-                    EightBitRegister::S => self.sp = ((value as u16) << 8) + (self.sp & 0x00FF),
-                    EightBitRegister::P => self.sp = (self.sp & 0xFF00) + value as u16,
-                }
-            }
-            MicroOp::StoreToMemory { value, address } => {
-                self.memory.set_byte(address, value);
+    // disassembles from start until the block ends at an unconditional jump,
+    // a return or an opcode that can't be decoded, or after max instructions
+    pub fn disassemble_block(self: &Self, start: u16, max: usize) -> Vec<(u16, String)> {
+        let mut block = Vec::new();
+        let mut address = start;
+        while block.len() < max {
+            let (decoded, text, length) = self.disassemble_instruction(address);
+            block.push((address, text));
+            if matches!(
+                decoded,
+                DecodedInstruction::Jump
+                    | DecodedInstruction::RelativeJump
+                    | DecodedInstruction::Return
+                    | DecodedInstruction::Illegal { .. }
+                    | DecodedInstruction::Unimplemented { .. }
+                    | DecodedInstruction::UnimplementedPrefixed { .. }
+            ) {
+                break;
             }
-            MicroOp::StoreToSixteenBitRegister { register, value } => match register {
-                SixteenBitRegister::Bc => {
-                    self.set_bc(value);
-                }
-                SixteenBitRegister::Hl => {
-                    self.set_hl(value);
-                }
-                SixteenBitRegister::De => {
-                    self.set_de(value);
-                }
-                SixteenBitRegister::Sp => {
-                    self.set_sp(value);
-                }
-            },
+            address = address.wrapping_add(length);
         }
-        self.pc += 1;
+        block
     }
 
-    fn fetch_and_execute_instruction(self: &mut Self) {
-        let instruction = self.get_instruction();
-        self.pc += 1;
-        match instruction {
-            Instruction::NOP => {}
-            // LD rr,nn instruction
-            Instruction::LoadBcTwoByteImmediate => {
-                self.load_eight_bit_register_with_immediate(EightBitRegister::C);
-                self.load_eight_bit_register_with_immediate(EightBitRegister::B);
-            }
-            Instruction::LoadDeTwoByteImmediate => {
-                self.load_eight_bit_register_with_immediate(EightBitRegister::E);
-                self.load_eight_bit_register_with_immediate(EightBitRegister::D);
-            }
-            Instruction::LoadHlTwoByteImmediate => {
-                self.load_eight_bit_register_with_immediate(EightBitRegister::L);
-                self.load_eight_bit_register_with_immediate(EightBitRegister::H);
-            }
-            Instruction::LoadSpTwoByteImmediate => {
-                self.load_eight_bit_register_with_immediate(EightBitRegister::P);
-                self.load_eight_bit_register_with_immediate(EightBitRegister::S);
-            }
-            Instruction::StoreBcA => self.micro_op_queue.push_back(MicroOp::StoreToMemory {
-                value: self.a,
-                address: self.get_bc(),
-            }),
-            Instruction::StoreDeA => self.micro_op_queue.push_back(MicroOp::StoreToMemory {
-                value: self.a,
-                address: self.get_de(),
-            }),
-            Instruction::StoreHlPlusA => {
-                self.micro_op_queue.push_back(MicroOp::StoreToMemory {
-                    value: self.a,
-                    address: self.get_hl(),
-                });
-                self.set_hl(self.get_hl() + 1);
-            }
-            Instruction::StoreHlMinusA => {
-                self.micro_op_queue.push_back(MicroOp::StoreToMemory {
-                    value: self.a,
-                    address: self.get_hl(),
-                });
-                self.set_hl(self.get_hl() - 1);
-            }
-            // Inc rr
-            Instruction::IncBc => {
-                let new_value = self.get_bc().wrapping_add(1);
-                self.micro_op_queue
-                    .push_back(MicroOp::StoreToSixteenBitRegister {
-                        register: SixteenBitRegister::Bc,
-                        value: new_value,
-                    });
-            }
-            Instruction::IncDe => {
-                let new_value = self.get_de().wrapping_add(1);
-                self.micro_op_queue
-                    .push_back(MicroOp::StoreToSixteenBitRegister {
-                        register: SixteenBitRegister::De,
-                        value: new_value,
-                    });
-            }
-            Instruction::IncHl => {
-                let new_value = self.get_hl().wrapping_add(1);
-                self.micro_op_queue
-                    .push_back(MicroOp::StoreToSixteenBitRegister {
-                        register: SixteenBitRegister::Hl,
-                        value: new_value,
-                    });
-            }
-            Instruction::IncSp => {
-                let new_value = self.get_sp().wrapping_add(1);
-                self.micro_op_queue
-                    .push_back(MicroOp::StoreToSixteenBitRegister {
-                        register: SixteenBitRegister::Sp,
-                        value: new_value,
-                    });
+    // returns the decoded instruction at address, its text and its length
+    fn disassemble_instruction(self: &Self, address: u16) -> (DecodedInstruction, String, u16) {
+        let (decoded, operands) = match self.decode(self.memory.peek(address)) {
+            DecodedInstruction::Prefix => (
+                self.decode_prefixed(self.memory.peek(address.wrapping_add(1))),
+                address.wrapping_add(2),
+            ),
+            decoded => (decoded, address.wrapping_add(1)),
+        };
+
+        let text = decoded.to_string();
+        let byte = self.memory.peek(operands);
+        let word = u16::from_le_bytes([byte, self.memory.peek(operands.wrapping_add(1))]);
+        let length = operands.wrapping_sub(address);
+        let (text, length) = if text.contains("n16") {
+            (text.replace("n16", &self.address_name(word)), length + 2)
+        } else if text.contains("e8") {
+            // relative jumps are taken from the end of the instruction
+            let target = operands.wrapping_add(1).wrapping_add(byte as i8 as u16);
+            (text.replace("e8", &self.address_name(target)), length + 1)
+        } else if text.starts_with("LDH") {
+            let name = self.address_name(HIGH_PAGE + byte as u16);
+            (text.replace("n8", &name), length + 1)
+        } else if text.contains("n8") {
+            (text.replace("n8", &format!("${:02X}", byte)), length + 1)
+        } else {
+            (text, length)
+        };
+        (decoded, text, length)
+    }
+
+    // the address an instruction at pc would read, write or jump to given the
+    // current registers, or None for instructions that don't touch memory or
+    // branch. The operands are the bytes that follow the opcode
+    pub fn effective_address(self: &Self, opcode: u8, operands: &[u8]) -> Option<u16> {
+        let byte = operands.first().copied();
+        let word = match operands {
+            [low, high, ..] => Some(u16::from_le_bytes([*low, *high])),
+            _ => None,
+        };
+
+        match opcode {
+            // HALT sits in the middle of the LD r,r' block
+            0x76 => None,
+            0x02 | 0x0A => Some(self.get_bc()),
+            0x12 | 0x1A => Some(self.get_de()),
+            0x22 | 0x2A | 0x32 | 0x3A | 0x34 | 0x35 | 0x36 | 0xE9 => Some(self.get_hl()),
+            // LD r,(HL), LD (HL),r and the ALU ops on (HL)
+            0x40..=0xBF if opcode & 0x07 == 0x06 || opcode & 0xF8 == 0x70 => Some(self.get_hl()),
+            0x08 | 0xEA | 0xFA => word,
+            0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA | 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => word,
+            // relative jumps are taken from the end of the two byte instruction
+            0x18 | 0x20 | 0x28 | 0x30 | 0x38 => {
+                byte.map(|offset| self.pc.wrapping_add(2).wrapping_add(offset as i8 as u16))
             }
-            // Inc r
-            Instruction::IncA => self.a = self.a.wrapping_add(1),
-            Instruction::IncB => self.b = self.b.wrapping_add(1),
-            Instruction::IncC => self.c = self.c.wrapping_add(1),
-            Instruction::IncD => self.d = self.d.wrapping_add(1),
-            Instruction::IncE => self.e = self.e.wrapping_add(1),
-            Instruction::IncH => self.h = self.h.wrapping_add(1),
-            Instruction::IncL => self.l = self.l.wrapping_add(1),
-            // Implement the LD B X instructions
-            Instruction::LoadBB => self.b = self.b,
-            Instruction::LoadBC => self.b = self.c,
-            Instruction::LoadBD => self.b = self.d,
-            Instruction::LoadBE => self.b = self.e,
-            Instruction::LoadBH => self.b = self.h,
-            Instruction::LoadBL => self.b = self.l,
-            Instruction::LoadBA => self.b = self.a,
-            // Implement the LD C X instructions
-            Instruction::LoadCB => self.c = self.b,
-            Instruction::LoadCC => self.c = self.c,
-            Instruction::LoadCD => self.c = self.d,
-            Instruction::LoadCE => self.c = self.e,
-            Instruction::LoadCH => self.c = self.h,
-            Instruction::LoadCL => self.c = self.l,
-            Instruction::LoadCA => self.c = self.a,
-            // Implement the LD D X instructions
-            Instruction::LoadDB => self.d = self.b,
-            Instruction::LoadDC => self.d = self.c,
-            Instruction::LoadDD => self.d = self.d,
-            Instruction::LoadDE => self.d = self.e,
-            Instruction::LoadDH => self.d = self.h,
-            Instruction::LoadDL => self.d = self.l,
-            Instruction::LoadDA => self.d = self.a,
-            // Implement the LD E X instructions
-            Instruction::LoadEB => self.e = self.b,
-            Instruction::LoadEC => self.e = self.c,
-            Instruction::LoadED => self.e = self.d,
-            Instruction::LoadEE => self.e = self.e,
-            Instruction::LoadEH => self.e = self.h,
-            Instruction::LoadEL => self.e = self.l,
-            Instruction::LoadEA => self.e = self.a,
-            // Implement the LD H X instructions
-            Instruction::LoadHB => self.h = self.b,
-            Instruction::LoadHC => self.h = self.c,
-            Instruction::LoadHD => self.h = self.d,
-            Instruction::LoadHE => self.h = self.e,
-            Instruction::LoadHH => self.h = self.h,
-            Instruction::LoadHL => self.h = self.l,
-            Instruction::LoadHA => self.h = self.a,
-            // Implement the LD L X instructions
-            Instruction::LoadLB => self.l = self.b,
-            Instruction::LoadLC => self.l = self.c,
-            Instruction::LoadLD => self.l = self.d,
-            Instruction::LoadLE => self.l = self.e,
-            Instruction::LoadLH => self.l = self.h,
-            Instruction::LoadLL => self.l = self.l,
-            Instruction::LoadLA => self.l = self.a,
-            // Add A X instruction
-            Instruction::AddAB => self.a = self.add(self.a, self.b),
-            Instruction::AddAC => self.a = self.add(self.a, self.c),
-            Instruction::AddAD => self.a = self.add(self.a, self.d),
-            Instruction::AddAE => self.a = self.add(self.a, self.e),
-            Instruction::AddAH => self.a = self.add(self.a, self.h),
-            Instruction::AddAL => self.a = self.add(self.a, self.l),
-            Instruction::AddAA => self.a = self.add(self.a, self.a),
-            // Adc A X instruction
-            Instruction::AdcAB => self.a = self.adc(self.a, self.b),
-            Instruction::AdcAC => self.a = self.adc(self.a, self.c),
-            Instruction::AdcAD => self.a = self.adc(self.a, self.d),
-            Instruction::AdcAE => self.a = self.adc(self.a, self.e),
-            Instruction::AdcAH => self.a = self.adc(self.a, self.h),
-            Instruction::AdcAL => self.a = self.adc(self.a, self.l),
-            Instruction::AdcAA => self.a = self.adc(self.a, self.a),
-            // Sub A X instruction
-            Instruction::SubAB => self.a = self.sub(self.a, self.b),
-            Instruction::SubAC => self.a = self.sub(self.a, self.c),
-            Instruction::SubAD => self.a = self.sub(self.a, self.d),
-            Instruction::SubAE => self.a = self.sub(self.a, self.e),
-            Instruction::SubAH => self.a = self.sub(self.a, self.h),
-            Instruction::SubAL => self.a = self.sub(self.a, self.l),
-            Instruction::SubAA => self.a = self.sub(self.a, self.a),
-            // Sbc A X instruction
-            Instruction::SbcAB => self.a = self.sbc(self.a, self.b),
-            Instruction::SbcAC => self.a = self.sbc(self.a, self.c),
-            Instruction::SbcAD => self.a = self.sbc(self.a, self.d),
-            Instruction::SbcAE => self.a = self.sbc(self.a, self.e),
-            Instruction::SbcAH => self.a = self.sbc(self.a, self.h),
-            Instruction::SbcAL => self.a = self.sbc(self.a, self.l),
-            Instruction::SbcAA => self.a = self.sbc(self.a, self.a),
-            // And A X instruction
-            Instruction::AndAB => self.a = self.and(self.a, self.b),
-            Instruction::AndAC => self.a = self.and(self.a, self.c),
-            Instruction::AndAD => self.a = self.and(self.a, self.d),
-            Instruction::AndAE => self.a = self.and(self.a, self.e),
-            Instruction::AndAH => self.a = self.and(self.a, self.h),
-            Instruction::AndAL => self.a = self.and(self.a, self.l),
-            Instruction::AndAA => self.a = self.and(self.a, self.a),
-            // Or A X instruction
-            Instruction::OrAB => self.a = self.or(self.a, self.b),
-            Instruction::OrAC => self.a = self.or(self.a, self.c),
-            Instruction::OrAD => self.a = self.or(self.a, self.d),
-            Instruction::OrAE => self.a = self.or(self.a, self.e),
-            Instruction::OrAH => self.a = self.or(self.a, self.h),
-            Instruction::OrAL => self.a = self.or(self.a, self.l),
-            Instruction::OrAA => self.a = self.or(self.a, self.a),
-            // Xor A X instruction
-            Instruction::XorAB => self.a = self.xor(self.a, self.b),
-            Instruction::XorAC => self.a = self.xor(self.a, self.c),
-            Instruction::XorAD => self.a = self.xor(self.a, self.d),
-            Instruction::XorAE => self.a = self.xor(self.a, self.e),
-            Instruction::XorAH => self.a = self.xor(self.a, self.h),
-            Instruction::XorAL => self.a = self.xor(self.a, self.l),
-            Instruction::XorAA => self.a = self.xor(self.a, self.a),
-            // Cp A X instruction
-            Instruction::CpAB => self.cp(self.a, self.b),
-            Instruction::CpAC => self.cp(self.a, self.c),
-            Instruction::CpAD => self.cp(self.a, self.d),
-            Instruction::CpAE => self.cp(self.a, self.e),
-            Instruction::CpAH => self.cp(self.a, self.h),
-            Instruction::CpAL => self.cp(self.a, self.l),
-            Instruction::CpAA => self.cp(self.a, self.a),
+            0xE0 | 0xF0 => byte.map(|offset| HIGH_PAGE + offset as u16),
+            0xE2 | 0xF2 => Some(HIGH_PAGE + self.c as u16),
+            // RST jumps to a fixed vector encoded in the opcode
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Some((opcode & 0x38) as u16),
+            // returns and pops read from the top of the stack, pushes write
+            // just below it
+            0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9 | 0xC1 | 0xD1 | 0xE1 | 0xF1 => Some(self.sp),
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => Some(self.sp.wrapping_sub(1)),
+            // prefixed instructions that work on (HL)
+            0xCB => byte
+                .filter(|prefixed| prefixed & 0x07 == 0x06)
+                .map(|_| self.get_hl()),
+            _ => None,
         }
     }
 
-    fn load_eight_bit_register_with_immediate(self: &mut Self, register: EightBitRegister) {
-        self.micro_op_queue.push_back(MicroOp::LoadImmediate {
-            destination: register,
-        });
+    // the label for an address in whichever ROM bank is currently mapped,
+    // or the address itself when there's no label for it
+    fn address_name(self: &Self, address: u16) -> String {
+        let bank = match address {
+            memory::ROM_BANK_N_START..memory::TILE_RAM_START => self.memory.rom_bank() as u8,
+            _ => 0,
+        };
+        match self.symbols.get(bank, address) {
+            Some(label) => label.to_string(),
+            None => format!("${:04X}", address),
+        }
     }
 
-    fn add(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
-        // this is ugly, but it's not something worth spending too long to make pretty
-        let half_carry: bool = (((value_one & 0xF) + (value_two & 0xF)) & 0x10) == 0x10;
-        let output: u16 = (value_one as u16) + (value_two as u16);
+    fn record_trace(self: &mut Self, pc: u16, opcode: u8, decoded: DecodedInstruction) {
+        if self.trace_capacity == 0 {
+            return;
+        }
+        if self.trace.len() >= 2 * self.trace_capacity {
+            self.trace.drain(..self.trace.len() - self.trace_capacity);
+        }
 
-        self.clear_flags();
+        // show the prefixed instruction rather than the prefix itself
+        let disassembly = match decoded {
+            DecodedInstruction::Prefix => self
+                .decode_prefixed(self.memory.peek(pc.wrapping_add(1)))
+                .to_string(),
+            _ => decoded.to_string(),
+        };
+        self.trace.push(TraceEntry {
+            pc,
+            opcode,
+            disassembly,
+        });
+    }
 
-        if output as u8 == 0 {
-            self.flags.set(CpuFlags::ZERO_FLAG, true);
-        }
+    // runs a full instruction, returning the number of cycles it took
+    pub fn step(self: &mut Self) -> Result<u8, EmuError> {
+        self.execute_instruction()?;
+        let mut cycles = CYCLES_PER_MICRO_OP;
 
-        if half_carry {
-            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+        while !self.micro_op_queue.is_empty() {
+            self.execute_instruction()?;
+            cycles += CYCLES_PER_MICRO_OP;
         }
 
-        if output > u8::MAX as u16 {
-            self.flags.set(CpuFlags::CARRY_FLAG, true);
+        if let Some(hook) = &mut self.post_hook {
+            hook(cycles);
         }
 
-        output as u8
+        Ok(cycles)
     }
 
-    fn adc(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
-        let mut carry: u16 = 0;
-
-        if self.flags.contains(CpuFlags::CARRY_FLAG) {
-            carry = 1;
-        }
+    pub fn set_pre_hook(self: &mut Self, f: impl FnMut(u16, u8) + 'a) {
+        self.pre_hook = Some(Box::new(f));
+    }
 
-        // this is ugly, but it's not something worth spending too long to make pretty
-        let half_carry: bool = (((value_one & 0xF) + (value_two & 0xF)) + carry as u8) > 0x0F;
-        let output: u16 = (value_one as u16) + (value_two as u16) + carry;
+    pub fn set_post_hook(self: &mut Self, f: impl FnMut(u8) + 'a) {
+        self.post_hook = Some(Box::new(f));
+    }
 
-        self.clear_flags();
+    // debugging aid that reports the stack pointer whenever a push or pop
+    // leaves it above where it started or down in the ROM
+    pub fn set_stack_guard(self: &mut Self, f: impl FnMut(u16) + 'a) {
+        self.stack_guard = Some(Box::new(f));
+    }
 
-        if output as u8 == 0 {
-            self.flags.set(CpuFlags::ZERO_FLAG, true);
+    fn check_stack(self: &mut Self) {
+        if let Some(guard) = &mut self.stack_guard {
+            if self.sp > INITIAL_SP || self.sp < memory::TILE_RAM_START {
+                guard(self.sp);
+            }
         }
+    }
 
-        self.flags.remove(CpuFlags::SUBTRACTION_FLAG);
+    // runs a single machine cycle
+    pub fn execute_instruction(self: &mut Self) -> Result<(), EmuError> {
+        // a locked CPU just lets the machine cycle pass, and so does a halted
+        // one until an interrupt wakes it
+        if self.locked {
+            return Ok(());
+        }
+        if self.halted {
+            if self.memory.pending_interrupts() == 0 {
+                return Ok(());
+            }
+            self.halted = false;
+        }
 
-        if half_carry {
-            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+        if self.micro_op_queue.is_empty() && self.service_interrupts() {
+            return Ok(());
         }
 
-        if output > u8::MAX as u16 {
+        match self.micro_op_queue.is_empty() {
+            true => self.fetch_and_execute_instruction(),
+            false => self.execute_micro_op(),
+        }
+    }
+
+    // with IME set, an interrupt that's pending at an instruction boundary is
+    // acknowledged and its vector called in place of the next fetch. That
+    // takes five machine cycles, the first spent here
+    fn service_interrupts(self: &mut Self) -> bool {
+        if !self.ime {
+            return false;
+        }
+        let pending = self.memory.pending_interrupts();
+        let Some(interrupt) = Interrupt::ALL
+            .into_iter()
+            .find(|interrupt| pending & interrupt.bit() != 0)
+        else {
+            return false;
+        };
+
+        self.ime = false;
+        self.memory.acknowledge_interrupt(interrupt);
+        let vector = interrupt.vector();
+        self.w = (vector >> 8) as u8;
+        self.z = vector as u8;
+        self.micro_op_queue.push_back(MicroOp::Internal);
+        self.micro_op_queue.push_back(MicroOp::Push {
+            value: (self.pc >> 8) as u8,
+        });
+        self.micro_op_queue.push_back(MicroOp::Push {
+            value: (self.pc & 0xFF) as u8,
+        });
+        self.micro_op_queue.push_back(MicroOp::Jump);
+        true
+    }
+
+    // the micro ops still queued are saved too, so a state taken partway
+    // through an instruction finishes it once loaded
+    pub fn save_state(self: &Self, writer: &mut StateWriter) {
+        for register in [
+            self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.w, self.z,
+        ] {
+            writer.write_u8(register);
+        }
+        writer.write_u8(self.get_f());
+        writer.write_u16(self.sp);
+        writer.write_u16(self.pc);
+        writer.write_bool(self.locked);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.ime);
+        writer.write_u8(self.micro_op_queue.len() as u8);
+        for micro_op in &self.micro_op_queue {
+            micro_op.save_state(writer);
+        }
+        self.memory.save_state(writer);
+    }
+
+    pub fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        let state = self.read_state(reader)?;
+        self.apply_state(state);
+        Ok(())
+    }
+
+    pub fn read_state(self: &Self, reader: &mut StateReader) -> Result<CpuState, StateError> {
+        let mut registers = [0; 9];
+        for register in registers.iter_mut() {
+            *register = reader.read_u8()?;
+        }
+        let f = reader.read_u8()?;
+        let sp = reader.read_u16()?;
+        let pc = reader.read_u16()?;
+        let locked = reader.read_bool()?;
+        let halted = reader.read_bool()?;
+        let ime = reader.read_bool()?;
+        let mut micro_op_queue = VecDeque::new();
+        for _ in 0..reader.read_u8()? {
+            micro_op_queue.push_back(MicroOp::load_state(reader)?);
+        }
+        let memory = self.memory.read_state(reader)?;
+
+        Ok(CpuState {
+            registers,
+            f,
+            sp,
+            pc,
+            locked,
+            halted,
+            ime,
+            micro_op_queue,
+            memory,
+        })
+    }
+
+    pub fn apply_state(self: &mut Self, state: CpuState) {
+        for (register, value) in [
+            &mut self.a,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.e,
+            &mut self.h,
+            &mut self.l,
+            &mut self.w,
+            &mut self.z,
+        ]
+        .into_iter()
+        .zip(state.registers)
+        {
+            *register = value;
+        }
+        self.set_f(state.f);
+        self.sp = state.sp;
+        self.pc = state.pc;
+        self.locked = state.locked;
+        self.halted = state.halted;
+        self.ime = state.ime;
+        self.micro_op_queue = state.micro_op_queue;
+        self.memory.apply_state(state.memory);
+    }
+
+    pub fn model(self: &Self) -> Model {
+        self.memory.model()
+    }
+
+    // a CPU emulating the given model, with the registers its boot ROM
+    // hands over to the cartridge with
+    pub fn with_model(memory: &'a mut memory::Memory, model: Model) -> Self {
+        memory.set_model(model);
+        let mut cpu = Cpu::new(memory);
+        cpu.set_boot_registers();
+        cpu
+    }
+
+    // puts the registers and I/O into the state the model's boot ROM hands
+    // over to the cartridge in
+    pub fn set_post_boot_state(self: &mut Self) {
+        self.set_boot_registers();
+        self.memory.set_post_boot_state();
+    }
+
+    // games tell the models apart by A, which is how CGB aware games know
+    // they can turn on color
+    fn set_boot_registers(self: &mut Self) {
+        let (af, bc, de, hl) = match self.model() {
+            Model::Dmg => (0x01B0, 0x0013, 0x00D8, 0x014D),
+            Model::Cgb => (0x1180, 0x0000, 0xFF56, 0x000D),
+            Model::Sgb => (0x0100, 0x0014, 0x0000, 0xC060),
+        };
+        self.set_af(af);
+        self.set_bc(bc);
+        self.set_de(de);
+        self.set_hl(hl);
+        self.sp = INITIAL_SP;
+        self.pc = INITIAL_PC;
+    }
+
+    pub fn is_locked(self: &Self) -> bool {
+        self.locked
+    }
+
+    pub fn is_halted(self: &Self) -> bool {
+        self.halted
+    }
+
+    pub fn ime(self: &Self) -> bool {
+        self.ime
+    }
+
+    pub fn set_ime(self: &mut Self, enabled: bool) {
+        self.ime = enabled;
+    }
+
+    pub fn interrupt_state(self: &Self) -> InterruptState {
+        InterruptState {
+            ime: self.ime,
+            ie: self.memory.ie(),
+            if_: self.memory.interrupt_flag(),
+            halted: self.halted,
+            pending: self.memory.pending_interrupts(),
+        }
+    }
+
+    // drops every requested interrupt, for getting a stuck debugging session
+    // past a request that keeps firing
+    pub fn clear_interrupt_requests(self: &mut Self) {
+        self.memory.set_interrupt_flag(0);
+    }
+
+    // raises an interrupt without the peripheral behind it, so dispatch can
+    // be tested on its own
+    pub fn request_interrupt(self: &mut Self, interrupt: Interrupt) {
+        self.memory.request_interrupt(interrupt.bit());
+    }
+
+    pub fn get_a(self: &Self) -> u8 {
+        self.a
+    }
+
+    pub fn get_b(self: &Self) -> u8 {
+        self.b
+    }
+
+    pub fn get_c(self: &Self) -> u8 {
+        self.c
+    }
+
+    pub fn get_d(self: &Self) -> u8 {
+        self.d
+    }
+
+    pub fn get_e(self: &Self) -> u8 {
+        self.e
+    }
+
+    pub fn get_h(self: &Self) -> u8 {
+        self.h
+    }
+
+    pub fn get_l(self: &Self) -> u8 {
+        self.l
+    }
+
+    // F only has its upper nibble, so the low nibble of AF is always 0
+    pub fn get_af(self: &Self) -> u16 {
+        ((self.a as u16) << 8) + (self.get_f() as u16)
+    }
+
+    pub fn get_bc(self: &Self) -> u16 {
+        ((self.b as u16) << 8) + (self.c as u16)
+    }
+
+    pub fn get_de(self: &Self) -> u16 {
+        ((self.d as u16) << 8) + (self.e as u16)
+    }
+
+    pub fn get_hl(self: &Self) -> u16 {
+        ((self.h as u16) << 8) + (self.l as u16)
+    }
+
+    // W and Z hold the high and low bytes of a 16-bit operand
+    fn get_wz(self: &Self) -> u16 {
+        ((self.w as u16) << 8) + (self.z as u16)
+    }
+
+    pub fn get_sp(self: &Self) -> u16 {
+        self.sp
+    }
+
+    fn set_af(self: &mut Self, value: u16) {
+        self.a = (value >> 8) as u8;
+        self.set_f((value & 0xFF) as u8);
+    }
+
+    fn set_bc(self: &mut Self, value: u16) {
+        self.b = (value >> 8) as u8;
+        self.c = (value & 0xFF) as u8;
+    }
+
+    fn set_de(self: &mut Self, value: u16) {
+        self.d = (value >> 8) as u8;
+        self.e = (value & 0xFF) as u8;
+    }
+
+    fn set_hl(self: &mut Self, value: u16) {
+        self.h = (value >> 8) as u8;
+        self.l = (value & 0xFF) as u8;
+    }
+
+    fn set_sp(self: &mut Self, value: u16) {
+        self.sp = value;
+    }
+
+    fn get_eight_bit_register(self: &Self, register: EightBitRegister) -> u8 {
+        match register {
+            EightBitRegister::A => self.a,
+            EightBitRegister::B => self.b,
+            EightBitRegister::D => self.d,
+            EightBitRegister::H => self.h,
+            EightBitRegister::F => self.get_f(),
+            EightBitRegister::C => self.c,
+            EightBitRegister::E => self.e,
+            EightBitRegister::L => self.l,
+            EightBitRegister::S => (self.sp >> 8) as u8,
+            EightBitRegister::P => (self.sp & 0xFF) as u8,
+            EightBitRegister::W => self.w,
+            EightBitRegister::Z => self.z,
+        }
+    }
+
+    fn set_eight_bit_register(self: &mut Self, register: EightBitRegister, value: u8) {
+        match register {
+            EightBitRegister::A => self.a = value,
+            EightBitRegister::B => self.b = value,
+            EightBitRegister::D => self.d = value,
+            EightBitRegister::H => self.h = value,
+            EightBitRegister::F => self.set_f(value),
+            EightBitRegister::C => self.c = value,
+            EightBitRegister::E => self.e = value,
+            EightBitRegister::L => self.l = value,
+            EightBitRegister::S => self.sp = ((value as u16) << 8) + (self.sp & 0x00FF),
+            EightBitRegister::P => self.sp = (self.sp & 0xFF00) + value as u16,
+            EightBitRegister::W => self.w = value,
+            EightBitRegister::Z => self.z = value,
+        }
+    }
+
+    fn get_sixteen(self: &Self, register: SixteenBitRegister) -> u16 {
+        match register {
+            SixteenBitRegister::Af => self.get_af(),
+            SixteenBitRegister::Bc => self.get_bc(),
+            SixteenBitRegister::De => self.get_de(),
+            SixteenBitRegister::Hl => self.get_hl(),
+            SixteenBitRegister::Sp => self.get_sp(),
+        }
+    }
+
+    fn set_sixteen(self: &mut Self, register: SixteenBitRegister, value: u16) {
+        match register {
+            SixteenBitRegister::Af => self.set_af(value),
+            SixteenBitRegister::Bc => self.set_bc(value),
+            SixteenBitRegister::De => self.set_de(value),
+            SixteenBitRegister::Hl => self.set_hl(value),
+            SixteenBitRegister::Sp => self.set_sp(value),
+        }
+    }
+
+    fn execute_micro_op(self: &mut Self) -> Result<(), EmuError> {
+        let micro_op = self.micro_op_queue.pop_front().unwrap();
+
+        match micro_op {
+            MicroOp::LoadImmediate { destination } => {
+                let value = self.memory.get_data(self.pc);
+                self.pc = self.pc.wrapping_add(1);
+                self.set_eight_bit_register(destination, value);
+            }
+            MicroOp::StoreToMemory { value, address } => {
+                self.memory.set_byte(address, value);
+            }
+            MicroOp::StoreWordToMemory { value } => {
+                self.memory.write_word(self.get_wz(), value);
+            }
+            MicroOp::Internal => {}
+            MicroOp::ReadModify { operation } => {
+                let value = self.memory.get_data(self.get_hl());
+                self.z = match operation {
+                    ReadModifyWriteOp::Increment => self.inc_eight_bit(value),
+                    ReadModifyWriteOp::Decrement => self.dec_eight_bit(value),
+                    ReadModifyWriteOp::ShiftLeftArithmetic => self.sla(value),
+                    ReadModifyWriteOp::ShiftRightArithmetic => self.sra(value),
+                    ReadModifyWriteOp::ShiftRightLogical => self.srl(value),
+                };
+            }
+            MicroOp::WriteBack => {
+                self.memory.set_byte(self.get_hl(), self.z);
+            }
+            MicroOp::StoreToSixteenBitRegister { register, value } => {
+                self.set_sixteen(register, value);
+            }
+            MicroOp::ExecutePrefixed => {
+                // errors point at the prefix rather than the second byte
+                let pc = self.pc.wrapping_sub(1);
+                let opcode = self.memory.get_data(self.pc);
+                self.pc = self.pc.wrapping_add(1);
+                let decoded = self.decode_prefixed(opcode);
+                return self.execute(pc, decoded);
+            }
+            MicroOp::StoreToHighPage { value } => {
+                self.memory.set_byte(HIGH_PAGE + self.z as u16, value);
+            }
+            MicroOp::LoadFromHighPage { destination } => {
+                let value = self.memory.get_data(HIGH_PAGE + self.z as u16);
+                self.set_eight_bit_register(destination, value);
+            }
+            MicroOp::RelativeJump => {
+                self.pc = self.pc.wrapping_add_signed(self.z as i8 as i16);
+            }
+            MicroOp::Jump => {
+                self.pc = self.get_wz();
+            }
+            MicroOp::Push { value } => {
+                self.sp = self.sp.wrapping_sub(1);
+                self.memory.set_byte(self.sp, value);
+                self.check_stack();
+            }
+            MicroOp::Pop { destination } => {
+                let value = self.memory.get_data(self.sp);
+                self.sp = self.sp.wrapping_add(1);
+                self.set_eight_bit_register(destination, value);
+                self.check_stack();
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_and_execute_instruction(self: &mut Self) -> Result<(), EmuError> {
+        let pc = self.pc;
+        let opcode = self.memory.get_data(pc);
+        // the address space is a ring, so running off the end carries on at 0
+        self.pc = self.pc.wrapping_add(1);
+        if let Some(hook) = &mut self.pre_hook {
+            hook(pc, opcode);
+        }
+        let decoded = self.decode(opcode);
+        self.record_trace(pc, opcode, decoded);
+        self.execute(pc, decoded)
+    }
+
+    fn decode(self: &Self, opcode: u8) -> DecodedInstruction {
+        Cpu::decode_opcode(opcode)
+    }
+
+    // decoding doesn't depend on any CPU state, so it's also used to name
+    // instructions outside of a running CPU
+    fn decode_opcode(opcode: u8) -> DecodedInstruction {
+        if ILLEGAL_OPCODES.contains(&opcode) {
+            return DecodedInstruction::Illegal { opcode };
+        }
+
+        let instruction: Instruction = match num::FromPrimitive::from_u8(opcode) {
+            Some(instruction) => instruction,
+            None => return DecodedInstruction::Unimplemented { opcode },
+        };
+
+        match instruction {
+            Instruction::Nop => DecodedInstruction::Nop,
+            Instruction::Stop => DecodedInstruction::Stop,
+            Instruction::Halt => DecodedInstruction::Halt,
+            // LD rr,nn instruction
+            Instruction::LoadBcTwoByteImmediate => DecodedInstruction::LoadSixteenBitImmediate {
+                register: SixteenBitRegister::Bc,
+            },
+            Instruction::LoadDeTwoByteImmediate => DecodedInstruction::LoadSixteenBitImmediate {
+                register: SixteenBitRegister::De,
+            },
+            Instruction::LoadHlTwoByteImmediate => DecodedInstruction::LoadSixteenBitImmediate {
+                register: SixteenBitRegister::Hl,
+            },
+            Instruction::LoadSpTwoByteImmediate => DecodedInstruction::LoadSixteenBitImmediate {
+                register: SixteenBitRegister::Sp,
+            },
+            Instruction::StoreSp => DecodedInstruction::StoreStackPointer,
+            // Store from accumulator
+            Instruction::StoreBcA => DecodedInstruction::StoreAccumulator {
+                address: IndirectAddress::Bc,
+            },
+            Instruction::StoreDeA => DecodedInstruction::StoreAccumulator {
+                address: IndirectAddress::De,
+            },
+            Instruction::StoreHlPlusA => DecodedInstruction::StoreAccumulator {
+                address: IndirectAddress::HlIncrement,
+            },
+            Instruction::StoreHlMinusA => DecodedInstruction::StoreAccumulator {
+                address: IndirectAddress::HlDecrement,
+            },
+            // Inc rr
+            Instruction::IncBc => DecodedInstruction::IncrementSixteenBit {
+                register: SixteenBitRegister::Bc,
+            },
+            Instruction::IncDe => DecodedInstruction::IncrementSixteenBit {
+                register: SixteenBitRegister::De,
+            },
+            Instruction::IncHl => DecodedInstruction::IncrementSixteenBit {
+                register: SixteenBitRegister::Hl,
+            },
+            Instruction::IncSp => DecodedInstruction::IncrementSixteenBit {
+                register: SixteenBitRegister::Sp,
+            },
+            // Dec rr
+            Instruction::DecBc => DecodedInstruction::DecrementSixteenBit {
+                register: SixteenBitRegister::Bc,
+            },
+            Instruction::DecDe => DecodedInstruction::DecrementSixteenBit {
+                register: SixteenBitRegister::De,
+            },
+            Instruction::DecHl => DecodedInstruction::DecrementSixteenBit {
+                register: SixteenBitRegister::Hl,
+            },
+            Instruction::DecSp => DecodedInstruction::DecrementSixteenBit {
+                register: SixteenBitRegister::Sp,
+            },
+            // Inc r
+            Instruction::IncA => DecodedInstruction::IncrementEightBit {
+                register: EightBitRegister::A,
+            },
+            Instruction::IncB => DecodedInstruction::IncrementEightBit {
+                register: EightBitRegister::B,
+            },
+            Instruction::IncC => DecodedInstruction::IncrementEightBit {
+                register: EightBitRegister::C,
+            },
+            Instruction::IncD => DecodedInstruction::IncrementEightBit {
+                register: EightBitRegister::D,
+            },
+            Instruction::IncE => DecodedInstruction::IncrementEightBit {
+                register: EightBitRegister::E,
+            },
+            Instruction::IncH => DecodedInstruction::IncrementEightBit {
+                register: EightBitRegister::H,
+            },
+            Instruction::IncL => DecodedInstruction::IncrementEightBit {
+                register: EightBitRegister::L,
+            },
+            // read-modify-write on (HL)
+            Instruction::IncHlIndirect => DecodedInstruction::ReadModifyWrite {
+                operation: ReadModifyWriteOp::Increment,
+            },
+            Instruction::DecHlIndirect => DecodedInstruction::ReadModifyWrite {
+                operation: ReadModifyWriteOp::Decrement,
+            },
+            Instruction::StoreHlImmediate => DecodedInstruction::StoreHlImmediate,
+            // LD r,n instructions
+            Instruction::LoadAImmediate => DecodedInstruction::LoadImmediate {
+                destination: EightBitRegister::A,
+            },
+            Instruction::LoadBImmediate => DecodedInstruction::LoadImmediate {
+                destination: EightBitRegister::B,
+            },
+            Instruction::LoadCImmediate => DecodedInstruction::LoadImmediate {
+                destination: EightBitRegister::C,
+            },
+            Instruction::LoadDImmediate => DecodedInstruction::LoadImmediate {
+                destination: EightBitRegister::D,
+            },
+            Instruction::LoadEImmediate => DecodedInstruction::LoadImmediate {
+                destination: EightBitRegister::E,
+            },
+            Instruction::LoadHImmediate => DecodedInstruction::LoadImmediate {
+                destination: EightBitRegister::H,
+            },
+            Instruction::LoadLImmediate => DecodedInstruction::LoadImmediate {
+                destination: EightBitRegister::L,
+            },
+            // High page loads
+            Instruction::StoreHighPageA => DecodedInstruction::StoreAccumulatorHighPage,
+            Instruction::LoadAHighPage => DecodedInstruction::LoadAccumulatorHighPage,
+            // Carry flag
+            Instruction::SetCarryFlag => DecodedInstruction::SetCarryFlag,
+            Instruction::ComplementCarryFlag => DecodedInstruction::ComplementCarryFlag,
+            // Jumps
+            Instruction::JumpRelative => DecodedInstruction::RelativeJump,
+            Instruction::JumpRelativeNz => DecodedInstruction::ConditionalRelativeJump {
+                condition: Condition::NotZero,
+            },
+            Instruction::JumpRelativeZ => DecodedInstruction::ConditionalRelativeJump {
+                condition: Condition::Zero,
+            },
+            Instruction::JumpRelativeNc => DecodedInstruction::ConditionalRelativeJump {
+                condition: Condition::NotCarry,
+            },
+            Instruction::JumpRelativeC => DecodedInstruction::ConditionalRelativeJump {
+                condition: Condition::Carry,
+            },
+            Instruction::JumpImmediate => DecodedInstruction::Jump,
+            Instruction::JumpNz => DecodedInstruction::ConditionalJump {
+                condition: Condition::NotZero,
+            },
+            Instruction::JumpZ => DecodedInstruction::ConditionalJump {
+                condition: Condition::Zero,
+            },
+            Instruction::JumpNc => DecodedInstruction::ConditionalJump {
+                condition: Condition::NotCarry,
+            },
+            Instruction::JumpC => DecodedInstruction::ConditionalJump {
+                condition: Condition::Carry,
+            },
+            // Calls and returns
+            Instruction::Call => DecodedInstruction::Call,
+            Instruction::CallNz => DecodedInstruction::ConditionalCall {
+                condition: Condition::NotZero,
+            },
+            Instruction::CallZ => DecodedInstruction::ConditionalCall {
+                condition: Condition::Zero,
+            },
+            Instruction::CallNc => DecodedInstruction::ConditionalCall {
+                condition: Condition::NotCarry,
+            },
+            Instruction::CallC => DecodedInstruction::ConditionalCall {
+                condition: Condition::Carry,
+            },
+            Instruction::Return => DecodedInstruction::Return,
+            Instruction::ReturnNz => DecodedInstruction::ConditionalReturn {
+                condition: Condition::NotZero,
+            },
+            Instruction::ReturnZ => DecodedInstruction::ConditionalReturn {
+                condition: Condition::Zero,
+            },
+            Instruction::ReturnNc => DecodedInstruction::ConditionalReturn {
+                condition: Condition::NotCarry,
+            },
+            Instruction::ReturnC => DecodedInstruction::ConditionalReturn {
+                condition: Condition::Carry,
+            },
+            // the LD B X instructions
+            Instruction::LoadBB => DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::B,
+            },
+            Instruction::LoadBC => DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::C,
+            },
+            Instruction::LoadBD => DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::D,
+            },
+            Instruction::LoadBE => DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::E,
+            },
+            Instruction::LoadBH => DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::H,
+            },
+            Instruction::LoadBL => DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::L,
+            },
+            Instruction::LoadBA => DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::A,
+            },
+            // the LD C X instructions
+            Instruction::LoadCB => DecodedInstruction::Load {
+                destination: EightBitRegister::C,
+                source: EightBitRegister::B,
+            },
+            Instruction::LoadCC => DecodedInstruction::Load {
+                destination: EightBitRegister::C,
+                source: EightBitRegister::C,
+            },
+            Instruction::LoadCD => DecodedInstruction::Load {
+                destination: EightBitRegister::C,
+                source: EightBitRegister::D,
+            },
+            Instruction::LoadCE => DecodedInstruction::Load {
+                destination: EightBitRegister::C,
+                source: EightBitRegister::E,
+            },
+            Instruction::LoadCH => DecodedInstruction::Load {
+                destination: EightBitRegister::C,
+                source: EightBitRegister::H,
+            },
+            Instruction::LoadCL => DecodedInstruction::Load {
+                destination: EightBitRegister::C,
+                source: EightBitRegister::L,
+            },
+            Instruction::LoadCA => DecodedInstruction::Load {
+                destination: EightBitRegister::C,
+                source: EightBitRegister::A,
+            },
+            // the LD D X instructions
+            Instruction::LoadDB => DecodedInstruction::Load {
+                destination: EightBitRegister::D,
+                source: EightBitRegister::B,
+            },
+            Instruction::LoadDC => DecodedInstruction::Load {
+                destination: EightBitRegister::D,
+                source: EightBitRegister::C,
+            },
+            Instruction::LoadDD => DecodedInstruction::Load {
+                destination: EightBitRegister::D,
+                source: EightBitRegister::D,
+            },
+            Instruction::LoadDE => DecodedInstruction::Load {
+                destination: EightBitRegister::D,
+                source: EightBitRegister::E,
+            },
+            Instruction::LoadDH => DecodedInstruction::Load {
+                destination: EightBitRegister::D,
+                source: EightBitRegister::H,
+            },
+            Instruction::LoadDL => DecodedInstruction::Load {
+                destination: EightBitRegister::D,
+                source: EightBitRegister::L,
+            },
+            Instruction::LoadDA => DecodedInstruction::Load {
+                destination: EightBitRegister::D,
+                source: EightBitRegister::A,
+            },
+            // the LD E X instructions
+            Instruction::LoadEB => DecodedInstruction::Load {
+                destination: EightBitRegister::E,
+                source: EightBitRegister::B,
+            },
+            Instruction::LoadEC => DecodedInstruction::Load {
+                destination: EightBitRegister::E,
+                source: EightBitRegister::C,
+            },
+            Instruction::LoadED => DecodedInstruction::Load {
+                destination: EightBitRegister::E,
+                source: EightBitRegister::D,
+            },
+            Instruction::LoadEE => DecodedInstruction::Load {
+                destination: EightBitRegister::E,
+                source: EightBitRegister::E,
+            },
+            Instruction::LoadEH => DecodedInstruction::Load {
+                destination: EightBitRegister::E,
+                source: EightBitRegister::H,
+            },
+            Instruction::LoadEL => DecodedInstruction::Load {
+                destination: EightBitRegister::E,
+                source: EightBitRegister::L,
+            },
+            Instruction::LoadEA => DecodedInstruction::Load {
+                destination: EightBitRegister::E,
+                source: EightBitRegister::A,
+            },
+            // the LD H X instructions
+            Instruction::LoadHB => DecodedInstruction::Load {
+                destination: EightBitRegister::H,
+                source: EightBitRegister::B,
+            },
+            Instruction::LoadHC => DecodedInstruction::Load {
+                destination: EightBitRegister::H,
+                source: EightBitRegister::C,
+            },
+            Instruction::LoadHD => DecodedInstruction::Load {
+                destination: EightBitRegister::H,
+                source: EightBitRegister::D,
+            },
+            Instruction::LoadHE => DecodedInstruction::Load {
+                destination: EightBitRegister::H,
+                source: EightBitRegister::E,
+            },
+            Instruction::LoadHH => DecodedInstruction::Load {
+                destination: EightBitRegister::H,
+                source: EightBitRegister::H,
+            },
+            Instruction::LoadHL => DecodedInstruction::Load {
+                destination: EightBitRegister::H,
+                source: EightBitRegister::L,
+            },
+            Instruction::LoadHA => DecodedInstruction::Load {
+                destination: EightBitRegister::H,
+                source: EightBitRegister::A,
+            },
+            // the LD L X instructions
+            Instruction::LoadLB => DecodedInstruction::Load {
+                destination: EightBitRegister::L,
+                source: EightBitRegister::B,
+            },
+            Instruction::LoadLC => DecodedInstruction::Load {
+                destination: EightBitRegister::L,
+                source: EightBitRegister::C,
+            },
+            Instruction::LoadLD => DecodedInstruction::Load {
+                destination: EightBitRegister::L,
+                source: EightBitRegister::D,
+            },
+            Instruction::LoadLE => DecodedInstruction::Load {
+                destination: EightBitRegister::L,
+                source: EightBitRegister::E,
+            },
+            Instruction::LoadLH => DecodedInstruction::Load {
+                destination: EightBitRegister::L,
+                source: EightBitRegister::H,
+            },
+            Instruction::LoadLL => DecodedInstruction::Load {
+                destination: EightBitRegister::L,
+                source: EightBitRegister::L,
+            },
+            Instruction::LoadLA => DecodedInstruction::Load {
+                destination: EightBitRegister::L,
+                source: EightBitRegister::A,
+            },
+            // the ALU instructions on registers
+            Instruction::AddAB
+            | Instruction::AddAC
+            | Instruction::AddAD
+            | Instruction::AddAE
+            | Instruction::AddAH
+            | Instruction::AddAL
+            | Instruction::AddAA
+            | Instruction::AdcAB
+            | Instruction::AdcAC
+            | Instruction::AdcAD
+            | Instruction::AdcAE
+            | Instruction::AdcAH
+            | Instruction::AdcAL
+            | Instruction::AdcAA
+            | Instruction::SubAB
+            | Instruction::SubAC
+            | Instruction::SubAD
+            | Instruction::SubAE
+            | Instruction::SubAH
+            | Instruction::SubAL
+            | Instruction::SubAA
+            | Instruction::SbcAB
+            | Instruction::SbcAC
+            | Instruction::SbcAD
+            | Instruction::SbcAE
+            | Instruction::SbcAH
+            | Instruction::SbcAL
+            | Instruction::SbcAA
+            | Instruction::AndAB
+            | Instruction::AndAC
+            | Instruction::AndAD
+            | Instruction::AndAE
+            | Instruction::AndAH
+            | Instruction::AndAL
+            | Instruction::AndAA
+            | Instruction::OrAB
+            | Instruction::OrAC
+            | Instruction::OrAD
+            | Instruction::OrAE
+            | Instruction::OrAH
+            | Instruction::OrAL
+            | Instruction::OrAA
+            | Instruction::XorAB
+            | Instruction::XorAC
+            | Instruction::XorAD
+            | Instruction::XorAE
+            | Instruction::XorAH
+            | Instruction::XorAL
+            | Instruction::XorAA
+            | Instruction::CpAB
+            | Instruction::CpAC
+            | Instruction::CpAD
+            | Instruction::CpAE
+            | Instruction::CpAH
+            | Instruction::CpAL
+            | Instruction::CpAA => DecodedInstruction::Alu {
+                operation: AluOp::from_opcode(opcode),
+                source: Cpu::alu_source(opcode),
+            },
+            Instruction::Prefix => DecodedInstruction::Prefix,
+        }
+    }
+
+    // the low 3 bits of an ALU opcode pick its register operand. 6 would be
+    // (HL), but those opcodes aren't register ALU instructions and never
+    // decode to one
+    fn alu_source(opcode: u8) -> EightBitRegister {
+        match opcode & 0x07 {
+            0 => EightBitRegister::B,
+            1 => EightBitRegister::C,
+            2 => EightBitRegister::D,
+            3 => EightBitRegister::E,
+            4 => EightBitRegister::H,
+            5 => EightBitRegister::L,
+            7 => EightBitRegister::A,
+            _ => unreachable!("{:#04X} takes its ALU operand from (HL)", opcode),
+        }
+    }
+
+    fn decode_prefixed(self: &Self, opcode: u8) -> DecodedInstruction {
+        let instruction: PrefixedInstruction = match num::FromPrimitive::from_u8(opcode) {
+            Some(instruction) => instruction,
+            None => return DecodedInstruction::UnimplementedPrefixed { opcode },
+        };
+
+        match instruction {
+            // SLA r instructions
+            PrefixedInstruction::SlaB => DecodedInstruction::ShiftLeftArithmetic {
+                register: EightBitRegister::B,
+            },
+            PrefixedInstruction::SlaC => DecodedInstruction::ShiftLeftArithmetic {
+                register: EightBitRegister::C,
+            },
+            PrefixedInstruction::SlaD => DecodedInstruction::ShiftLeftArithmetic {
+                register: EightBitRegister::D,
+            },
+            PrefixedInstruction::SlaE => DecodedInstruction::ShiftLeftArithmetic {
+                register: EightBitRegister::E,
+            },
+            PrefixedInstruction::SlaH => DecodedInstruction::ShiftLeftArithmetic {
+                register: EightBitRegister::H,
+            },
+            PrefixedInstruction::SlaL => DecodedInstruction::ShiftLeftArithmetic {
+                register: EightBitRegister::L,
+            },
+            PrefixedInstruction::SlaA => DecodedInstruction::ShiftLeftArithmetic {
+                register: EightBitRegister::A,
+            },
+            PrefixedInstruction::SlaHl => DecodedInstruction::ReadModifyWrite {
+                operation: ReadModifyWriteOp::ShiftLeftArithmetic,
+            },
+            // SRA r instructions
+            PrefixedInstruction::SraB => DecodedInstruction::ShiftRightArithmetic {
+                register: EightBitRegister::B,
+            },
+            PrefixedInstruction::SraC => DecodedInstruction::ShiftRightArithmetic {
+                register: EightBitRegister::C,
+            },
+            PrefixedInstruction::SraD => DecodedInstruction::ShiftRightArithmetic {
+                register: EightBitRegister::D,
+            },
+            PrefixedInstruction::SraE => DecodedInstruction::ShiftRightArithmetic {
+                register: EightBitRegister::E,
+            },
+            PrefixedInstruction::SraH => DecodedInstruction::ShiftRightArithmetic {
+                register: EightBitRegister::H,
+            },
+            PrefixedInstruction::SraL => DecodedInstruction::ShiftRightArithmetic {
+                register: EightBitRegister::L,
+            },
+            PrefixedInstruction::SraA => DecodedInstruction::ShiftRightArithmetic {
+                register: EightBitRegister::A,
+            },
+            PrefixedInstruction::SraHl => DecodedInstruction::ReadModifyWrite {
+                operation: ReadModifyWriteOp::ShiftRightArithmetic,
+            },
+            // SRL r instructions
+            PrefixedInstruction::SrlB => DecodedInstruction::ShiftRightLogical {
+                register: EightBitRegister::B,
+            },
+            PrefixedInstruction::SrlC => DecodedInstruction::ShiftRightLogical {
+                register: EightBitRegister::C,
+            },
+            PrefixedInstruction::SrlD => DecodedInstruction::ShiftRightLogical {
+                register: EightBitRegister::D,
+            },
+            PrefixedInstruction::SrlE => DecodedInstruction::ShiftRightLogical {
+                register: EightBitRegister::E,
+            },
+            PrefixedInstruction::SrlH => DecodedInstruction::ShiftRightLogical {
+                register: EightBitRegister::H,
+            },
+            PrefixedInstruction::SrlL => DecodedInstruction::ShiftRightLogical {
+                register: EightBitRegister::L,
+            },
+            PrefixedInstruction::SrlA => DecodedInstruction::ShiftRightLogical {
+                register: EightBitRegister::A,
+            },
+            PrefixedInstruction::SrlHl => DecodedInstruction::ReadModifyWrite {
+                operation: ReadModifyWriteOp::ShiftRightLogical,
+            },
+        }
+    }
+
+    // pc is the address the instruction started at, used for reporting errors
+    fn execute(self: &mut Self, pc: u16, decoded: DecodedInstruction) -> Result<(), EmuError> {
+        match decoded {
+            DecodedInstruction::Nop => {}
+            DecodedInstruction::Stop => {
+                // STOP is followed by a padding byte that gets skipped. Low
+                // power mode isn't modeled, so STOP only matters for the CGB
+                // speed switch
+                self.pc = self.pc.wrapping_add(1);
+                self.memory.stop();
+            }
+            DecodedInstruction::Halt => self.halted = true,
+            DecodedInstruction::LoadSixteenBitImmediate { register } => {
+                // the low byte comes first in memory
+                let (low, high) = match register {
+                    SixteenBitRegister::Af => (EightBitRegister::F, EightBitRegister::A),
+                    SixteenBitRegister::Bc => (EightBitRegister::C, EightBitRegister::B),
+                    SixteenBitRegister::De => (EightBitRegister::E, EightBitRegister::D),
+                    SixteenBitRegister::Hl => (EightBitRegister::L, EightBitRegister::H),
+                    SixteenBitRegister::Sp => (EightBitRegister::P, EightBitRegister::S),
+                };
+                self.load_eight_bit_register_with_immediate(low);
+                self.load_eight_bit_register_with_immediate(high);
+            }
+            DecodedInstruction::StoreAccumulator { address } => {
+                let target = match address {
+                    IndirectAddress::Bc => self.get_bc(),
+                    IndirectAddress::De => self.get_de(),
+                    IndirectAddress::HlIncrement | IndirectAddress::HlDecrement => self.get_hl(),
+                };
+                self.micro_op_queue.push_back(MicroOp::StoreToMemory {
+                    value: self.a,
+                    address: target,
+                });
+                match address {
+                    IndirectAddress::HlIncrement => self.set_hl(self.get_hl().wrapping_add(1)),
+                    IndirectAddress::HlDecrement => self.set_hl(self.get_hl().wrapping_sub(1)),
+                    _ => {}
+                }
+            }
+            // unlike the 8-bit versions, 16-bit INC and DEC never touch the flags
+            DecodedInstruction::IncrementSixteenBit { register } => {
+                let new_value = self.get_sixteen(register).wrapping_add(1);
+                self.micro_op_queue
+                    .push_back(MicroOp::StoreToSixteenBitRegister {
+                        register,
+                        value: new_value,
+                    });
+            }
+            DecodedInstruction::DecrementSixteenBit { register } => {
+                let new_value = self.get_sixteen(register).wrapping_sub(1);
+                self.micro_op_queue
+                    .push_back(MicroOp::StoreToSixteenBitRegister {
+                        register,
+                        value: new_value,
+                    });
+            }
+            DecodedInstruction::IncrementEightBit { register } => {
+                let new_value = self.inc_eight_bit(self.get_eight_bit_register(register));
+                self.set_eight_bit_register(register, new_value);
+            }
+            DecodedInstruction::Load {
+                destination,
+                source,
+            } => {
+                let value = self.get_eight_bit_register(source);
+                self.set_eight_bit_register(destination, value);
+            }
+            DecodedInstruction::LoadImmediate { destination } => {
+                self.load_eight_bit_register_with_immediate(destination);
+            }
+            DecodedInstruction::StoreStackPointer => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.load_eight_bit_register_with_immediate(EightBitRegister::W);
+                self.micro_op_queue
+                    .push_back(MicroOp::StoreWordToMemory { value: self.sp });
+                // the second byte of SP is written on its own cycle
+                self.micro_op_queue.push_back(MicroOp::Internal);
+            }
+            DecodedInstruction::StoreAccumulatorHighPage => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.micro_op_queue
+                    .push_back(MicroOp::StoreToHighPage { value: self.a });
+            }
+            DecodedInstruction::LoadAccumulatorHighPage => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.micro_op_queue.push_back(MicroOp::LoadFromHighPage {
+                    destination: EightBitRegister::A,
+                });
+            }
+            DecodedInstruction::StoreHlImmediate => {
+                // the immediate lands in Z, which WriteBack stores to (HL)
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.micro_op_queue.push_back(MicroOp::WriteBack);
+            }
+            DecodedInstruction::SetCarryFlag => {
+                self.flags
+                    .remove(CpuFlags::SUBTRACTION_FLAG | CpuFlags::HALF_CARRY_FLAG);
+                self.flags.insert(CpuFlags::CARRY_FLAG);
+            }
+            DecodedInstruction::ComplementCarryFlag => {
+                self.flags
+                    .remove(CpuFlags::SUBTRACTION_FLAG | CpuFlags::HALF_CARRY_FLAG);
+                self.flags.toggle(CpuFlags::CARRY_FLAG);
+            }
+            DecodedInstruction::RelativeJump => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.micro_op_queue.push_back(MicroOp::RelativeJump);
+            }
+            // a branch that isn't taken skips the cycles spent jumping, which
+            // is what makes conditional instructions take different times
+            DecodedInstruction::ConditionalRelativeJump { condition } => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                if self.condition_met(condition) {
+                    self.micro_op_queue.push_back(MicroOp::RelativeJump);
+                }
+            }
+            DecodedInstruction::Jump => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.load_eight_bit_register_with_immediate(EightBitRegister::W);
+                self.micro_op_queue.push_back(MicroOp::Jump);
+            }
+            DecodedInstruction::ConditionalJump { condition } => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.load_eight_bit_register_with_immediate(EightBitRegister::W);
+                if self.condition_met(condition) {
+                    self.micro_op_queue.push_back(MicroOp::Jump);
+                }
+            }
+            DecodedInstruction::Call => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.load_eight_bit_register_with_immediate(EightBitRegister::W);
+                self.call();
+            }
+            DecodedInstruction::ConditionalCall { condition } => {
+                self.load_eight_bit_register_with_immediate(EightBitRegister::Z);
+                self.load_eight_bit_register_with_immediate(EightBitRegister::W);
+                if self.condition_met(condition) {
+                    self.call();
+                }
+            }
+            DecodedInstruction::Return => self.ret(),
+            DecodedInstruction::ConditionalReturn { condition } => {
+                // checking the condition takes a cycle of its own
+                self.micro_op_queue.push_back(MicroOp::Internal);
+                if self.condition_met(condition) {
+                    self.ret();
+                }
+            }
+            DecodedInstruction::Alu { operation, source } => {
+                if let Some(result) = self.alu(operation, self.get_eight_bit_register(source)) {
+                    self.a = result;
+                }
+            }
+            DecodedInstruction::Prefix => self.micro_op_queue.push_back(MicroOp::ExecutePrefixed),
+            DecodedInstruction::ShiftLeftArithmetic { register } => {
+                let value = self.sla(self.get_eight_bit_register(register));
+                self.set_eight_bit_register(register, value);
+            }
+            DecodedInstruction::ShiftRightArithmetic { register } => {
+                let value = self.sra(self.get_eight_bit_register(register));
+                self.set_eight_bit_register(register, value);
+            }
+            DecodedInstruction::ShiftRightLogical { register } => {
+                let value = self.srl(self.get_eight_bit_register(register));
+                self.set_eight_bit_register(register, value);
+            }
+            DecodedInstruction::ReadModifyWrite { operation } => {
+                self.micro_op_queue
+                    .push_back(MicroOp::ReadModify { operation });
+                self.micro_op_queue.push_back(MicroOp::WriteBack);
+            }
+            DecodedInstruction::Illegal { opcode } => {
+                self.locked = true;
+                return Err(EmuError::IllegalInstruction { pc, opcode });
+            }
+            DecodedInstruction::Unimplemented { opcode } => {
+                return Err(EmuError::UnimplementedInstruction { pc, opcode })
+            }
+            DecodedInstruction::UnimplementedPrefixed { opcode } => {
+                return Err(EmuError::UnimplementedPrefixedInstruction { pc, opcode })
+            }
+        }
+        Ok(())
+    }
+
+    fn condition_met(self: &Self, condition: Condition) -> bool {
+        match condition {
+            Condition::NotZero => !self.flags.contains(CpuFlags::ZERO_FLAG),
+            Condition::Zero => self.flags.contains(CpuFlags::ZERO_FLAG),
+            Condition::NotCarry => !self.flags.contains(CpuFlags::CARRY_FLAG),
+            Condition::Carry => self.flags.contains(CpuFlags::CARRY_FLAG),
+        }
+    }
+
+    // jumps to the address in WZ once the two operand bytes have been read,
+    // pushing the address of the following instruction
+    fn call(self: &mut Self) {
+        let return_address = self.pc.wrapping_add(2);
+        self.micro_op_queue.push_back(MicroOp::Jump);
+        self.micro_op_queue.push_back(MicroOp::Push {
+            value: (return_address >> 8) as u8,
+        });
+        self.micro_op_queue.push_back(MicroOp::Push {
+            value: (return_address & 0xFF) as u8,
+        });
+    }
+
+    fn ret(self: &mut Self) {
+        self.micro_op_queue.push_back(MicroOp::Pop {
+            destination: EightBitRegister::Z,
+        });
+        self.micro_op_queue.push_back(MicroOp::Pop {
+            destination: EightBitRegister::W,
+        });
+        self.micro_op_queue.push_back(MicroOp::Jump);
+    }
+
+    fn load_eight_bit_register_with_immediate(self: &mut Self, register: EightBitRegister) {
+        self.micro_op_queue.push_back(MicroOp::LoadImmediate {
+            destination: register,
+        });
+    }
+
+    // INC r leaves the carry flag alone, unlike adding 1 with ADD
+    fn inc_eight_bit(self: &mut Self, value: u8) -> u8 {
+        let output = value.wrapping_add(1);
+
+        self.flags.set(CpuFlags::ZERO_FLAG, output == 0);
+        self.flags.remove(CpuFlags::SUBTRACTION_FLAG);
+        self.flags
+            .set(CpuFlags::HALF_CARRY_FLAG, (value & 0x0F) == 0x0F);
+
+        output
+    }
+
+    fn dec_eight_bit(self: &mut Self, value: u8) -> u8 {
+        let output = value.wrapping_sub(1);
+
+        self.flags.set(CpuFlags::ZERO_FLAG, output == 0);
+        self.flags.insert(CpuFlags::SUBTRACTION_FLAG);
+        self.flags
+            .set(CpuFlags::HALF_CARRY_FLAG, (value & 0x0F) == 0x00);
+
+        output
+    }
+
+    // applies the operation to A and the operand, returning the result to
+    // store in A or None for CP, which only sets flags
+    fn alu(self: &mut Self, op: AluOp, operand: u8) -> Option<u8> {
+        match op {
+            AluOp::Add => Some(self.add(self.a, operand)),
+            AluOp::AddWithCarry => Some(self.adc(self.a, operand)),
+            AluOp::Subtract => Some(self.sub(self.a, operand)),
+            AluOp::SubtractWithCarry => Some(self.sbc(self.a, operand)),
+            AluOp::And => Some(self.and(self.a, operand)),
+            AluOp::Xor => Some(self.xor(self.a, operand)),
+            AluOp::Or => Some(self.or(self.a, operand)),
+            AluOp::Compare => {
+                self.cp(self.a, operand);
+                None
+            }
+        }
+    }
+
+    fn add(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
+        // this is ugly, but it's not something worth spending too long to make pretty
+        let half_carry: bool = (((value_one & 0xF) + (value_two & 0xF)) & 0x10) == 0x10;
+        let output: u16 = (value_one as u16) + (value_two as u16);
+
+        self.clear_flags();
+
+        if output as u8 == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        if half_carry {
+            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+        }
+
+        if output > u8::MAX as u16 {
+            self.flags.set(CpuFlags::CARRY_FLAG, true);
+        }
+
+        output as u8
+    }
+
+    fn adc(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
+        let mut carry: u16 = 0;
+
+        if self.flags.contains(CpuFlags::CARRY_FLAG) {
+            carry = 1;
+        }
+
+        // this is ugly, but it's not something worth spending too long to make pretty
+        let half_carry: bool = (((value_one & 0xF) + (value_two & 0xF)) + carry as u8) > 0x0F;
+        let output: u16 = (value_one as u16) + (value_two as u16) + carry;
+
+        self.clear_flags();
+
+        if output as u8 == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        self.flags.remove(CpuFlags::SUBTRACTION_FLAG);
+
+        if half_carry {
+            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+        }
+
+        if output > u8::MAX as u16 {
+            self.flags.set(CpuFlags::CARRY_FLAG, true);
+        }
+
+        output as u8
+    }
+
+    fn sub(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
+        // a half carry happens when the low nibble has to borrow from the high one
+        let half_carry: bool = (value_one & 0xF) < (value_two & 0xF);
+        let output: u8 = value_one.wrapping_sub(value_two);
+
+        self.clear_flags();
+
+        if output == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        self.flags.set(CpuFlags::SUBTRACTION_FLAG, true);
+
+        if half_carry {
+            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+        }
+
+        if value_one < value_two {
+            self.flags.set(CpuFlags::CARRY_FLAG, true);
+        }
+
+        output
+    }
+
+    fn sbc(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
+        let mut carry: u8 = 0;
+
+        if self.flags.contains(CpuFlags::CARRY_FLAG) {
+            carry = 1;
+        }
+
+        let output: u8 = value_one.wrapping_sub(value_two).wrapping_sub(carry);
+
+        self.clear_flags();
+
+        if output == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        self.flags.set(CpuFlags::SUBTRACTION_FLAG, true);
+
+        // widen before adding the carry so an operand nibble of 0xF can't wrap
+        if ((value_one & 0x0F) as u16) < (value_two & 0x0F) as u16 + carry as u16 {
+            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+        }
+
+        if (value_one as u16) < value_two as u16 + carry as u16 {
+            self.flags.set(CpuFlags::CARRY_FLAG, true);
+        }
+
+        output
+    }
+
+    fn and(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
+        let output = value_one & value_two;
+
+        self.clear_flags();
+
+        if output == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+
+        output
+    }
+
+    fn or(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
+        let output = value_one | value_two;
+
+        self.clear_flags();
+
+        if output == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        output
+    }
+
+    fn xor(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
+        let output = value_one ^ value_two;
+
+        self.clear_flags();
+
+        if output == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        self.flags.remove(CpuFlags::SUBTRACTION_FLAG);
+        self.flags.remove(CpuFlags::HALF_CARRY_FLAG);
+        self.flags.remove(CpuFlags::CARRY_FLAG);
+
+        output
+    }
+
+    fn cp(self: &mut Self, value_one: u8, value_two: u8) {
+        // cp is just subtraction without actually generating an output,
+        // so we can just discard the result
+        let _ = self.sub(value_one, value_two);
+    }
+
+    // shifts left, moving bit 7 into the carry and a 0 into bit 0
+    fn sla(self: &mut Self, value: u8) -> u8 {
+        let output = value << 1;
+        self.set_shift_flags(output, value & 0x80 != 0);
+        output
+    }
+
+    // shifts right, keeping bit 7 so the sign is preserved and moving
+    // bit 0 into the carry
+    fn sra(self: &mut Self, value: u8) -> u8 {
+        let output = (value >> 1) | (value & 0x80);
+        self.set_shift_flags(output, value & 0x01 != 0);
+        output
+    }
+
+    // shifts right, moving a 0 into bit 7 and bit 0 into the carry
+    fn srl(self: &mut Self, value: u8) -> u8 {
+        let output = value >> 1;
+        self.set_shift_flags(output, value & 0x01 != 0);
+        output
+    }
+
+    fn set_shift_flags(self: &mut Self, output: u8, carry: bool) {
+        self.clear_flags();
+
+        if output == 0 {
+            self.flags.set(CpuFlags::ZERO_FLAG, true);
+        }
+
+        if carry {
             self.flags.set(CpuFlags::CARRY_FLAG, true);
         }
+    }
+
+    #[cfg(test)]
+    fn pending_micro_ops(self: &Self) -> usize {
+        self.micro_op_queue.len()
+    }
+
+    #[cfg(test)]
+    fn queued_micro_ops(self: &Self) -> Vec<MicroOp> {
+        self.micro_op_queue.iter().copied().collect()
+    }
+
+    #[cfg(test)]
+    fn set_byte_in_memory(self: &mut Self, address: u16, data: u8) {
+        self.memory.set_byte(address, data);
+    }
+}
+
+// sets up registers, memory and a program in one expression, runs a few
+// instructions through the emulator and hands back what they left behind
+#[cfg(test)]
+mod scenario {
+    use super::*;
+    use crate::emulator::Emulator;
+
+    #[derive(Default)]
+    pub(super) struct Scenario {
+        memory: memory::Memory,
+        registers: [Option<u8>; 7],
+        flags: Option<CpuFlags>,
+    }
+
+    // the registers and memory after a scenario has run
+    pub(super) struct Outcome {
+        pub a: u8,
+        pub b: u8,
+        pub c: u8,
+        pub d: u8,
+        pub e: u8,
+        pub h: u8,
+        pub l: u8,
+        pub flags: CpuFlags,
+        pub sp: u16,
+        pub pc: u16,
+        memory: memory::Memory,
+    }
+
+    impl Outcome {
+        pub fn mem(self: &Self, address: u16) -> u8 {
+            self.memory.peek(address)
+        }
+    }
+
+    impl Scenario {
+        pub fn new() -> Self {
+            Scenario::default()
+        }
+
+        pub fn reg_a(self, value: u8) -> Self {
+            self.reg(0, value)
+        }
+
+        pub fn reg_b(self, value: u8) -> Self {
+            self.reg(1, value)
+        }
+
+        pub fn reg_c(self, value: u8) -> Self {
+            self.reg(2, value)
+        }
+
+        pub fn reg_d(self, value: u8) -> Self {
+            self.reg(3, value)
+        }
+
+        pub fn reg_e(self, value: u8) -> Self {
+            self.reg(4, value)
+        }
+
+        pub fn reg_h(self, value: u8) -> Self {
+            self.reg(5, value)
+        }
+
+        pub fn reg_l(self, value: u8) -> Self {
+            self.reg(6, value)
+        }
+
+        fn reg(mut self, index: usize, value: u8) -> Self {
+            self.registers[index] = Some(value);
+            self
+        }
+
+        pub fn flags(mut self, flags: CpuFlags) -> Self {
+            self.flags = Some(flags);
+            self
+        }
+
+        pub fn mem(mut self, address: u16, value: u8) -> Self {
+            self.memory.set_byte(address, value);
+            self
+        }
+
+        // the program starts where execution does
+        pub fn rom(mut self, program: &[u8]) -> Self {
+            self.memory.load_at(INITIAL_PC, program);
+            self
+        }
+
+        pub fn run_instructions(mut self, count: usize) -> Outcome {
+            let mut emulator = Emulator::new(&mut self.memory);
+            let cpu = emulator.cpu_mut();
+            let registers = [
+                &mut cpu.a, &mut cpu.b, &mut cpu.c, &mut cpu.d, &mut cpu.e, &mut cpu.h, &mut cpu.l,
+            ];
+            // anything not given starts cleared rather than as the boot ROM
+            // leaves it
+            for (register, value) in registers.into_iter().zip(self.registers) {
+                *register = value.unwrap_or(0);
+            }
+            cpu.flags = self.flags.unwrap_or(CpuFlags::empty());
+
+            for _ in 0..count {
+                emulator.step().unwrap();
+            }
+
+            let cpu = emulator.cpu();
+            let (a, b, c, d, e, h, l) = (cpu.a, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l);
+            let (flags, sp, pc) = (cpu.flags, cpu.sp, cpu.pc);
+            drop(emulator);
+            Outcome {
+                a,
+                b,
+                c,
+                d,
+                e,
+                h,
+                l,
+                flags,
+                sp,
+                pc,
+                memory: self.memory,
+            }
+        }
+    }
+
+    #[test]
+    fn test_scenario_pokes_memory_and_registers() {
+        let outcome = Scenario::new()
+            .reg_a(0x42)
+            .reg_h(0xC0)
+            .reg_l(0x00)
+            .mem(0xC001, 0x99)
+            .rom(&[Instruction::StoreHlPlusA as u8, Instruction::Nop as u8])
+            .run_instructions(2);
+
+        assert_eq!(outcome.mem(0xC000), 0x42);
+        assert_eq!(outcome.mem(0xC001), 0x99);
+        assert_eq!((outcome.h, outcome.l), (0xC0, 0x01));
+        assert_eq!(outcome.pc, INITIAL_PC + 2);
+    }
+}
+
+#[cfg(test)]
+mod test_decode {
+    use super::*;
+
+    #[test]
+    fn test_decode_load_b_c() {
+        let mut memory = memory::Memory::new();
+        let cpu = Cpu::new(&mut memory);
+        let decoded = cpu.decode(0x41);
+
+        assert_eq!(
+            decoded,
+            DecodedInstruction::Load {
+                destination: EightBitRegister::B,
+                source: EightBitRegister::C,
+            }
+        );
+        assert_eq!(decoded.to_string(), "LD B,C");
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_cpu() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_byte_in_memory(start, 0xD3);
+
+        assert!(matches!(
+            cpu.step(),
+            Err(EmuError::IllegalInstruction { opcode: 0xD3, .. })
+        ));
+        assert!(cpu.is_locked());
+
+        // time keeps passing, but nothing else is ever fetched
+        assert_eq!(cpu.step().unwrap(), 4);
+        assert_eq!(cpu.pc, start + 1);
+    }
+
+    #[test]
+    fn test_unimplemented_opcode_is_not_illegal() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let opcode = (0..=0xFF)
+            .find(|&opcode| matches!(cpu.decode(opcode), DecodedInstruction::Unimplemented { .. }))
+            .unwrap();
+        cpu.set_byte_in_memory(cpu.pc, opcode);
+
+        assert!(matches!(
+            cpu.step(),
+            Err(EmuError::UnimplementedInstruction { .. })
+        ));
+        assert!(!cpu.is_locked());
+    }
+
+    #[test]
+    fn test_instruction_mnemonics() {
+        assert_eq!(Instruction::LoadBC.to_string(), "LD B,C");
+        assert_eq!(Instruction::AddAB.to_string(), "ADD A,B");
+        assert_eq!(Instruction::XorAA.to_string(), "XOR A,A");
+        assert_eq!(Instruction::IncA.to_string(), "INC A");
+        assert_eq!(Instruction::Call.to_string(), "CALL n16");
+        assert_eq!(Instruction::StoreHlImmediate.to_string(), "LD (HL),n8");
+    }
+
+    #[test]
+    fn test_decode_unknown_opcode() {
+        let mut memory = memory::Memory::new();
+        let cpu = Cpu::new(&mut memory);
+
+        assert_eq!(
+            cpu.decode(0xD3),
+            DecodedInstruction::Illegal { opcode: 0xD3 }
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_alu {
+    use super::*;
+
+    const OPS: [AluOp; 8] = [
+        AluOp::Add,
+        AluOp::AddWithCarry,
+        AluOp::Subtract,
+        AluOp::SubtractWithCarry,
+        AluOp::And,
+        AluOp::Xor,
+        AluOp::Or,
+        AluOp::Compare,
+    ];
+
+    #[test]
+    fn test_decodes_operation_and_source() {
+        assert_eq!(
+            Cpu::decode_opcode(Instruction::SbcAB as u8),
+            DecodedInstruction::Alu {
+                operation: AluOp::SubtractWithCarry,
+                source: EightBitRegister::B,
+            }
+        );
+        assert_eq!(
+            Cpu::decode_opcode(Instruction::CpAA as u8),
+            DecodedInstruction::Alu {
+                operation: AluOp::Compare,
+                source: EightBitRegister::A,
+            }
+        );
+    }
+
+    // one case of each operation, which stay on when the per-register test
+    // modules are left out
+    #[test]
+    fn test_each_operation() {
+        let cases = [
+            (Instruction::AddAC, 0x3A, 0xC6, false, 0x00, 0b1011_0000),
+            (Instruction::AdcAC, 0xE1, 0x0F, true, 0xF1, 0b0010_0000),
+            (Instruction::SubAC, 0x3E, 0x3E, false, 0x00, 0b1100_0000),
+            (Instruction::SbcAC, 0x3B, 0x2A, true, 0x10, 0b0100_0000),
+            (Instruction::AndAC, 0x5A, 0x3F, false, 0x1A, 0b0010_0000),
+            (Instruction::XorAC, 0xFF, 0x0F, false, 0xF0, 0b0000_0000),
+            (Instruction::OrAC, 0x00, 0x00, false, 0x00, 0b1000_0000),
+            (Instruction::CpAC, 0x3C, 0x40, false, 0x3C, 0b0101_0000),
+        ];
+        for (instruction, a, c, carry, expected_a, expected_f) in cases {
+            let mut memory = memory::Memory::new();
+            let mut cpu = Cpu::new(&mut memory);
+            cpu.a = a;
+            cpu.c = c;
+            cpu.flags.set(CpuFlags::CARRY_FLAG, carry);
+            cpu.set_byte_in_memory(cpu.pc, instruction as u8);
+            cpu.step().unwrap();
+
+            assert_eq!(
+                (cpu.a, cpu.get_f()),
+                (expected_a, expected_f),
+                "{}",
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn test_dispatcher_matches_instructions() {
+        let operands = [0x00, 0x01, 0x0F, 0x10, 0x7F, 0x80, 0xFF];
+        for (index, &op) in OPS.iter().enumerate() {
+            // the B forms of each operation
+            let opcode = 0x80 | (index as u8) << 3;
+            for &a in &operands {
+                for &b in &operands {
+                    for carry in [false, true] {
+                        let mut memory = memory::Memory::new();
+                        let mut cpu = Cpu::new(&mut memory);
+                        cpu.a = a;
+                        cpu.b = b;
+                        cpu.flags.set(CpuFlags::CARRY_FLAG, carry);
+                        cpu.set_byte_in_memory(cpu.pc, opcode);
+                        cpu.step().unwrap();
+                        let executed = (cpu.a, cpu.get_f());
+
+                        cpu.a = a;
+                        cpu.set_f(0);
+                        cpu.flags.set(CpuFlags::CARRY_FLAG, carry);
+                        let result = cpu.alu(op, b);
+                        if op == AluOp::Compare {
+                            assert_eq!(result, None);
+                        }
+                        let dispatched = (result.unwrap_or(a), cpu.get_f());
+
+                        assert_eq!(executed, dispatched, "{} {:#04X},{:#04X}", op, a, b);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_disassemble {
+    use super::*;
+
+    #[test]
+    fn test_call_uses_label() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_byte_in_memory(start, Instruction::Call as u8);
+        cpu.set_byte_in_memory(start + 1, 0x40);
+        cpu.set_byte_in_memory(start + 2, 0x21);
+
+        assert_eq!(cpu.disassemble(start), "CALL $2140");
+        cpu.load_symbols("00:2140 ClearScreen\n");
+        assert_eq!(cpu.disassemble(start), "CALL ClearScreen");
+    }
+
+    #[test]
+    fn test_operands_are_filled_in() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.load_symbols("00:ff80 hCounter\n");
+        let program = [
+            Instruction::LoadBImmediate as u8,
+            0x12,
+            Instruction::StoreHighPageA as u8,
+            0x80,
+            Instruction::JumpRelative as u8,
+            0xFA,
+        ];
+        for (offset, &byte) in program.iter().enumerate() {
+            cpu.set_byte_in_memory(start + offset as u16, byte);
+        }
+
+        assert_eq!(cpu.disassemble(start), "LD B,$12");
+        assert_eq!(cpu.disassemble(start + 2), "LDH (hCounter),A");
+        assert_eq!(cpu.disassemble(start + 4), "JR $0100");
+    }
+
+    #[test]
+    fn test_block_stops_at_return() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        let program = [
+            Instruction::LoadBImmediate as u8,
+            0x12,
+            Instruction::Call as u8,
+            0x00,
+            0x20,
+            Instruction::Nop as u8,
+            Instruction::Return as u8,
+            Instruction::Nop as u8,
+        ];
+        for (offset, &byte) in program.iter().enumerate() {
+            cpu.set_byte_in_memory(start + offset as u16, byte);
+        }
+
+        assert_eq!(
+            cpu.disassemble_block(start, 16),
+            vec![
+                (start, String::from("LD B,$12")),
+                (start + 2, String::from("CALL $2000")),
+                (start + 5, String::from("NOP")),
+                (start + 6, String::from("RET")),
+            ]
+        );
+        assert_eq!(cpu.disassemble_block(start, 2).len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod test_effective_address {
+    use super::*;
+
+    #[test]
+    fn test_load_a_from_hl() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_hl(0xC123);
+
+        assert_eq!(cpu.effective_address(0x7E, &[]), Some(0xC123));
+        assert_eq!(cpu.effective_address(0x77, &[]), Some(0xC123));
+        assert_eq!(cpu.effective_address(0xCB, &[0x26]), Some(0xC123));
+        assert_eq!(cpu.effective_address(0xCB, &[0x27]), None);
+        assert_eq!(cpu.effective_address(0x76, &[]), None);
+    }
+
+    #[test]
+    fn test_high_page_store() {
+        let mut memory = memory::Memory::new();
+        let cpu = Cpu::new(&mut memory);
+
+        assert_eq!(cpu.effective_address(0xE0, &[0x80]), Some(0xFF80));
+    }
+
+    #[test]
+    fn test_branch_targets() {
+        let mut memory = memory::Memory::new();
+        let cpu = Cpu::new(&mut memory);
+
+        assert_eq!(cpu.effective_address(0xCD, &[0x40, 0x21]), Some(0x2140));
+        assert_eq!(cpu.effective_address(0x18, &[0xFE]), Some(cpu.pc));
+        assert_eq!(cpu.effective_address(0xEF, &[]), Some(0x0028));
+    }
+
+    #[test]
+    fn test_nop_has_no_address() {
+        let mut memory = memory::Memory::new();
+        let cpu = Cpu::new(&mut memory);
+
+        assert_eq!(cpu.effective_address(0x00, &[]), None);
+    }
+}
+
+#[cfg(test)]
+mod test_micro_op_queue {
+    use super::*;
 
-        output as u8
+    #[test]
+    fn test_nop_enqueues_nothing() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::Nop as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.pending_micro_ops(), 0);
     }
 
-    fn sub(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
-        // this is ugly, but it's not something worth spending too long to make pretty
-        let half_carry: bool = (((value_one & 0xF) - (value_two & 0xF)) & 0x10) == 0x10;
-        let output: u8 = value_one.wrapping_sub(value_two);
+    #[test]
+    fn test_store_a_to_bc_enqueues_one_store() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.a = 0x12;
+        cpu.set_bc(0xC000);
 
-        self.clear_flags();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::StoreBcA as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(
+            cpu.queued_micro_ops(),
+            vec![MicroOp::StoreToMemory {
+                value: 0x12,
+                address: 0xC000,
+            }]
+        );
+    }
 
-        if output == 0 {
-            self.flags.set(CpuFlags::ZERO_FLAG, true);
-        }
+    #[test]
+    fn test_load_bc_immediate_enqueues_two_loads() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
 
-        self.flags.set(CpuFlags::SUBTRACTION_FLAG, true);
+        cpu.set_byte_in_memory(cpu.pc, Instruction::LoadBcTwoByteImmediate as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(
+            cpu.queued_micro_ops(),
+            vec![
+                MicroOp::LoadImmediate {
+                    destination: EightBitRegister::C,
+                },
+                MicroOp::LoadImmediate {
+                    destination: EightBitRegister::B,
+                },
+            ]
+        );
+
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.pending_micro_ops(), 1);
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.pending_micro_ops(), 0);
+    }
+}
 
-        if half_carry {
-            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
-        }
+#[cfg(test)]
+mod test_step {
+    use super::*;
 
-        if value_one < value_two {
-            self.flags.set(CpuFlags::CARRY_FLAG, true);
-        }
+    #[test]
+    fn test_step_reports_cycles() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
 
-        output as u8
+        cpu.set_byte_in_memory(start, Instruction::Nop as u8);
+        cpu.set_byte_in_memory(start + 1, Instruction::LoadBcTwoByteImmediate as u8);
+        cpu.set_byte_in_memory(start + 4, Instruction::StoreBcA as u8);
+
+        assert_eq!(cpu.step().unwrap(), 4);
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.step().unwrap(), 8);
+        assert_eq!(cpu.pc, start + 5);
     }
 
-    fn sbc(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
-        let mut carry: u8 = 0;
+    #[test]
+    fn test_step_into_illegal_opcode() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+
+        cpu.set_byte_in_memory(start, Instruction::Nop as u8);
+        cpu.set_byte_in_memory(start + 1, 0xDD);
+
+        assert_eq!(cpu.step().unwrap(), 4);
+        assert!(matches!(
+            cpu.step(),
+            Err(EmuError::IllegalInstruction {
+                pc,
+                opcode: 0xDD
+            }) if pc == start + 1
+        ));
+    }
 
-        if self.flags.contains(CpuFlags::CARRY_FLAG) {
-            carry = 1;
+    #[test]
+    fn test_trace_keeps_most_recent_instructions() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_trace_capacity(2);
+
+        cpu.set_byte_in_memory(start, Instruction::Nop as u8);
+        cpu.set_byte_in_memory(start + 1, Instruction::LoadBImmediate as u8);
+        cpu.set_byte_in_memory(start + 2, 0x12);
+        cpu.set_byte_in_memory(start + 3, Instruction::Prefix as u8);
+        cpu.set_byte_in_memory(start + 4, PrefixedInstruction::SlaB as u8);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        let expected_trace = vec![
+            TraceEntry {
+                pc: start + 1,
+                opcode: Instruction::LoadBImmediate as u8,
+                disassembly: String::from("LD B,n8"),
+            },
+            TraceEntry {
+                pc: start + 3,
+                opcode: Instruction::Prefix as u8,
+                disassembly: String::from("SLA B"),
+            },
+        ];
+        assert_eq!(cpu.recent_trace(), expected_trace);
+    }
+
+    #[test]
+    fn test_trace_stays_capped_over_many_instructions() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_trace_capacity(3);
+
+        for _ in 0..10 {
+            cpu.step().unwrap();
         }
 
-        let output: u8 = value_one.wrapping_sub(value_two).wrapping_sub(carry as u8);
+        let pcs: Vec<u16> = cpu.recent_trace().iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [start + 7, start + 8, start + 9]);
+    }
 
-        self.clear_flags();
+    #[test]
+    fn test_trace_does_not_read_through_the_bus() {
+        let mut memory = memory::Memory::new();
+        let reads = alloc::rc::Rc::new(core::cell::Cell::new(0));
+        let counter = reads.clone();
+        memory.watch_read(INITIAL_PC + 1, move |_, _| counter.set(counter.get() + 1));
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_trace_capacity(1);
 
-        if output == 0 {
-            self.flags.set(CpuFlags::ZERO_FLAG, true);
-        }
+        cpu.set_byte_in_memory(INITIAL_PC, Instruction::Prefix as u8);
+        cpu.set_byte_in_memory(INITIAL_PC + 1, PrefixedInstruction::SlaB as u8);
+        cpu.step().unwrap();
 
-        self.flags.set(CpuFlags::SUBTRACTION_FLAG, true);
+        // only the CPU fetching the second byte counts
+        assert_eq!(reads.get(), 1);
+        assert_eq!(cpu.recent_trace()[0].disassembly, "SLA B");
+    }
 
-        if value_one & 0x0F < (value_two.wrapping_sub(carry) & 0x0F) {
-            self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
-        }
+    #[test]
+    fn test_hooks_see_each_instruction() {
+        let mut executed = Vec::new();
+        let mut cycles = Vec::new();
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+
+        cpu.set_byte_in_memory(start, Instruction::Nop as u8);
+        cpu.set_byte_in_memory(start + 1, Instruction::LoadBImmediate as u8);
+        cpu.set_byte_in_memory(start + 3, Instruction::IncB as u8);
+        cpu.set_pre_hook(|pc, opcode| executed.push((pc, opcode)));
+        cpu.set_post_hook(|instruction_cycles| cycles.push(instruction_cycles));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        drop(cpu);
+
+        assert_eq!(
+            executed,
+            vec![
+                (start, Instruction::Nop as u8),
+                (start + 1, Instruction::LoadBImmediate as u8),
+                (start + 3, Instruction::IncB as u8),
+            ]
+        );
+        assert_eq!(cycles, vec![4, 8, 4]);
+    }
 
-        // only happens if wrap around, so we must have carried
-        if output >= value_one {
-            self.flags.set(CpuFlags::CARRY_FLAG, true);
-        }
+    #[test]
+    fn test_load_immediate() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
 
-        output as u8
+        cpu.set_byte_in_memory(cpu.pc, Instruction::LoadEImmediate as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, 0x42);
+
+        assert_eq!(cpu.step().unwrap(), 8);
+        assert_eq!(cpu.e, 0x42);
     }
 
-    fn and(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
-        let output = value_one & value_two;
+    #[test]
+    fn test_high_page_store_and_load() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.a = 0x42;
+
+        cpu.set_byte_in_memory(start, Instruction::StoreHighPageA as u8);
+        cpu.set_byte_in_memory(start + 1, 0x80);
+        cpu.set_byte_in_memory(start + 2, Instruction::LoadAImmediate as u8);
+        cpu.set_byte_in_memory(start + 3, 0x00);
+        cpu.set_byte_in_memory(start + 4, Instruction::LoadAHighPage as u8);
+        cpu.set_byte_in_memory(start + 5, 0x80);
+
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.memory.get_data(0xFF80), 0x42);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.a, 0x42);
+    }
 
-        self.clear_flags();
+    #[test]
+    fn test_relative_jump() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
 
-        if output == 0 {
-            self.flags.set(CpuFlags::ZERO_FLAG, true);
-        }
+        cpu.set_byte_in_memory(start, Instruction::JumpRelative as u8);
+        cpu.set_byte_in_memory(start + 1, 0xFE);
 
-        self.flags.set(CpuFlags::HALF_CARRY_FLAG, true);
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.pc, start);
+    }
+}
 
-        output as u8
+#[cfg(test)]
+mod test_conditional_branch {
+    use super::*;
+
+    #[test]
+    fn test_jr_nz_taken() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.flags = CpuFlags::empty();
+
+        cpu.set_byte_in_memory(start, Instruction::JumpRelativeNz as u8);
+        cpu.set_byte_in_memory(start + 1, 0x10);
+
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.pc, start + 0x12);
     }
 
-    fn or(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
-        let output = value_one | value_two;
+    #[test]
+    fn test_jr_nz_not_taken() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.flags = CpuFlags::ZERO_FLAG;
 
-        self.clear_flags();
+        cpu.set_byte_in_memory(start, Instruction::JumpRelativeNz as u8);
+        cpu.set_byte_in_memory(start + 1, 0x10);
 
-        if output == 0 {
-            self.flags.set(CpuFlags::ZERO_FLAG, true);
-        }
+        assert_eq!(cpu.step().unwrap(), 8);
+        assert_eq!(cpu.pc, start + 2);
+    }
 
-        output as u8
+    #[test]
+    fn test_jp_c() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+
+        cpu.set_byte_in_memory(start, Instruction::JumpC as u8);
+        cpu.set_byte_in_memory(start + 1, 0x00);
+        cpu.set_byte_in_memory(start + 2, 0x02);
+        cpu.set_byte_in_memory(start + 3, Instruction::JumpC as u8);
+        cpu.set_byte_in_memory(start + 4, 0x00);
+        cpu.set_byte_in_memory(start + 5, 0x02);
+
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.pc, start + 3);
+
+        cpu.flags = CpuFlags::CARRY_FLAG;
+        assert_eq!(cpu.step().unwrap(), 16);
+        assert_eq!(cpu.pc, 0x0200);
     }
 
-    fn xor(self: &mut Self, value_one: u8, value_two: u8) -> u8 {
-        let output = value_one ^ value_two;
+    #[test]
+    fn test_call_and_return() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
 
-        self.clear_flags();
+        cpu.set_byte_in_memory(start, Instruction::Call as u8);
+        cpu.set_byte_in_memory(start + 1, 0x00);
+        cpu.set_byte_in_memory(start + 2, 0x02);
+        cpu.set_byte_in_memory(0x0200, Instruction::Return as u8);
 
-        if output == 0 {
-            self.flags.set(CpuFlags::ZERO_FLAG, true);
-        }
+        assert_eq!(cpu.step().unwrap(), 24);
+        assert_eq!(cpu.pc, 0x0200);
+        assert_eq!(cpu.sp, INITIAL_SP - 2);
+        assert_eq!(cpu.memory.read_word(cpu.sp), start + 3);
 
-        self.flags.remove(CpuFlags::SUBTRACTION_FLAG);
-        self.flags.remove(CpuFlags::HALF_CARRY_FLAG);
-        self.flags.remove(CpuFlags::CARRY_FLAG);
+        assert_eq!(cpu.step().unwrap(), 16);
+        assert_eq!(cpu.pc, start + 3);
+        assert_eq!(cpu.sp, INITIAL_SP);
+    }
 
-        output as u8
+    #[test]
+    fn test_conditional_call_cycles() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.flags = CpuFlags::ZERO_FLAG;
+
+        cpu.set_byte_in_memory(start, Instruction::CallNz as u8);
+        cpu.set_byte_in_memory(start + 3, Instruction::CallZ as u8);
+        cpu.set_byte_in_memory(start + 4, 0x00);
+        cpu.set_byte_in_memory(start + 5, 0x02);
+
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.sp, INITIAL_SP);
+        assert_eq!(cpu.step().unwrap(), 24);
+        assert_eq!(cpu.pc, 0x0200);
     }
 
-    fn cp(self: &mut Self, value_one: u8, value_two: u8) {
-        // cp is just subtraction without actually generating an output,
-        // so we can just discard the result
-        let _ = self.sub(value_one, value_two);
+    #[test]
+    fn test_conditional_return_cycles() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.sp = 0xFFFC;
+        cpu.memory.write_word(0xFFFC, 0x0200);
+        cpu.flags = CpuFlags::CARRY_FLAG;
+
+        cpu.set_byte_in_memory(start, Instruction::ReturnNc as u8);
+        cpu.set_byte_in_memory(start + 1, Instruction::ReturnC as u8);
+
+        assert_eq!(cpu.step().unwrap(), 8);
+        assert_eq!(cpu.pc, start + 1);
+        assert_eq!(cpu.step().unwrap(), 20);
+        assert_eq!(cpu.pc, 0x0200);
+        assert_eq!(cpu.sp, 0xFFFE);
     }
+}
 
-    #[cfg(test)]
-    fn set_byte_in_memory(self: &mut Self, address: u16, data: u8) {
-        self.memory.set_byte(address, data);
+#[cfg(test)]
+mod test_stack_guard {
+    use super::*;
+
+    #[test]
+    fn test_stack_guard_fires_on_underflow() {
+        let mut suspicious = Vec::new();
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+
+        cpu.set_byte_in_memory(start, Instruction::Call as u8);
+        cpu.set_byte_in_memory(start + 1, 0x00);
+        cpu.set_byte_in_memory(start + 2, 0x02);
+        cpu.set_byte_in_memory(0x0200, Instruction::Return as u8);
+        cpu.set_byte_in_memory(start + 3, Instruction::Return as u8);
+        cpu.set_stack_guard(|sp| suspicious.push(sp));
+
+        // the call and its return are balanced
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // this return pops past the top of the stack
+        cpu.step().unwrap();
+        drop(cpu);
+
+        assert_eq!(suspicious, vec![0xFFFF, 0x0000]);
     }
 }
 
@@ -701,8 +3387,8 @@ mod test_store_sixteen_bit_from_accumulator {
         cpu.c = (address & 0xFF) as u8;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::StoreBcA as u8);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.memory.get_data(address), expected_value);
     }
@@ -719,8 +3405,8 @@ mod test_store_sixteen_bit_from_accumulator {
         cpu.e = (address & 0xFF) as u8;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::StoreDeA as u8);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.memory.get_data(address), expected_value);
     }
@@ -738,8 +3424,8 @@ mod test_store_sixteen_bit_from_accumulator {
         let initial_hl = cpu.get_hl();
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::StoreHlPlusA as u8);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.memory.get_data(address), expected_value);
         assert_eq!(cpu.get_hl(), initial_hl + 1);
@@ -758,8 +3444,8 @@ mod test_store_sixteen_bit_from_accumulator {
         let initial_hl = cpu.get_hl();
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::StoreHlMinusA as u8);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.memory.get_data(address), expected_value);
         assert_eq!(cpu.get_hl(), initial_hl - 1);
@@ -777,7 +3463,7 @@ mod tes_inc_r {
         cpu.a = 0xFF;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.a, 0x00);
     }
 
@@ -788,7 +3474,7 @@ mod tes_inc_r {
         cpu.b = 0xFF;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.b, 0x00);
     }
 
@@ -799,7 +3485,7 @@ mod tes_inc_r {
         cpu.c = 0xFF;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncC as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.c, 0x00);
     }
 
@@ -810,7 +3496,7 @@ mod tes_inc_r {
         cpu.d = 0xFF;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncD as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.d, 0x00);
     }
 
@@ -821,7 +3507,7 @@ mod tes_inc_r {
         cpu.e = 0xFF;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncE as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.e, 0x00);
     }
 
@@ -832,7 +3518,7 @@ mod tes_inc_r {
         cpu.h = 0xFF;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncH as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.h, 0x00);
     }
 
@@ -843,9 +3529,180 @@ mod tes_inc_r {
         cpu.l = 0xFF;
 
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncL as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.l, 0x00);
     }
+
+    #[test]
+    fn test_inc_half_carry() {
+        let expected_flags = CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.b = 0x0F;
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::IncB as u8);
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.b, 0x10);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_inc_zero_and_half_carry() {
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.c = 0xFF;
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::IncC as u8);
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.c, 0x00);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_inc_preserves_carry() {
+        let expected_flags = CpuFlags::CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.flags = CpuFlags::CARRY_FLAG | CpuFlags::SUBTRACTION_FLAG;
+        cpu.a = 0x01;
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::IncA as u8);
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.a, 0x02);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+}
+
+#[cfg(test)]
+mod test_store_hl_immediate {
+    use super::*;
+
+    #[test]
+    fn test_store_hl_immediate() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_hl(0xC000);
+
+        cpu.set_byte_in_memory(start, Instruction::StoreHlImmediate as u8);
+        cpu.set_byte_in_memory(start + 1, 0x99);
+
+        assert_eq!(cpu.step().unwrap(), 12);
+        assert_eq!(cpu.memory.get_data(0xC000), 0x99);
+        assert_eq!(cpu.pc, start + 2);
+        assert_eq!(cpu.get_hl(), 0xC000);
+    }
+}
+
+#[cfg(test)]
+mod test_read_modify_write {
+    use super::*;
+
+    #[test]
+    fn test_inc_hl_reads_then_writes() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::IncHlIndirect as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(
+            cpu.queued_micro_ops(),
+            vec![
+                MicroOp::ReadModify {
+                    operation: ReadModifyWriteOp::Increment
+                },
+                MicroOp::WriteBack,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inc_hl() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_hl(0xC000);
+        cpu.set_byte_in_memory(0xC000, 0x0F);
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::IncHlIndirect as u8);
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
+        // the new value isn't visible until the write cycle
+        assert_eq!(cpu.memory.get_data(0xC000), 0x0F);
+
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.memory.get_data(0xC000), 0x10);
+        assert_eq!(cpu.flags, CpuFlags::HALF_CARRY_FLAG);
+    }
+
+    #[test]
+    fn test_dec_hl() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_hl(0xC000);
+        cpu.set_byte_in_memory(0xC000, 0x01);
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::DecHlIndirect as u8);
+        assert_eq!(cpu.step().unwrap(), 12);
+
+        assert_eq!(cpu.memory.get_data(0xC000), 0x00);
+        assert_eq!(cpu.flags, CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG);
+    }
+
+    #[test]
+    fn test_sla_hl() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_hl(0xC000);
+        cpu.set_byte_in_memory(0xC000, 0x81);
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::Prefix as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, PrefixedInstruction::SlaHl as u8);
+        assert_eq!(cpu.step().unwrap(), 16);
+
+        assert_eq!(cpu.memory.get_data(0xC000), 0x02);
+        assert_eq!(cpu.flags, CpuFlags::CARRY_FLAG);
+    }
+}
+
+#[cfg(test)]
+mod test_sixteen_bit_registers {
+    use super::*;
+
+    #[test]
+    fn test_af_round_trip() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.set_sixteen(SixteenBitRegister::Af, 0x12FF);
+
+        assert_eq!(cpu.a, 0x12);
+        assert_eq!(
+            cpu.flags,
+            CpuFlags::ZERO_FLAG
+                | CpuFlags::SUBTRACTION_FLAG
+                | CpuFlags::HALF_CARRY_FLAG
+                | CpuFlags::CARRY_FLAG
+        );
+        // the low nibble of F doesn't survive the round trip
+        assert_eq!(cpu.get_sixteen(SixteenBitRegister::Af), 0x12F0);
+    }
+
+    #[test]
+    fn test_store_to_af() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.micro_op_queue
+            .push_back(MicroOp::StoreToSixteenBitRegister {
+                register: SixteenBitRegister::Af,
+                value: 0xAB5A,
+            });
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.get_af(), 0xAB50);
+    }
 }
 
 #[cfg(test)]
@@ -859,8 +3716,8 @@ mod test_inc_rr {
         cpu.set_bc(0xFFFF);
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncBc as u8);
 
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.get_bc(), 0x0000);
     }
 
@@ -871,8 +3728,8 @@ mod test_inc_rr {
         cpu.set_de(0xFFFF);
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncDe as u8);
 
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.get_de(), 0x0000);
     }
 
@@ -883,8 +3740,8 @@ mod test_inc_rr {
         cpu.set_hl(0xFFFF);
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncHl as u8);
 
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.get_hl(), 0x0000);
     }
 
@@ -895,50 +3752,98 @@ mod test_inc_rr {
         cpu.set_sp(0xFFFF);
         cpu.set_byte_in_memory(cpu.pc, Instruction::IncSp as u8);
 
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
         assert_eq!(cpu.get_sp(), 0x0000);
     }
+
+    #[test]
+    fn test_dec_de() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_de(0x0000);
+        cpu.set_byte_in_memory(cpu.pc, Instruction::DecDe as u8);
+
+        assert_eq!(cpu.step().unwrap(), 8);
+        assert_eq!(cpu.get_de(), 0xFFFF);
+    }
+
+    // 16-bit INC and DEC share the register store micro op with loads, so
+    // make sure none of them pick up flag updates
+    #[test]
+    fn test_sixteen_bit_inc_dec_preserve_flags() {
+        let instructions = [
+            Instruction::IncBc as u8,
+            Instruction::IncDe as u8,
+            Instruction::IncHl as u8,
+            Instruction::IncSp as u8,
+            Instruction::DecBc as u8,
+            Instruction::DecDe as u8,
+            Instruction::DecHl as u8,
+            Instruction::DecSp as u8,
+        ];
+
+        for all_flags in [true, false] {
+            for opcode in instructions {
+                let mut memory = memory::Memory::new();
+                let mut cpu = Cpu::new(&mut memory);
+                let expected_flags = match all_flags {
+                    true => CpuFlags::all(),
+                    false => CpuFlags::empty(),
+                };
+                cpu.flags = expected_flags;
+                // wrap every register so a carry out would show up if computed
+                cpu.set_bc(0xFFFF);
+                cpu.set_de(0xFFFF);
+                cpu.set_hl(0x0000);
+                cpu.set_sp(0x0FFF);
+
+                cpu.set_byte_in_memory(cpu.pc, opcode);
+                cpu.step().unwrap();
+
+                assert_eq!(cpu.get_f(), expected_flags.bits(), "opcode {:#04X}", opcode);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test_load_sixteen_bit_immediate {
+    use super::scenario::Scenario;
     use super::*;
 
     #[test]
     fn test_load_bc() {
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
         let lower_byte = 0x0F;
         let upper_byte = 0xF0;
 
-        cpu.set_byte_in_memory(cpu.pc, Instruction::LoadBcTwoByteImmediate as u8);
-        cpu.set_byte_in_memory(cpu.pc + 1, lower_byte);
-        cpu.set_byte_in_memory(cpu.pc + 2, upper_byte);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        let outcome = Scenario::new()
+            .rom(&[
+                Instruction::LoadBcTwoByteImmediate as u8,
+                lower_byte,
+                upper_byte,
+            ])
+            .run_instructions(1);
 
-        assert_eq!(cpu.c, lower_byte);
-        assert_eq!(cpu.b, upper_byte);
+        assert_eq!(outcome.c, lower_byte);
+        assert_eq!(outcome.b, upper_byte);
     }
 
     #[test]
     fn test_load_de() {
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
         let lower_byte = 0x0F;
         let upper_byte = 0xF0;
 
-        cpu.set_byte_in_memory(cpu.pc, Instruction::LoadDeTwoByteImmediate as u8);
-        cpu.set_byte_in_memory(cpu.pc + 1, lower_byte);
-        cpu.set_byte_in_memory(cpu.pc + 2, upper_byte);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        let outcome = Scenario::new()
+            .rom(&[
+                Instruction::LoadDeTwoByteImmediate as u8,
+                lower_byte,
+                upper_byte,
+            ])
+            .run_instructions(1);
 
-        assert_eq!(cpu.e, lower_byte);
-        assert_eq!(cpu.d, upper_byte);
+        assert_eq!(outcome.e, lower_byte);
+        assert_eq!(outcome.d, upper_byte);
     }
 
     #[test]
@@ -951,9 +3856,9 @@ mod test_load_sixteen_bit_immediate {
         cpu.set_byte_in_memory(cpu.pc, Instruction::LoadHlTwoByteImmediate as u8);
         cpu.set_byte_in_memory(cpu.pc + 1, lower_byte);
         cpu.set_byte_in_memory(cpu.pc + 2, upper_byte);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.l, lower_byte);
         assert_eq!(cpu.h, upper_byte);
@@ -961,22 +3866,37 @@ mod test_load_sixteen_bit_immediate {
 
     #[test]
     fn test_load_sp() {
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
         let lower_byte = 0x0F;
         let upper_byte = 0xF0;
 
-        cpu.set_byte_in_memory(cpu.pc, Instruction::LoadSpTwoByteImmediate as u8);
-        cpu.set_byte_in_memory(cpu.pc + 1, lower_byte);
-        cpu.set_byte_in_memory(cpu.pc + 2, upper_byte);
-        cpu.execute_instruction();
-        cpu.execute_instruction();
-        cpu.execute_instruction();
+        let outcome = Scenario::new()
+            .rom(&[
+                Instruction::LoadSpTwoByteImmediate as u8,
+                lower_byte,
+                upper_byte,
+            ])
+            .run_instructions(1);
+
+        assert_eq!(outcome.sp, ((upper_byte as u16) << 8) + (lower_byte as u16));
+    }
+
+    #[test]
+    fn test_store_sp() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.sp = 0xBEEF;
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::StoreSp as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, 0x00);
+        cpu.set_byte_in_memory(cpu.pc + 2, 0xC0);
 
-        assert_eq!(cpu.sp, ((upper_byte as u16) << 8) + (lower_byte as u16));
+        assert_eq!(cpu.step().unwrap(), 20);
+        assert_eq!(cpu.memory.get_data(0xC000), 0xEF);
+        assert_eq!(cpu.memory.get_data(0xC001), 0xBE);
     }
 }
 
+#[cfg(test)]
 mod test_adc {
     use super::*;
 
@@ -989,7 +3909,7 @@ mod test_adc {
 
         cpu.a = 0x02;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1004,7 +3924,7 @@ mod test_adc {
 
         cpu.a = 0x80;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1020,7 +3940,7 @@ mod test_adc {
 
         cpu.a = 0x02;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1037,7 +3957,7 @@ mod test_adc {
         cpu.a = 0x80;
         cpu.b = 0x80;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1053,7 +3973,7 @@ mod test_adc {
         cpu.a = 0x02;
         cpu.b = 0x02;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1069,7 +3989,7 @@ mod test_adc {
         cpu.a = 0x80;
         cpu.b = 0x80;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1086,7 +4006,7 @@ mod test_adc {
         cpu.a = 0x02;
         cpu.b = 0x02;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1103,14 +4023,17 @@ mod test_adc {
         cpu.a = 0x80;
         cpu.b = 0x80;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AdcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 }
 
-#[cfg(test)]
+// The modules below run every ALU instruction against every register. They
+// only build with `cargo test --features slow-tests`, test_alu covers each
+// operation the rest of the time
+#[cfg(all(test, feature = "slow-tests"))]
 mod test_sub {
     use super::*;
 
@@ -1123,7 +4046,7 @@ mod test_sub {
 
         cpu.a = 0xFF;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1139,7 +4062,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.b = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1155,7 +4078,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.b = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1171,7 +4094,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.c = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAC as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1187,7 +4110,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.c = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAC as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1203,7 +4126,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.d = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAD as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1219,7 +4142,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.d = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAD as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1235,7 +4158,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.e = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAE as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1251,7 +4174,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.e = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAE as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1267,7 +4190,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.h = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAH as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1283,7 +4206,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.h = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAH as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1299,7 +4222,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.l = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAL as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1315,7 +4238,7 @@ mod test_sub {
         cpu.a = 0xFF;
         cpu.l = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SubAL as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1335,7 +4258,7 @@ mod test_sbc {
 
         cpu.a = 0xFF;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1344,14 +4267,15 @@ mod test_sbc {
     #[test]
     fn test_sbc_aa_with_carry() {
         let expected_value = 0xFF;
-        let expected_flags = CpuFlags::SUBTRACTION_FLAG | CpuFlags::CARRY_FLAG;
+        let expected_flags =
+            CpuFlags::SUBTRACTION_FLAG | CpuFlags::HALF_CARRY_FLAG | CpuFlags::CARRY_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
         cpu.flags.set(CpuFlags::CARRY_FLAG, true);
 
         cpu.a = 0xFF;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1367,7 +4291,7 @@ mod test_sbc {
         cpu.a = 0xFF;
         cpu.b = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1383,7 +4307,7 @@ mod test_sbc {
         cpu.a = 0xFF;
         cpu.b = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1400,7 +4324,7 @@ mod test_sbc {
         cpu.a = 0xFF;
         cpu.b = 0xF0;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1409,7 +4333,8 @@ mod test_sbc {
     #[test]
     fn test_sbc_ab_zero_with_carry() {
         let expected_value = 0xFF;
-        let expected_flags = CpuFlags::CARRY_FLAG | CpuFlags::SUBTRACTION_FLAG;
+        let expected_flags =
+            CpuFlags::CARRY_FLAG | CpuFlags::HALF_CARRY_FLAG | CpuFlags::SUBTRACTION_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
         cpu.flags.set(CpuFlags::CARRY_FLAG, true);
@@ -1417,14 +4342,47 @@ mod test_sbc {
         cpu.a = 0xFF;
         cpu.b = cpu.a;
         cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_sbc_half_carry_from_carry_in() {
+        let expected_value = 0x0F;
+        let expected_flags = CpuFlags::SUBTRACTION_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.flags.set(CpuFlags::CARRY_FLAG, true);
+
+        cpu.a = 0x20;
+        cpu.b = 0x10;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAB as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_sbc_zero_operand_no_carry() {
+        let expected_value = 0x42;
+        let expected_flags = CpuFlags::SUBTRACTION_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0x42;
+        cpu.b = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::SbcAB as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "slow-tests"))]
 mod and_tests {
     use super::*;
 
@@ -1437,7 +4395,7 @@ mod and_tests {
 
         cpu.a = expected_value;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1452,7 +4410,7 @@ mod and_tests {
 
         cpu.a = expected_value;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAA as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1468,7 +4426,7 @@ mod and_tests {
         cpu.a = 0xFF;
         cpu.b = 0x0F;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1484,7 +4442,7 @@ mod and_tests {
         cpu.a = 0xF0;
         cpu.b = 0x0F;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAB as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1500,7 +4458,7 @@ mod and_tests {
         cpu.a = 0xFF;
         cpu.c = 0x0F;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAC as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1516,7 +4474,7 @@ mod and_tests {
         cpu.a = 0xF0;
         cpu.c = 0x0F;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAC as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1532,7 +4490,7 @@ mod and_tests {
         cpu.a = 0xFF;
         cpu.d = 0x0F;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAD as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1548,7 +4506,7 @@ mod and_tests {
         cpu.a = 0xF0;
         cpu.d = 0x0F;
         cpu.set_byte_in_memory(cpu.pc, Instruction::AndAD as u8);
-        cpu.execute_instruction();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
@@ -1563,145 +4521,371 @@ mod and_tests {
 
         cpu.a = 0xFF;
         cpu.e = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAE as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAE as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_and_ae_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xF0;
+        cpu.e = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAE as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_and_ah_non_zero() {
+        let expected_value = 0x0F;
+        let expected_flags = CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xFF;
+        cpu.h = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAH as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_and_ah_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xF0;
+        cpu.h = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAH as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_and_al_non_zero() {
+        let expected_value = 0x0F;
+        let expected_flags = CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xFF;
+        cpu.l = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAL as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_and_al_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xF0;
+        cpu.l = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAL as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod or_test {
+    use super::*;
+    #[test]
+    fn test_or_aa_non_zero() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::empty();
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = expected_value;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAA as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_aa_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = expected_value;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAA as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_ab_non_zero() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::empty();
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xFF;
+        cpu.b = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAB as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_ab_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0x00;
+        cpu.b = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAB as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_ac_non_zero() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::empty();
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xFF;
+        cpu.c = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAC as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_ac_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0x00;
+        cpu.c = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAC as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_ad_non_zero() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::empty();
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xFF;
+        cpu.d = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAD as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_ad_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0x00;
+        cpu.d = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAD as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_or_ae_non_zero() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::empty();
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0xFF;
+        cpu.e = 0x0F;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAE as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_and_ae_zero() {
+    fn test_or_ae_zero() {
         let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0xF0;
-        cpu.e = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAE as u8);
-        cpu.execute_instruction();
+        cpu.a = 0x00;
+        cpu.e = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAE as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_and_ah_non_zero() {
-        let expected_value = 0x0F;
-        let expected_flags = CpuFlags::HALF_CARRY_FLAG;
+    fn test_or_ah_non_zero() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.h = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAH as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAH as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_and_ah_zero() {
+    fn test_or_ah_zero() {
         let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0xF0;
-        cpu.h = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAH as u8);
-        cpu.execute_instruction();
+        cpu.a = 0x00;
+        cpu.h = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAH as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_and_al_non_zero() {
-        let expected_value = 0x0F;
-        let expected_flags = CpuFlags::HALF_CARRY_FLAG;
+    fn test_or_al_non_zero() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.l = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAL as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAL as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_and_al_zero() {
+    fn test_or_al_zero() {
         let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::HALF_CARRY_FLAG;
+        let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0xF0;
-        cpu.l = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::AndAL as u8);
-        cpu.execute_instruction();
+        cpu.a = 0x00;
+        cpu.l = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAL as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 }
 
-#[cfg(test)]
-mod or_test {
+#[cfg(all(test, feature = "slow-tests"))]
+mod xortest {
     use super::*;
     #[test]
-    fn test_or_aa_non_zero() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::empty();
+    fn test_xor_aa_non_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = expected_value;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAA as u8);
-        cpu.execute_instruction();
+        cpu.a = 0x01;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAA as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_aa_zero() {
+    fn test_xor_aa_zero() {
         let expected_value = 0x00;
         let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = expected_value;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAA as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAA as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ab_non_zero() {
-        let expected_value = 0xFF;
+    fn test_xor_ab_non_zero() {
+        let expected_value = 0xF0;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.b = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAB as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAB as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ab_zero() {
+    fn test_xor_ab_zero() {
         let expected_value = 0x00;
         let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
@@ -1709,31 +4893,31 @@ mod or_test {
 
         cpu.a = 0x00;
         cpu.b = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAB as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAB as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ac_non_zero() {
-        let expected_value = 0xFF;
+    fn test_xor_ac_non_zero() {
+        let expected_value = 0xF0;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.c = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAC as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAC as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ac_zero() {
+    fn test_xor_ac_zero() {
         let expected_value = 0x00;
         let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
@@ -1741,31 +4925,31 @@ mod or_test {
 
         cpu.a = 0x00;
         cpu.c = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAC as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAC as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ad_non_zero() {
-        let expected_value = 0xFF;
+    fn test_xor_ad_non_zero() {
+        let expected_value = 0xF0;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.d = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAD as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAD as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ad_zero() {
+    fn test_xor_ad_zero() {
         let expected_value = 0x00;
         let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
@@ -1773,31 +4957,31 @@ mod or_test {
 
         cpu.a = 0x00;
         cpu.d = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAD as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAD as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ae_non_zero() {
-        let expected_value = 0xFF;
+    fn test_xor_ae_non_zero() {
+        let expected_value = 0xF0;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.e = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAE as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAE as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ae_zero() {
+    fn test_xor_ae_zero() {
         let expected_value = 0x00;
         let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
@@ -1805,31 +4989,31 @@ mod or_test {
 
         cpu.a = 0x00;
         cpu.e = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAE as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAE as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ah_non_zero() {
-        let expected_value = 0xFF;
+    fn test_xor_ah_non_zero() {
+        let expected_value = 0xF0;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.h = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAH as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAH as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_ah_zero() {
+    fn test_xor_ah_zero() {
         let expected_value = 0x00;
         let expected_flags = CpuFlags::ZERO_FLAG;
         let mut memory = memory::Memory::new();
@@ -1837,383 +5021,879 @@ mod or_test {
 
         cpu.a = 0x00;
         cpu.h = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAH as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAH as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_al_non_zero() {
-        let expected_value = 0xFF;
+    fn test_xor_al_non_zero() {
+        let expected_value = 0xF0;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = 0xFF;
         cpu.l = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAL as u8);
-        cpu.execute_instruction();
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAL as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_xor_al_zero() {
+        let expected_value = 0x00;
+        let expected_flags = CpuFlags::ZERO_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = 0x00;
+        cpu.l = 0x00;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAL as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.flags, expected_flags);
+    }
+}
+
+#[cfg(test)]
+mod test_stop {
+    use super::*;
+    use crate::model::Model;
+
+    #[test]
+    fn test_stop_switches_speed() {
+        let mut memory = memory::Memory::new();
+        memory.set_model(Model::Cgb);
+        memory.set_byte(0xFF4D, 0x01);
+        assert_eq!(memory.get_data(0xFF4D), 0x7F);
+
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_byte_in_memory(start, Instruction::Stop as u8);
+        cpu.set_byte_in_memory(start + 1, 0x00);
+        cpu.execute_instruction().unwrap();
+
+        assert_eq!(cpu.pc, start + 2);
+        assert!(cpu.memory.double_speed());
+        assert_eq!(cpu.memory.get_data(0xFF4D), 0xFE);
+    }
+
+    #[test]
+    fn test_stop_without_armed_switch() {
+        let mut memory = memory::Memory::new();
+        memory.set_byte(0xFF4D, 0x01);
+        assert_eq!(memory.get_data(0xFF4D), 0xFF);
+
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_byte_in_memory(cpu.pc, Instruction::Stop as u8);
+        cpu.execute_instruction().unwrap();
+
+        assert!(!cpu.memory.double_speed());
+    }
+}
+
+#[cfg(test)]
+mod test_interrupts {
+    use super::*;
+
+    #[test]
+    fn test_halt_waits_for_pending_interrupt() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_byte_in_memory(start, Instruction::Halt as u8);
+        cpu.memory_mut().set_ie(memory::VBLANK_INTERRUPT);
+
+        cpu.step().unwrap();
+        assert!(cpu.is_halted());
+        // requested but disabled interrupts don't wake the CPU
+        cpu.memory_mut().request_interrupt(memory::TIMER_INTERRUPT);
+        assert_eq!(cpu.step().unwrap(), 4);
+        assert_eq!(cpu.pc, start + 1);
+
+        cpu.memory_mut().request_interrupt(memory::VBLANK_INTERRUPT);
+        cpu.step().unwrap();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.pc, start + 2);
+    }
+
+    #[test]
+    fn test_vblank_interrupt_is_serviced() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.set_ime(true);
+        cpu.memory_mut().set_ie(memory::VBLANK_INTERRUPT);
+
+        cpu.request_interrupt(Interrupt::VBlank);
+        assert_eq!(cpu.step().unwrap(), 20);
+
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.memory().interrupt_flag() & memory::VBLANK_INTERRUPT, 0);
+        assert!(!cpu.ime());
+        assert_eq!(cpu.sp, INITIAL_SP - 2);
+        assert_eq!(cpu.memory().peek(cpu.sp), start as u8);
+        assert_eq!(cpu.memory().peek(cpu.sp + 1), (start >> 8) as u8);
+    }
+
+    #[test]
+    fn test_interrupts_wait_for_ime_and_follow_priority() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        let start = cpu.pc;
+        cpu.memory_mut().set_ie(0x1F);
+        cpu.request_interrupt(Interrupt::Joypad);
+        cpu.request_interrupt(Interrupt::Timer);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, start + 1);
+
+        cpu.set_ime(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, Interrupt::Timer.vector());
+        assert_eq!(
+            cpu.memory().interrupt_flag() & 0x1F,
+            memory::JOYPAD_INTERRUPT
+        );
+    }
+
+    #[test]
+    fn test_interrupt_state_snapshot() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.memory_mut()
+            .set_ie(Interrupt::VBlank.bit() | Interrupt::Timer.bit());
+        cpu.request_interrupt(Interrupt::Timer);
+        cpu.request_interrupt(Interrupt::Serial);
+        cpu.request_interrupt(Interrupt::VBlank);
+        cpu.set_ime(true);
+        cpu.halted = true;
+
+        let state = cpu.interrupt_state();
+
+        assert!(state.ime);
+        assert!(state.halted);
+        assert_eq!(state.ie, 0x05);
+        assert_eq!(state.if_, 0x0D);
+        assert_eq!(state.pending, 0x05);
+        assert_eq!(
+            state.to_string(),
+            "IME:1 IE:05 IF:0D HALT:1 PENDING:05 (VBlank, Timer)"
+        );
+
+        cpu.clear_interrupt_requests();
+        let state = cpu.interrupt_state();
+        assert_eq!(state.pending, 0);
+        assert_eq!(state.to_string(), "IME:1 IE:05 IF:00 HALT:1 PENDING:00");
+    }
+}
+
+#[cfg(test)]
+mod test_carry_flag {
+    use super::scenario::Scenario;
+    use super::*;
+
+    #[test]
+    fn test_scf() {
+        let outcome = Scenario::new()
+            .flags(CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG | CpuFlags::HALF_CARRY_FLAG)
+            .rom(&[Instruction::SetCarryFlag as u8])
+            .run_instructions(1);
+
+        assert_eq!(outcome.flags, CpuFlags::ZERO_FLAG | CpuFlags::CARRY_FLAG);
+    }
+
+    #[test]
+    fn test_ccf() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.flags = CpuFlags::CARRY_FLAG | CpuFlags::HALF_CARRY_FLAG;
+
+        cpu.set_byte_in_memory(cpu.pc, Instruction::ComplementCarryFlag as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, Instruction::ComplementCarryFlag as u8);
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.flags, CpuFlags::empty());
+
+        cpu.execute_instruction().unwrap();
+        assert_eq!(cpu.flags, CpuFlags::CARRY_FLAG);
+    }
+
+    #[test]
+    fn test_f_low_nibble_always_zero() {
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_f(0xFF);
+        assert_eq!(cpu.get_f(), 0xF0);
+
+        let program = [
+            Instruction::SetCarryFlag as u8,
+            Instruction::ComplementCarryFlag as u8,
+            Instruction::AddAA as u8,
+            Instruction::SubAB as u8,
+            Instruction::SetCarryFlag as u8,
+            Instruction::AdcAA as u8,
+        ];
+        for (i, opcode) in program.iter().enumerate() {
+            cpu.set_byte_in_memory(cpu.pc + i as u16, *opcode);
+        }
+        cpu.a = 0x0F;
+        cpu.b = 0x1F;
+
+        for _ in program {
+            cpu.step().unwrap();
+            assert_eq!(cpu.get_f() & 0x0F, 0);
+            assert_eq!(cpu.get_eight_bit_register(EightBitRegister::F) & 0x0F, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_cp {
+    use super::scenario::Scenario;
+    use super::*;
+
+    #[test]
+    fn test_cp_aa() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+        let mut memory = memory::Memory::new();
+        let mut cpu = Cpu::new(&mut memory);
+
+        cpu.a = expected_value;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAA as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_or_al_zero() {
-        let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
+    fn test_cp_ab() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+
+        let outcome = Scenario::new()
+            .reg_a(expected_value)
+            .reg_b(expected_value)
+            .rom(&[Instruction::CpAB as u8])
+            .run_instructions(1);
+
+        assert_eq!(outcome.a, expected_value);
+        assert_eq!(outcome.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_cp_ac() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+
+        let outcome = Scenario::new()
+            .reg_a(expected_value)
+            .reg_c(expected_value)
+            .rom(&[Instruction::CpAC as u8])
+            .run_instructions(1);
+
+        assert_eq!(outcome.a, expected_value);
+        assert_eq!(outcome.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_cp_ad() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+
+        let outcome = Scenario::new()
+            .reg_a(expected_value)
+            .reg_d(expected_value)
+            .rom(&[Instruction::CpAD as u8])
+            .run_instructions(1);
+
+        assert_eq!(outcome.a, expected_value);
+        assert_eq!(outcome.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_cp_ae() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+
+        let outcome = Scenario::new()
+            .reg_a(expected_value)
+            .reg_e(expected_value)
+            .rom(&[Instruction::CpAE as u8])
+            .run_instructions(1);
+
+        assert_eq!(outcome.a, expected_value);
+        assert_eq!(outcome.flags, expected_flags);
+    }
+
+    #[test]
+    fn test_cp_ah() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0x00;
-        cpu.l = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::OrAL as u8);
-        cpu.execute_instruction();
+        cpu.a = expected_value;
+        cpu.h = cpu.a;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAH as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
-}
 
-#[cfg(test)]
-mod xortest {
-    use super::*;
     #[test]
-    fn test_xor_aa_non_zero() {
-        let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
+    fn test_cp_al() {
+        let expected_value = 0xFF;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0x01;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAA as u8);
-        cpu.execute_instruction();
+        cpu.a = expected_value;
+        cpu.l = cpu.a;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAL as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_xor_aa_zero() {
-        let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
+    fn test_cp_carry_when_less() {
+        let expected_value = 0x10;
+        let expected_flags = CpuFlags::SUBTRACTION_FLAG | CpuFlags::CARRY_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
         cpu.a = expected_value;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAA as u8);
-        cpu.execute_instruction();
+        cpu.b = 0x20;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAB as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_xor_ab_non_zero() {
-        let expected_value = 0xF0;
-        let expected_flags = CpuFlags::empty();
+    fn test_cp_half_carry_on_nibble_borrow() {
+        let expected_value = 0x10;
+        let expected_flags = CpuFlags::SUBTRACTION_FLAG | CpuFlags::HALF_CARRY_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0xFF;
-        cpu.b = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAB as u8);
-        cpu.execute_instruction();
+        cpu.a = expected_value;
+        cpu.b = 0x01;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAB as u8);
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
+}
+
+#[cfg(test)]
+mod test_shift {
+    use super::*;
 
     #[test]
-    fn test_xor_ab_zero() {
+    fn test_sla_b() {
         let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::CARRY_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0x00;
-        cpu.b = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAB as u8);
-        cpu.execute_instruction();
+        cpu.b = 0x80;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::Prefix as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, PrefixedInstruction::SlaB as u8);
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.b, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_xor_ac_non_zero() {
-        let expected_value = 0xF0;
+    fn test_sra_b_keeps_sign() {
+        let expected_value = 0xC0;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
+        cpu.flags.set(CpuFlags::CARRY_FLAG, true);
 
-        cpu.a = 0xFF;
-        cpu.c = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAC as u8);
-        cpu.execute_instruction();
+        cpu.b = 0x80;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::Prefix as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, PrefixedInstruction::SraB as u8);
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.b, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_xor_ac_zero() {
+    fn test_sra_a_carry_out() {
         let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
+        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::CARRY_FLAG;
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = 0x00;
-        cpu.c = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAC as u8);
-        cpu.execute_instruction();
+        cpu.a = 0x01;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::Prefix as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, PrefixedInstruction::SraA as u8);
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
         assert_eq!(cpu.a, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
 
     #[test]
-    fn test_xor_ad_non_zero() {
-        let expected_value = 0xF0;
+    fn test_srl_b_clears_bit_seven() {
+        let expected_value = 0x40;
         let expected_flags = CpuFlags::empty();
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
+        cpu.flags.set(CpuFlags::CARRY_FLAG, true);
 
-        cpu.a = 0xFF;
-        cpu.d = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAD as u8);
-        cpu.execute_instruction();
+        cpu.b = 0x80;
+        cpu.set_byte_in_memory(cpu.pc, Instruction::Prefix as u8);
+        cpu.set_byte_in_memory(cpu.pc + 1, PrefixedInstruction::SrlB as u8);
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
+        assert_eq!(cpu.b, expected_value);
         assert_eq!(cpu.flags, expected_flags);
     }
+}
+
+#[cfg(test)]
+mod test_address_wrap {
+    use super::*;
 
     #[test]
-    fn test_xor_ad_zero() {
-        let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
+    fn test_nop_at_top_of_memory_wraps_pc() {
         let mut memory = memory::Memory::new();
+        memory.set_ie(Instruction::Nop as u8);
         let mut cpu = Cpu::new(&mut memory);
+        cpu.pc = 0xFFFF;
 
-        cpu.a = 0x00;
-        cpu.d = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAD as u8);
-        cpu.execute_instruction();
+        cpu.step().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+        assert_eq!(cpu.pc, 0x0000);
     }
 
     #[test]
-    fn test_xor_ae_non_zero() {
-        let expected_value = 0xF0;
-        let expected_flags = CpuFlags::empty();
+    fn test_operand_fetch_wraps_pc() {
         let mut memory = memory::Memory::new();
+        // LD A,$42 with the operand in IE
+        memory.set_byte(0xFFFE, 0x3E);
+        memory.set_ie(0x42);
         let mut cpu = Cpu::new(&mut memory);
+        cpu.pc = 0xFFFE;
 
-        cpu.a = 0xFF;
-        cpu.e = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAE as u8);
-        cpu.execute_instruction();
+        cpu.step().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.pc, 0x0000);
     }
 
     #[test]
-    fn test_xor_ae_zero() {
-        let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
+    fn test_prefixed_opcode_fetch_wraps_pc() {
         let mut memory = memory::Memory::new();
+        // SRA D, with its second byte back at the start of the ROM
+        memory.set_ie(0xCB);
+        memory.load_at(0x0000, &[PrefixedInstruction::SraD as u8]);
         let mut cpu = Cpu::new(&mut memory);
+        cpu.pc = 0xFFFF;
+        cpu.d = 0x82;
 
-        cpu.a = 0x00;
-        cpu.e = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAE as u8);
-        cpu.execute_instruction();
+        cpu.step().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+        assert_eq!(cpu.d, 0xC1);
+        assert_eq!(cpu.pc, 0x0001);
     }
 
     #[test]
-    fn test_xor_ah_non_zero() {
-        let expected_value = 0xF0;
-        let expected_flags = CpuFlags::empty();
+    fn test_hl_increment_wraps() {
         let mut memory = memory::Memory::new();
+        memory.load_at(INITIAL_PC, &[Instruction::StoreHlPlusA as u8]);
         let mut cpu = Cpu::new(&mut memory);
+        cpu.set_hl(0xFFFF);
+        cpu.a = 0x99;
 
-        cpu.a = 0xFF;
-        cpu.h = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAH as u8);
-        cpu.execute_instruction();
+        cpu.step().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+        assert_eq!(cpu.memory.ie(), 0x99);
+        assert_eq!(cpu.get_hl(), 0x0000);
     }
+}
 
-    #[test]
-    fn test_xor_ah_zero() {
-        let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
+#[cfg(test)]
+mod test_save_state {
+    use super::*;
 
-        cpu.a = 0x00;
-        cpu.h = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAH as u8);
-        cpu.execute_instruction();
+    // LD BC,$1234 then INC BC
+    const PROGRAM: [u8; 4] = [0x01, 0x34, 0x12, 0x03];
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+    fn load_program() -> memory::Memory {
+        let mut memory = memory::Memory::new();
+        memory.load_at(INITIAL_PC, &PROGRAM);
+        memory
     }
 
     #[test]
-    fn test_xor_al_non_zero() {
-        let expected_value = 0xF0;
-        let expected_flags = CpuFlags::empty();
-        let mut memory = memory::Memory::new();
+    fn test_state_saved_mid_instruction_resumes() {
+        let mut memory = load_program();
         let mut cpu = Cpu::new(&mut memory);
+        // the opcode fetch and the first operand read
+        cpu.execute_instruction().unwrap();
+        cpu.execute_instruction().unwrap();
+        assert!(!cpu.micro_op_queue.is_empty());
+        let mut writer = StateWriter::new();
+        cpu.save_state(&mut writer);
+        let state = writer.finish();
+
+        let mut fresh_memory = load_program();
+        let mut resumed = Cpu::new(&mut fresh_memory);
+        resumed
+            .load_state(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert_eq!(resumed.micro_op_queue, cpu.micro_op_queue);
+        while !resumed.micro_op_queue.is_empty() {
+            resumed.execute_instruction().unwrap();
+        }
+        resumed.step().unwrap();
 
-        cpu.a = 0xFF;
-        cpu.l = 0x0F;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAL as u8);
-        cpu.execute_instruction();
+        let mut uninterrupted_memory = load_program();
+        let mut uninterrupted = Cpu::new(&mut uninterrupted_memory);
+        uninterrupted.step().unwrap();
+        uninterrupted.step().unwrap();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+        assert_eq!(resumed.get_bc(), 0x1235);
+        assert_eq!(resumed.get_bc(), uninterrupted.get_bc());
+        assert_eq!(resumed.pc, uninterrupted.pc);
     }
 
     #[test]
-    fn test_xor_al_zero() {
-        let expected_value = 0x00;
-        let expected_flags = CpuFlags::ZERO_FLAG;
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
+    fn test_every_micro_op_round_trips() {
+        let micro_ops = [
+            MicroOp::LoadImmediate {
+                destination: EightBitRegister::Z,
+            },
+            MicroOp::StoreToMemory {
+                value: 0x12,
+                address: 0xC034,
+            },
+            MicroOp::StoreToSixteenBitRegister {
+                register: SixteenBitRegister::Sp,
+                value: 0xFFFE,
+            },
+            MicroOp::StoreWordToMemory { value: 0xBEEF },
+            MicroOp::Internal,
+            MicroOp::ReadModify {
+                operation: ReadModifyWriteOp::ShiftRightLogical,
+            },
+            MicroOp::WriteBack,
+            MicroOp::ExecutePrefixed,
+            MicroOp::StoreToHighPage { value: 0x80 },
+            MicroOp::LoadFromHighPage {
+                destination: EightBitRegister::A,
+            },
+            MicroOp::RelativeJump,
+            MicroOp::Jump,
+            MicroOp::Push { value: 0x01 },
+            MicroOp::Pop {
+                destination: EightBitRegister::W,
+            },
+        ];
 
-        cpu.a = 0x00;
-        cpu.l = 0x00;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::XorAL as u8);
-        cpu.execute_instruction();
+        let mut writer = StateWriter::new();
+        for micro_op in &micro_ops {
+            micro_op.save_state(&mut writer);
+        }
+        let state = writer.finish();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+        let mut reader = StateReader::new(&state).unwrap();
+        for micro_op in &micro_ops {
+            assert_eq!(MicroOp::load_state(&mut reader).unwrap(), *micro_op);
+        }
     }
 }
 
-mod test_cp {
+#[cfg(test)]
+mod test_post_boot_state {
     use super::*;
 
     #[test]
-    fn test_cp_aa() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
+    fn test_a_identifies_model() {
+        for (model, expected_a) in [(Model::Dmg, 0x01), (Model::Sgb, 0x01), (Model::Cgb, 0x11)] {
+            let mut memory = memory::Memory::with_model(model);
+            let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = expected_value;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAA as u8);
-        cpu.execute_instruction();
+            cpu.set_post_boot_state();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+            assert_eq!(cpu.a, expected_a);
+            assert_eq!(cpu.model(), model);
+        }
     }
 
     #[test]
-    fn test_cp_ab() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+    fn test_dmg_registers() {
         let mut memory = memory::Memory::new();
         let mut cpu = Cpu::new(&mut memory);
 
-        cpu.a = expected_value;
-        cpu.b = cpu.a;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAB as u8);
-        cpu.execute_instruction();
+        cpu.set_post_boot_state();
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+        assert_eq!(cpu.get_af(), 0x01B0);
+        assert_eq!(cpu.get_bc(), 0x0013);
+        assert_eq!(cpu.get_de(), 0x00D8);
+        assert_eq!(cpu.get_hl(), 0x014D);
+        assert_eq!(cpu.sp, INITIAL_SP);
+        assert_eq!(cpu.pc, INITIAL_PC);
     }
+}
 
-    #[test]
-    fn test_cp_ac() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
+// feeds byte streams into a fresh CPU to make sure no opcode sequence can
+// panic it, only stop it with an error
+#[cfg(test)]
+mod test_fuzz {
+    use super::*;
 
-        cpu.a = expected_value;
-        cpu.c = cpu.a;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAC as u8);
-        cpu.execute_instruction();
+    const MAX_INSTRUCTIONS: usize = 200;
+    const RANDOM_PROGRAMS: usize = 2000;
+    const PROGRAM_LENGTH: usize = 64;
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+    // xorshift, so runs are repeatable without pulling in a crate
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(self: &mut Self) -> u8 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            (self.0 >> 24) as u8
+        }
     }
 
-    #[test]
-    fn test_cp_ad() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+    // runs the program until it errors or the instruction budget runs out,
+    // checking every instruction takes time to run
+    fn run(program: &[u8], registers: [u8; 8]) {
         let mut memory = memory::Memory::new();
+        memory.load_at(INITIAL_PC, program);
         let mut cpu = Cpu::new(&mut memory);
+        let [a, b, c, d, e, h, l, f] = registers;
+        (cpu.a, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l) = (a, b, c, d, e, h, l);
+        cpu.set_f(f);
+
+        for _ in 0..MAX_INSTRUCTIONS {
+            match cpu.step() {
+                Ok(cycles) => assert!(cycles > 0, "no progress running {:02X?}", program),
+                Err(_) => return,
+            }
+        }
+    }
 
-        cpu.a = expected_value;
-        cpu.d = cpu.a;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAD as u8);
-        cpu.execute_instruction();
+    fn seed_corpus() -> Vec<Vec<u8>> {
+        let mut corpus = Vec::new();
+        for opcode in ILLEGAL_OPCODES {
+            corpus.push(vec![opcode]);
+        }
+        for opcode in 0..=0xFF {
+            corpus.push(vec![opcode]);
+            corpus.push(vec![Instruction::Prefix as u8, opcode]);
+        }
+        corpus
+    }
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+    #[test]
+    fn test_seed_corpus() {
+        for program in seed_corpus() {
+            run(&program, [0; 8]);
+            // pointers at the very top of the address space
+            run(&program, [0xFF; 8]);
+        }
     }
 
     #[test]
-    fn test_cp_ae() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
+    fn test_random_programs() {
+        let mut rng = Rng(0x1234_5678);
+        for _ in 0..RANDOM_PROGRAMS {
+            let program: Vec<u8> = (0..PROGRAM_LENGTH).map(|_| rng.next()).collect();
+            let registers = [0; 8].map(|_: u8| rng.next());
+            run(&program, registers);
+        }
+    }
+}
 
-        cpu.a = expected_value;
-        cpu.e = cpu.a;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAE as u8);
-        cpu.execute_instruction();
+// checks every implemented opcode takes as long as the published timings
+#[cfg(test)]
+mod test_cycle_table {
+    use super::*;
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+    // cycles for each opcode, with conditional instructions listed at the
+    // length they take when the condition fails. 0 marks opcodes that
+    // don't exist
+    #[rustfmt::skip]
+    const CYCLES: [u8; 256] = [
+        4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4,
+        4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4,
+        8, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+        8, 12, 8, 8, 12, 12, 12, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        8, 12, 12, 16, 12, 16, 8, 16, 8, 16, 12, 4, 12, 24, 8, 16,
+        8, 12, 12, 0, 12, 16, 8, 16, 8, 16, 12, 0, 12, 0, 8, 16,
+        12, 12, 8, 0, 0, 16, 8, 16, 16, 4, 16, 0, 0, 0, 8, 16,
+        12, 12, 8, 4, 0, 16, 8, 16, 12, 8, 16, 4, 0, 0, 8, 16,
+    ];
+
+    // JR, RET, JP and CALL on a condition, and how long each takes when
+    // the condition holds
+    #[rustfmt::skip]
+    const CONDITIONAL: [(u8, u8); 16] = [
+        (0x20, 12), (0x28, 12), (0x30, 12), (0x38, 12),
+        (0xC0, 20), (0xC8, 20), (0xD0, 20), (0xD8, 20),
+        (0xC2, 16), (0xCA, 16), (0xD2, 16), (0xDA, 16),
+        (0xC4, 24), (0xCC, 24), (0xD4, 24), (0xDC, 24),
+    ];
+
+    // opcodes the CPU doesn't implement yet, which are the only ones allowed
+    // to report themselves as unimplemented. Implementing one means taking
+    // it off this list
+    #[rustfmt::skip]
+    const UNIMPLEMENTED: [u8; 85] = [
+        0x05, 0x07, 0x09, 0x0A, 0x0D, 0x0F, 0x15, 0x17, 0x19, 0x1A, 0x1D, 0x1F,
+        0x25, 0x27, 0x29, 0x2A, 0x2D, 0x2F, 0x39, 0x3A, 0x3D, 0x46, 0x4E, 0x56,
+        0x5E, 0x66, 0x6E, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x77, 0x78, 0x79,
+        0x7A, 0x7B, 0x7C, 0x7D, 0x7E, 0x7F, 0x86, 0x8E, 0x96, 0x9E, 0xA6, 0xAE,
+        0xB6, 0xBE, 0xC1, 0xC5, 0xC6, 0xC7, 0xCE, 0xCF, 0xD1, 0xD5, 0xD6, 0xD7,
+        0xD9, 0xDE, 0xDF, 0xE1, 0xE2, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xEE,
+        0xEF, 0xF1, 0xF2, 0xF3, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA, 0xFB, 0xFE,
+        0xFF,
+    ];
+
+    // of the prefixed instructions only SLA, SRA and SRL are implemented
+    fn prefixed_implemented(opcode: u8) -> bool {
+        matches!(opcode, 0x20..=0x2F | 0x38..=0x3F)
     }
 
-    #[test]
-    fn test_cp_ah() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
-        let mut memory = memory::Memory::new();
-        let mut cpu = Cpu::new(&mut memory);
-
-        cpu.a = expected_value;
-        cpu.h = cpu.a;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAH as u8);
-        cpu.execute_instruction();
+    // every prefixed instruction takes 8 cycles, except those on (HL)
+    // which take 16, or 12 for BIT
+    fn prefixed_cycles(opcode: u8) -> u8 {
+        match (opcode & 0x07 == 0x06, opcode & 0xC0 == 0x40) {
+            (false, _) => 8,
+            (true, true) => 12,
+            (true, false) => 16,
+        }
+    }
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+    // conditions are picked by bits 3 and 4: NZ, Z, NC and C
+    fn condition_holds(opcode: u8, flags: CpuFlags) -> bool {
+        match (opcode >> 3) & 0x03 {
+            0 => !flags.contains(CpuFlags::ZERO_FLAG),
+            1 => flags.contains(CpuFlags::ZERO_FLAG),
+            2 => !flags.contains(CpuFlags::CARRY_FLAG),
+            _ => flags.contains(CpuFlags::CARRY_FLAG),
+        }
     }
 
-    #[test]
-    fn test_cp_al() {
-        let expected_value = 0xFF;
-        let expected_flags = CpuFlags::ZERO_FLAG | CpuFlags::SUBTRACTION_FLAG;
+    // runs the instruction with its operands all zero, pointers into
+    // working RAM and the given flags
+    fn measure(program: &[u8], flags: CpuFlags) -> Result<u8, EmuError> {
         let mut memory = memory::Memory::new();
+        memory.load_at(INITIAL_PC, program);
         let mut cpu = Cpu::new(&mut memory);
+        cpu.set_hl(memory::WORKING_RAM_START);
+        cpu.set_bc(memory::WORKING_RAM_START);
+        cpu.set_de(memory::WORKING_RAM_START);
+        cpu.sp = memory::ECHO_RAM_START;
+        cpu.flags = flags;
+        cpu.step()
+    }
 
-        cpu.a = expected_value;
-        cpu.l = cpu.a;
-        cpu.set_byte_in_memory(cpu.pc, Instruction::CpAL as u8);
-        cpu.execute_instruction();
+    #[test]
+    fn test_cycles_match_reference() {
+        let mut mismatches = Vec::new();
+        // the prefix is covered by the prefixed table below
+        for opcode in (0..=0xFF_u8).filter(|&opcode| opcode != Instruction::Prefix as u8) {
+            let unimplemented = UNIMPLEMENTED.contains(&opcode);
+            for flags in [CpuFlags::empty(), CpuFlags::all()] {
+                let cycles = match measure(&[opcode], flags) {
+                    Ok(_) if unimplemented => {
+                        mismatches.push(format!("{:02X} is implemented but still listed", opcode));
+                        continue;
+                    }
+                    Ok(cycles) => cycles,
+                    Err(EmuError::IllegalInstruction { .. }) if CYCLES[opcode as usize] == 0 => {
+                        continue;
+                    }
+                    Err(EmuError::UnimplementedInstruction { .. }) if unimplemented => continue,
+                    Err(error) => {
+                        mismatches.push(format!("{:02X}: {}", opcode, error));
+                        continue;
+                    }
+                };
+                let expected = match CONDITIONAL.iter().find(|(code, _)| *code == opcode) {
+                    Some(&(_, taken)) if condition_holds(opcode, flags) => taken,
+                    _ => CYCLES[opcode as usize],
+                };
+                if cycles != expected {
+                    mismatches.push(format!(
+                        "{:02X} ({}) with flags {:?}: expected {} cycles, took {}",
+                        opcode,
+                        Cpu::decode_opcode(opcode),
+                        flags,
+                        expected,
+                        cycles
+                    ));
+                }
+            }
+        }
+        assert!(mismatches.is_empty(), "\n{}", mismatches.join("\n"));
+    }
 
-        assert_eq!(cpu.a, expected_value);
-        assert_eq!(cpu.flags, expected_flags);
+    #[test]
+    fn test_prefixed_cycles_match_reference() {
+        let mut mismatches = Vec::new();
+        for opcode in 0..=0xFF_u8 {
+            let cycles = match measure(&[Instruction::Prefix as u8, opcode], CpuFlags::empty()) {
+                Ok(_) if !prefixed_implemented(opcode) => {
+                    mismatches.push(format!("CB {:02X} is implemented but not listed", opcode));
+                    continue;
+                }
+                Ok(cycles) => cycles,
+                Err(EmuError::UnimplementedPrefixedInstruction { .. })
+                    if !prefixed_implemented(opcode) =>
+                {
+                    continue;
+                }
+                Err(error) => {
+                    mismatches.push(format!("CB {:02X}: {}", opcode, error));
+                    continue;
+                }
+            };
+            if cycles != prefixed_cycles(opcode) {
+                mismatches.push(format!(
+                    "CB {:02X}: expected {} cycles, took {}",
+                    opcode,
+                    prefixed_cycles(opcode),
+                    cycles
+                ));
+            }
+        }
+        assert!(mismatches.is_empty(), "\n{}", mismatches.join("\n"));
     }
 }
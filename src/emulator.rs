@@ -0,0 +1,813 @@
+use crate::audio::AudioBuffer;
+use crate::clock::CLOCK_HZ;
+pub use crate::clock::CYCLES_PER_FRAME;
+use crate::cpu::Cpu;
+use crate::error::EmuError;
+use crate::memory::{Interrupt, Memory};
+use crate::model::Model;
+use crate::ppu::Ppu;
+use crate::state::{StateError, StateReader, StateWriter};
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::{fs, io, path::PathBuf};
+
+// Mooneye test ROMs stop on LD B,B once they're done, with B, C, D, E, H
+// and L holding the start of the Fibonacci sequence if the test passed
+const MOONEYE_BREAKPOINT: u8 = 0x40;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+// they all finish well within this many emulated seconds
+const MOONEYE_TIMEOUT_SECONDS: u64 = 120;
+
+// How a test ROM run by the emulator ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestResult {
+    Passed,
+    Failed,
+    // the ROM never reported a result
+    TimedOut,
+}
+
+// The first field where a lockstep run disagreed with the reference trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceMismatch {
+    // the name before the colon, like "A" or "PCMEM"
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+type FrameCallback<'a> = Box<dyn FnMut(&[u8]) + 'a>;
+type BreakCondition<'a> = Box<dyn Fn(&Cpu) -> bool + 'a>;
+
+// Ties the CPU together with the peripherals that run alongside it
+pub struct Emulator<'a> {
+    cpu: Cpu<'a>,
+    ppu: Ppu,
+    // cycles that have run since the start of the current frame
+    frame_cycles: u32,
+    // called with the finished framebuffer every time the PPU enters VBlank
+    frame_callback: Option<FrameCallback<'a>>,
+    // collects the APU's output once a front-end asks for audio
+    audio: Option<AudioBuffer>,
+    // how many frames of emulated time run_frame covers, so the front-end
+    // can play back faster or slower while pacing itself one frame at a time
+    speed_multiplier: f32,
+    // the most instructions a single run_frame may run before giving up, so
+    // a host embedding the emulator always gets control back
+    instruction_limit: Option<u64>,
+    // run_until_breakpoint stops once the pc reaches one of these addresses
+    // or one of the conditions holds
+    breakpoints: BTreeSet<u16>,
+    conditional_breakpoints: Vec<BreakCondition<'a>>,
+    // save state slots are stored next to this path
+    #[cfg(feature = "std")]
+    rom_path: Option<PathBuf>,
+}
+
+impl<'a> Emulator<'a> {
+    // emulates the model memory was built for, with the CPU registers its
+    // boot ROM hands over with. The I/O registers are left as memory has
+    // them, Cpu::set_post_boot_state sets those too
+    pub fn new(memory: &'a mut Memory) -> Self {
+        let model = memory.model();
+        Emulator::with_model(memory, model)
+    }
+
+    // emulates the given model, which replaces whatever memory was built for
+    pub fn with_model(memory: &'a mut Memory, model: Model) -> Self {
+        Emulator {
+            cpu: Cpu::with_model(memory, model),
+            ppu: Ppu::new(),
+            frame_cycles: 0,
+            frame_callback: None,
+            audio: None,
+            speed_multiplier: 1.0,
+            instruction_limit: None,
+            breakpoints: BTreeSet::new(),
+            conditional_breakpoints: Vec::new(),
+            #[cfg(feature = "std")]
+            rom_path: None,
+        }
+    }
+
+    pub fn model(self: &Self) -> Model {
+        self.cpu.model()
+    }
+
+    pub fn cpu(self: &Self) -> &Cpu<'a> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(self: &mut Self) -> &mut Cpu<'a> {
+        &mut self.cpu
+    }
+
+    pub fn ppu(self: &Self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(self: &mut Self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    pub fn set_frame_callback(self: &mut Self, callback: impl FnMut(&[u8]) + 'a) {
+        self.frame_callback = Some(Box::new(callback));
+    }
+
+    // starts collecting audio at the given rate, which is picked up with
+    // drain_audio
+    pub fn enable_audio(self: &mut Self, sample_rate: u32) -> Result<(), EmuError> {
+        if sample_rate == 0 || sample_rate > CLOCK_HZ as u32 {
+            return Err(EmuError::InvalidSampleRate(sample_rate));
+        }
+        self.audio = Some(AudioBuffer::new(CLOCK_HZ as u32, sample_rate));
+        Ok(())
+    }
+
+    // fills out with interleaved left and right samples, returning how many
+    // values were written
+    pub fn drain_audio(self: &mut Self, out: &mut [f32]) -> usize {
+        match &mut self.audio {
+            Some(audio) => audio.drain(out),
+            None => 0,
+        }
+    }
+
+    pub fn speed_multiplier(self: &Self) -> f32 {
+        self.speed_multiplier
+    }
+
+    // the multiplier is left alone if the new one is rejected
+    pub fn set_speed_multiplier(self: &mut Self, mult: f32) -> Result<(), EmuError> {
+        if !mult.is_finite() || mult <= 0.0 {
+            return Err(EmuError::InvalidSpeedMultiplier(mult));
+        }
+        self.speed_multiplier = mult;
+        Ok(())
+    }
+
+    // runs a single instruction, returning the number of cycles it took at
+    // the normal clock speed
+    pub fn step(self: &mut Self) -> Result<u8, EmuError> {
+        let cpu_cycles = self.cpu.step()?;
+        // the timer and OAM DMA are clocked with the CPU, but in double speed
+        // mode the CPU runs two cycles for every one the PPU and APU see
+        self.cpu.memory_mut().step_timer(cpu_cycles);
+        self.cpu.memory_mut().step_dma(cpu_cycles);
+        let cycles = match self.cpu.memory().double_speed() {
+            true => cpu_cycles / 2,
+            false => cpu_cycles,
+        };
+        self.frame_cycles += cycles as u32;
+
+        self.cpu.memory_mut().step_apu(cycles);
+        if let Some(audio) = &mut self.audio {
+            let (left, right) = self.cpu.memory_mut().apu_mut().sample();
+            for _ in 0..cycles {
+                audio.push(left, right);
+            }
+        }
+        if self.ppu.step(self.cpu.memory_mut(), cycles) {
+            self.cpu.memory_mut().joypad_mut().end_frame();
+            self.cpu.memory_mut().apply_game_shark_cheats();
+            if let Some(callback) = &mut self.frame_callback {
+                callback(self.ppu.framebuffer_indices());
+            }
+        }
+
+        Ok(cycles)
+    }
+
+    pub fn set_instruction_limit(self: &mut Self, limit: Option<u64>) {
+        self.instruction_limit = limit;
+    }
+
+    // runs until a full frame's worth of cycles have passed, scaled by the
+    // speed multiplier. Stops early with an error if the instruction limit
+    // is reached first, and the next call carries on with the same frame
+    pub fn run_frame(self: &mut Self) -> Result<(), EmuError> {
+        let target = (CYCLES_PER_FRAME as f32 * self.speed_multiplier) as u32;
+        let mut instructions = 0;
+        while self.frame_cycles < target {
+            if let Some(limit) = self.instruction_limit {
+                if instructions == limit {
+                    return Err(EmuError::InstructionLimit {
+                        pc: self.cpu.pc(),
+                        limit,
+                    });
+                }
+            }
+            self.step()?;
+            instructions += 1;
+        }
+        self.frame_cycles -= target;
+        Ok(())
+    }
+
+    // runs frames back to back, without waiting between them
+    pub fn run_frames(self: &mut Self, n: u32) -> Result<(), EmuError> {
+        for _ in 0..n {
+            self.run_frame()?;
+        }
+        Ok(())
+    }
+
+    // steps until the predicate holds, giving up after max_instructions,
+    // and returns whether the predicate was met
+    pub fn run_until<F: Fn(&Cpu) -> bool>(
+        self: &mut Self,
+        pred: F,
+        max_instructions: u64,
+    ) -> Result<bool, EmuError> {
+        for _ in 0..max_instructions {
+            if pred(&self.cpu) {
+                return Ok(true);
+            }
+            self.step()?;
+        }
+        Ok(pred(&self.cpu))
+    }
+
+    // runs a Mooneye acceptance test ROM until it reaches its LD B,B
+    // breakpoint, and reads the result from the registers
+    pub fn run_mooneye_test(self: &mut Self) -> Result<TestResult, EmuError> {
+        let mut cycles = 0;
+        while cycles < MOONEYE_TIMEOUT_SECONDS * CLOCK_HZ {
+            if self.cpu.memory().peek(self.cpu.pc()) == MOONEYE_BREAKPOINT {
+                let registers = [
+                    self.cpu.get_b(),
+                    self.cpu.get_c(),
+                    self.cpu.get_d(),
+                    self.cpu.get_e(),
+                    self.cpu.get_h(),
+                    self.cpu.get_l(),
+                ];
+                return Ok(match registers {
+                    MOONEYE_PASS => TestResult::Passed,
+                    _ => TestResult::Failed,
+                });
+            }
+            cycles += self.step()? as u64;
+        }
+        Ok(TestResult::TimedOut)
+    }
+
+    // runs exactly one instruction for diffing against a reference
+    // emulator, returning the gameboy-doctor line for the state it started
+    // from. When given the reference's line for the following instruction,
+    // the state the instruction left behind is checked against it
+    pub fn step_lockstep(
+        self: &mut Self,
+        expected: Option<&str>,
+    ) -> Result<(String, Option<TraceMismatch>), EmuError> {
+        let line = self.cpu.doctor_line();
+        self.step()?;
+        let mismatch =
+            expected.and_then(|expected| compare_trace_lines(expected, &self.cpu.doctor_line()));
+        Ok((line, mismatch))
+    }
+
+    pub fn request_interrupt(self: &mut Self, interrupt: Interrupt) {
+        self.cpu.request_interrupt(interrupt);
+    }
+
+    pub fn add_breakpoint(self: &mut Self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(self: &mut Self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    pub fn add_conditional_breakpoint(self: &mut Self, cond: impl Fn(&Cpu) -> bool + 'a) {
+        self.conditional_breakpoints.push(Box::new(cond));
+    }
+
+    pub fn clear_breakpoints(self: &mut Self) {
+        self.breakpoints.clear();
+        self.conditional_breakpoints.clear();
+    }
+
+    // steps at least once, then keeps going until a breakpoint is hit at an
+    // instruction boundary. Gives up after max_instructions and returns
+    // whether a breakpoint was hit
+    pub fn run_until_breakpoint(self: &mut Self, max_instructions: u64) -> Result<bool, EmuError> {
+        for _ in 0..max_instructions {
+            self.step()?;
+            if self.at_breakpoint() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn at_breakpoint(self: &Self) -> bool {
+        self.breakpoints.contains(&self.cpu.pc())
+            || self
+                .conditional_breakpoints
+                .iter()
+                .any(|cond| cond(&self.cpu))
+    }
+
+    pub fn save_state(self: &Self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.cpu.save_state(&mut writer);
+        self.ppu.save_state(&mut writer);
+        writer.write_u32(self.frame_cycles);
+        writer.finish()
+    }
+
+    // the whole state is read and checked before any of it is applied, so a
+    // state that fails to load leaves the emulator as it was
+    pub fn load_state(self: &mut Self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data)?;
+        let cpu = self.cpu.read_state(&mut reader)?;
+        let mut ppu = self.ppu.clone();
+        ppu.load_state(&mut reader)?;
+        let frame_cycles = reader.read_u32()?;
+        reader.finish()?;
+
+        self.cpu.apply_state(cpu);
+        self.ppu = ppu;
+        self.frame_cycles = frame_cycles;
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn set_rom_path(self: &mut Self, path: impl Into<PathBuf>) {
+        self.rom_path = Some(path.into());
+    }
+
+    // slot n of game.gb is stored in game.gb.state<n>
+    #[cfg(feature = "std")]
+    fn slot_path(self: &Self, slot: u8) -> io::Result<PathBuf> {
+        let rom_path = self.rom_path.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "no ROM path set to store save state slots next to",
+            )
+        })?;
+
+        let mut path = rom_path.clone().into_os_string();
+        path.push(format!(".state{}", slot));
+        Ok(path.into())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save_state_to_slot(self: &Self, slot: u8) -> io::Result<()> {
+        fs::write(self.slot_path(slot)?, self.save_state())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load_state_from_slot(self: &mut Self, slot: u8) -> io::Result<()> {
+        let data = fs::read(self.slot_path(slot)?)?;
+        self.load_state(&data)?;
+        Ok(())
+    }
+
+    pub fn take_serial_output(self: &mut Self) -> Vec<u8> {
+        self.cpu.memory_mut().take_serial_output()
+    }
+}
+
+// compares two gameboy-doctor lines field by field, a field missing from
+// either side counting as a mismatch
+fn compare_trace_lines(expected: &str, actual: &str) -> Option<TraceMismatch> {
+    let mut expected_fields = expected.split_whitespace();
+    let mut actual_fields = actual.split_whitespace();
+    loop {
+        let (expected_field, actual_field) = match (expected_fields.next(), actual_fields.next()) {
+            (None, None) => return None,
+            (expected_field, actual_field) if expected_field == actual_field => continue,
+            fields => fields,
+        };
+
+        let name = expected_field.or(actual_field).unwrap_or_default();
+        let name = name.split(':').next().unwrap_or_default();
+        let value = |field: Option<&str>| {
+            field
+                .and_then(|field| field.split_once(':'))
+                .map_or("", |(_, value)| value)
+                .to_string()
+        };
+        return Some(TraceMismatch {
+            field: name.to_string(),
+            expected: value(expected_field),
+            actual: value(actual_field),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory;
+    use crate::timer;
+
+    #[test]
+    fn test_run_frame_runs_a_frame_of_cycles() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+
+        emulator.run_frame().unwrap();
+
+        // every NOP takes 4 cycles and the frame length is a multiple of 4
+        assert_eq!(emulator.frame_cycles, 0);
+        assert_eq!(emulator.cpu().pc(), 0x100 + (CYCLES_PER_FRAME / 4) as u16);
+    }
+
+    #[test]
+    fn test_instruction_limit_stops_run_frame() {
+        // JR -2 forever, which takes 12 cycles every time round
+        let mut memory = Memory::new();
+        memory.load_at(0x100, &[0x18, 0xFE]);
+        let mut emulator = Emulator::new(&mut memory);
+
+        emulator.set_instruction_limit(Some(1000));
+        assert!(matches!(
+            emulator.run_frame(),
+            Err(EmuError::InstructionLimit {
+                pc: 0x100,
+                limit: 1000
+            })
+        ));
+        assert_eq!(emulator.frame_cycles, 12 * 1000);
+
+        emulator.set_instruction_limit(Some(CYCLES_PER_FRAME as u64 / 12 + 1));
+        emulator.run_frame().unwrap();
+    }
+
+    #[test]
+    fn test_run_frames() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+
+        emulator.run_frames(3).unwrap();
+
+        assert_eq!(
+            emulator.cpu().pc(),
+            0x100 + (3 * CYCLES_PER_FRAME / 4) as u16
+        );
+    }
+
+    #[test]
+    fn test_run_until_pc_reaches_address() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+
+        assert!(emulator.run_until(|cpu| cpu.pc() == 0x110, 100).unwrap());
+        assert_eq!(emulator.cpu().pc(), 0x110);
+
+        assert!(!emulator.run_until(|cpu| cpu.pc() == 0x100, 10).unwrap());
+        assert_eq!(emulator.cpu().pc(), 0x11A);
+    }
+
+    #[test]
+    fn test_double_speed_keeps_timer_on_cpu_clock() {
+        let mut memory = Memory::with_model(Model::Cgb);
+        // arm the speed switch through KEY1 and take it
+        memory.set_byte(0xFF4D, 0x01);
+        assert!(memory.stop());
+        let mut emulator = Emulator::with_model(&mut memory, Model::Cgb);
+        emulator
+            .cpu_mut()
+            .memory_mut()
+            .timer_mut()
+            .set_div_counter(0);
+
+        // a NOP is 4 CPU cycles but only 2 for the PPU
+        assert_eq!(emulator.step().unwrap(), 2);
+        assert_eq!(emulator.cpu().memory().timer().div_counter(), 4);
+    }
+
+    #[test]
+    fn test_speed_multiplier_scales_frames() {
+        let mut vblanks = [0; 2];
+        for (count, mult) in vblanks.iter_mut().zip([1.0, 2.0]) {
+            // spin on JR -2 so the CPU never runs off the end of the ROM
+            let mut rom = vec![0; 0x8000];
+            rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+            let mut memory = Memory::from_rom(rom).unwrap();
+            memory.set_lcdc(0x80);
+
+            let mut emulator = Emulator::new(&mut memory);
+            emulator.set_speed_multiplier(mult).unwrap();
+            emulator.set_frame_callback(|_| *count += 1);
+            emulator.run_frames(10).unwrap();
+        }
+
+        assert_eq!(vblanks, [10, 20]);
+    }
+
+    #[test]
+    fn test_invalid_speed_multiplier_is_rejected() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.set_speed_multiplier(2.0).unwrap();
+
+        for mult in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                emulator.set_speed_multiplier(mult),
+                Err(EmuError::InvalidSpeedMultiplier(_))
+            ));
+        }
+        assert_eq!(emulator.speed_multiplier(), 2.0);
+    }
+
+    #[test]
+    fn test_audio_is_collected_at_sample_rate() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut memory = Memory::from_rom(rom).unwrap();
+        let mut emulator = Emulator::new(&mut memory);
+        let mut out = vec![0.0; 4096];
+        assert_eq!(emulator.drain_audio(&mut out), 0);
+
+        emulator.enable_audio(48000).unwrap();
+        emulator.run_frame().unwrap();
+        // a frame lasts just under 1/60th of a second, or about 803 samples
+        // for each side
+        let written = emulator.drain_audio(&mut out);
+        assert!((1604..=1610).contains(&written), "{} values", written);
+    }
+
+    #[test]
+    fn test_invalid_sample_rate_is_rejected() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+
+        for rate in [0, CLOCK_HZ as u32 + 1] {
+            assert!(matches!(
+                emulator.enable_audio(rate),
+                Err(EmuError::InvalidSampleRate(_))
+            ));
+        }
+        assert_eq!(emulator.drain_audio(&mut [0.0; 4]), 0);
+    }
+
+    #[test]
+    fn test_timer_interrupt_wakes_halted_cpu() {
+        let mut rom = vec![0; 0x8000];
+        // HALT, then spin on JR -2
+        rom[0x100..0x103].copy_from_slice(&[0x76, 0x18, 0xFE]);
+        let mut memory = Memory::from_rom(rom).unwrap();
+        memory.set_ie(memory::TIMER_INTERRUPT);
+        memory.timer_mut().set_div_counter(0);
+        memory.set_tac(0b101);
+        memory.set_byte(timer::TIMA, 0xFF);
+
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.step().unwrap();
+        assert!(emulator.cpu().is_halted());
+
+        // TIMA overflows after 16 cycles and is reloaded 4 cycles later,
+        // and the timer keeps running the whole time the CPU is halted
+        assert!(emulator.run_until(|cpu| !cpu.is_halted(), 10).unwrap());
+        assert_ne!(
+            emulator.cpu().memory().interrupt_flag() & memory::TIMER_INTERRUPT,
+            0
+        );
+        assert_eq!(emulator.cpu().pc(), 0x101);
+    }
+
+    // a ROM that loads B, C, D, E, H and L and then stops on LD B,B
+    fn mooneye_rom(registers: [u8; 6]) -> Memory {
+        let mut rom = vec![0; 0x8000];
+        let loads = [0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E];
+        for (index, (&load, &value)) in loads.iter().zip(registers.iter()).enumerate() {
+            rom[0x100 + 2 * index] = load;
+            rom[0x101 + 2 * index] = value;
+        }
+        rom[0x10C] = MOONEYE_BREAKPOINT;
+        Memory::from_rom(rom).unwrap()
+    }
+
+    #[test]
+    fn test_mooneye_pass() {
+        let mut memory = mooneye_rom(MOONEYE_PASS);
+        let mut emulator = Emulator::new(&mut memory);
+
+        assert_eq!(emulator.run_mooneye_test().unwrap(), TestResult::Passed);
+        assert_eq!(emulator.cpu().pc(), 0x10C);
+    }
+
+    #[test]
+    fn test_mooneye_fail() {
+        let mut memory = mooneye_rom([0x42; 6]);
+        let mut emulator = Emulator::new(&mut memory);
+
+        assert_eq!(emulator.run_mooneye_test().unwrap(), TestResult::Failed);
+    }
+
+    #[test]
+    fn test_construction_picks_model() {
+        let mut initial_a = Vec::new();
+        for model in [Model::Dmg, Model::Cgb, Model::Sgb] {
+            let mut memory = Memory::new();
+            let emulator = Emulator::with_model(&mut memory, model);
+
+            assert_eq!(emulator.model(), model);
+            initial_a.push(emulator.cpu().get_a());
+        }
+
+        assert_eq!(initial_a, [0x01, 0x11, 0x01]);
+    }
+
+    #[test]
+    fn test_new_keeps_memory_model() {
+        let mut memory = Memory::with_model(Model::Cgb);
+        let emulator = Emulator::new(&mut memory);
+        assert_eq!(emulator.model(), Model::Cgb);
+        assert_eq!(emulator.cpu().get_a(), 0x11);
+
+        let mut memory = Memory::new();
+        let emulator = Emulator::new(&mut memory);
+        assert_eq!(emulator.model(), Model::Dmg);
+        assert_eq!(emulator.cpu().get_af(), 0x01B0);
+        assert_eq!(emulator.cpu().get_hl(), 0x014D);
+    }
+
+    #[test]
+    fn test_lockstep_matches_reference_trace() {
+        let mut memory = Memory::new();
+        // LD A,$42 then INC B
+        memory.load_at(0x100, &[0x3E, 0x42, 0x04]);
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.cpu_mut().set_post_boot_state();
+
+        let reference = [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3E,42,04,00",
+            "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:04,00,00,00",
+            "A:42 F:10 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:00,00,00,00",
+        ];
+        for index in 0..2 {
+            let (line, mismatch) = emulator.step_lockstep(Some(reference[index + 1])).unwrap();
+            assert_eq!(line, reference[index]);
+            assert_eq!(mismatch, None);
+        }
+    }
+
+    #[test]
+    fn test_lockstep_reports_mismatch() {
+        let mut memory = Memory::new();
+        memory.load_at(0x100, &[0x3E, 0x42, 0x04]);
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.cpu_mut().set_post_boot_state();
+
+        let wrong = "A:42 F:B0 B:00 C:14 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:04,00,00,00";
+        let (_, mismatch) = emulator.step_lockstep(Some(wrong)).unwrap();
+
+        assert_eq!(
+            mismatch,
+            Some(TraceMismatch {
+                field: "C".to_string(),
+                expected: "14".to_string(),
+                actual: "13".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_requested_interrupt_vectors_cpu() {
+        let mut memory = Memory::new();
+        memory.set_ie(memory::VBLANK_INTERRUPT);
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.cpu_mut().set_ime(true);
+
+        emulator.request_interrupt(Interrupt::VBlank);
+        emulator.step().unwrap();
+
+        assert_eq!(emulator.cpu().pc(), Interrupt::VBlank.vector());
+        assert_eq!(emulator.cpu().memory().interrupt_flag(), 0);
+    }
+
+    #[test]
+    fn test_address_breakpoint() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.add_breakpoint(0x104);
+
+        assert!(emulator.run_until_breakpoint(100).unwrap());
+        assert_eq!(emulator.cpu().pc(), 0x104);
+
+        emulator.remove_breakpoint(0x104);
+        assert!(!emulator.run_until_breakpoint(10).unwrap());
+        assert_eq!(emulator.cpu().pc(), 0x10E);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        // NOP; NOP; LD A,0x42; NOP
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x105].copy_from_slice(&[0x00, 0x00, 0x3E, 0x42, 0x00]);
+        let mut memory = Memory::from_rom(rom).unwrap();
+
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.add_conditional_breakpoint(|cpu| cpu.get_a() == 0x42);
+
+        assert!(emulator.run_until_breakpoint(100).unwrap());
+        // stops right after the load, before the following NOP runs
+        assert_eq!(emulator.cpu().pc(), 0x104);
+        assert_eq!(emulator.cpu().get_a(), 0x42);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_save_state_slot_round_trip() {
+        let directory = std::env::temp_dir().join(format!("gameboy-slots-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        // LD A,0x12; LD A,0x34; JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x3E, 0x12, 0x3E, 0x34, 0x18, 0xFE]);
+        let mut memory = Memory::from_rom(rom).unwrap();
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.set_rom_path(directory.join("game.gb"));
+
+        emulator.step().unwrap();
+        emulator.cpu_mut().memory_mut().set_byte(0xC000, 0x56);
+        emulator.save_state_to_slot(3).unwrap();
+        assert!(directory.join("game.gb.state3").exists());
+
+        emulator.step().unwrap();
+        emulator.cpu_mut().memory_mut().set_byte(0xC000, 0x78);
+        assert_eq!(emulator.cpu().get_a(), 0x34);
+
+        emulator.load_state_from_slot(3).unwrap();
+        assert_eq!(emulator.cpu().get_a(), 0x12);
+        assert_eq!(emulator.cpu().pc(), 0x102);
+        assert_eq!(emulator.cpu().memory().get_data(0xC000), 0x56);
+
+        assert!(emulator.load_state_from_slot(4).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_load_state_rejects_garbage() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+
+        assert_eq!(
+            emulator.load_state(b"not a state"),
+            Err(StateError::BadHeader)
+        );
+        let mut state = emulator.save_state();
+        state.pop();
+        assert_eq!(emulator.load_state(&state), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn test_failed_load_leaves_emulator_untouched() {
+        // a state from a session whose boot ROM has already been unmapped
+        let mut other_memory = Memory::new();
+        let mut truncated = Emulator::new(&mut other_memory).save_state();
+        truncated.pop();
+
+        let mut memory = Memory::new();
+        memory.load_boot_rom(vec![0x00; 0x100]);
+        memory.set_byte(0xC000, 0x56);
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.step().unwrap();
+        let before = emulator.save_state();
+
+        // only the last field is missing, so everything before it parses
+        assert_eq!(emulator.load_state(&truncated), Err(StateError::Truncated));
+        assert!(emulator.cpu().memory().boot_rom_mapped());
+        assert_eq!(emulator.cpu().pc(), 0x101);
+        assert_eq!(emulator.cpu().memory().peek(0xC000), 0x56);
+        assert_eq!(emulator.save_state(), before);
+    }
+
+    #[test]
+    fn test_load_state_rejects_trailing_data() {
+        let mut memory = Memory::new();
+        let mut emulator = Emulator::new(&mut memory);
+        let mut state = emulator.save_state();
+        state.push(0);
+
+        assert_eq!(emulator.load_state(&state), Err(StateError::TrailingData));
+        state.pop();
+        assert_eq!(emulator.load_state(&state), Ok(()));
+    }
+
+    #[test]
+    fn test_frame_callback_fires_once_per_frame() {
+        let mut frames = Vec::new();
+        let mut memory = Memory::new();
+        // turn the LCD on
+        memory.set_lcdc(0x80);
+
+        let mut emulator = Emulator::new(&mut memory);
+        emulator.set_frame_callback(|framebuffer| frames.push(framebuffer.len()));
+        emulator.run_frame().unwrap();
+        drop(emulator);
+
+        assert_eq!(
+            frames,
+            vec![crate::ppu::SCREEN_WIDTH * crate::ppu::SCREEN_HEIGHT]
+        );
+    }
+}
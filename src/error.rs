@@ -0,0 +1,59 @@
+use crate::cartridge::RomLoadError;
+use core::fmt;
+
+// Everything that can stop emulation, so a front-end can report a problem
+// instead of the emulator aborting
+#[derive(Debug)]
+pub enum EmuError {
+    Rom(RomLoadError),
+    // one of the opcodes that locks up real hardware
+    IllegalInstruction { pc: u16, opcode: u8 },
+    // a valid opcode the CPU doesn't support yet
+    UnimplementedInstruction { pc: u16, opcode: u8 },
+    UnimplementedPrefixedInstruction { pc: u16, opcode: u8 },
+    // a single call ran more instructions than the front-end allowed
+    InstructionLimit { pc: u16, limit: u64 },
+    // speed multipliers have to be finite and above zero
+    InvalidSpeedMultiplier(f32),
+    // audio can only be resampled down to a rate between 1Hz and the clock
+    InvalidSampleRate(u32),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::Rom(error) => write!(f, "{}", error),
+            EmuError::IllegalInstruction { pc, opcode } => {
+                write!(f, "illegal opcode {:#04X} at {:#06X}", opcode, pc)
+            }
+            EmuError::UnimplementedInstruction { pc, opcode } => {
+                write!(f, "unimplemented opcode {:#04X} at {:#06X}", opcode, pc)
+            }
+            EmuError::UnimplementedPrefixedInstruction { pc, opcode } => {
+                write!(
+                    f,
+                    "unimplemented opcode 0xCB {:#04X} at {:#06X}",
+                    opcode, pc
+                )
+            }
+            EmuError::InstructionLimit { pc, limit } => {
+                write!(f, "stopped at {:#06X} after {} instructions", pc, limit)
+            }
+            EmuError::InvalidSpeedMultiplier(mult) => {
+                write!(f, "speed multiplier must be positive, got {}", mult)
+            }
+            EmuError::InvalidSampleRate(rate) => {
+                write!(f, "can't play audio at {}Hz", rate)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EmuError {}
+
+impl From<RomLoadError> for EmuError {
+    fn from(error: RomLoadError) -> Self {
+        EmuError::Rom(error)
+    }
+}
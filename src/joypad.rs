@@ -0,0 +1,210 @@
+use crate::state::{StateError, StateReader, StateWriter};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+// Bits of the P1 register that select which group of buttons is read
+const SELECT_DIRECTIONS: u8 = 0b0001_0000;
+const SELECT_ACTIONS: u8 = 0b0010_0000;
+// the top two bits of P1 are unused and always read as 1
+const UNUSED_BITS: u8 = 0b1100_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // directions live in the low nibble and actions in the high nibble, so
+    // each nibble lines up with the bits of P1 once its group is selected
+    fn mask(self: Self) -> u8 {
+        match self {
+            Button::Right => 0b0000_0001,
+            Button::Left => 0b0000_0010,
+            Button::Up => 0b0000_0100,
+            Button::Down => 0b0000_1000,
+            Button::A => 0b0001_0000,
+            Button::B => 0b0010_0000,
+            Button::Select => 0b0100_0000,
+            Button::Start => 0b1000_0000,
+        }
+    }
+}
+
+// A single button change, tagged with the frame it happened on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+#[derive(Clone)]
+pub struct Joypad {
+    // a set bit means the button is held down
+    pressed: u8,
+    // the group select bits last written to P1
+    select: u8,
+    // the number of VBlanks seen so far
+    frame: u64,
+    // Some while input is being recorded
+    recording: Option<Vec<InputEvent>>,
+    // events waiting to be applied once their frame is reached
+    playback: VecDeque<InputEvent>,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            pressed: 0,
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            frame: 0,
+            recording: None,
+            playback: VecDeque::new(),
+        }
+    }
+
+    // recording and playback belong to the session rather than the game, so
+    // they're left out of save states
+    pub fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_u8(self.pressed);
+        writer.write_u8(self.select);
+        writer.write_u64(self.frame);
+    }
+
+    pub fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.pressed = reader.read_u8()?;
+        self.select = reader.read_u8()?;
+        self.frame = reader.read_u64()?;
+        Ok(())
+    }
+
+    pub fn set_button(self: &mut Self, button: Button, pressed: bool) {
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+
+        if let Some(recording) = &mut self.recording {
+            recording.push(InputEvent {
+                frame: self.frame,
+                button,
+                pressed,
+            });
+        }
+    }
+
+    pub fn is_pressed(self: &Self, button: Button) -> bool {
+        self.pressed & button.mask() != 0
+    }
+
+    // the P1 register reports pressed buttons as 0 bits
+    pub fn read(self: &Self) -> u8 {
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            pressed |= self.pressed >> 4;
+        }
+
+        UNUSED_BITS | self.select | (!pressed & 0x0F)
+    }
+
+    // only the group select bits of P1 are writable
+    pub fn write(self: &mut Self, value: u8) {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    }
+
+    pub fn frame(self: &Self) -> u64 {
+        self.frame
+    }
+
+    pub fn start_recording(self: &mut Self) {
+        self.recording = Some(Vec::new());
+    }
+
+    pub fn export_recording(self: &Self) -> Vec<InputEvent> {
+        self.recording.clone().unwrap_or_default()
+    }
+
+    // queues up recorded events, applying any that are already due
+    pub fn play_recording(self: &mut Self, events: Vec<InputEvent>) {
+        self.playback.extend(events);
+        self.apply_playback();
+    }
+
+    // called by the PPU at every VBlank
+    pub fn end_frame(self: &mut Self) {
+        self.frame += 1;
+        self.apply_playback();
+    }
+
+    fn apply_playback(self: &mut Self) {
+        while let Some(event) = self.playback.front() {
+            if event.frame > self.frame {
+                break;
+            }
+            let event = self.playback.pop_front().unwrap();
+            self.set_button(event.button, event.pressed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_selected_group() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Start, true);
+        joypad.set_button(Button::Left, true);
+
+        joypad.write(!SELECT_ACTIONS);
+        assert_eq!(joypad.read() & 0x0F, 0b0111);
+
+        joypad.write(!SELECT_DIRECTIONS);
+        assert_eq!(joypad.read() & 0x0F, 0b1101);
+    }
+
+    #[test]
+    fn test_replay_recording() {
+        let mut joypad = Joypad::new();
+        joypad.start_recording();
+        joypad.set_button(Button::A, true);
+        joypad.end_frame();
+        joypad.end_frame();
+        joypad.set_button(Button::A, false);
+        joypad.set_button(Button::Down, true);
+
+        let recording = joypad.export_recording();
+        assert_eq!(recording.len(), 3);
+
+        let mut replay = Joypad::new();
+        replay.play_recording(recording);
+        assert!(replay.is_pressed(Button::A));
+        assert!(!replay.is_pressed(Button::Down));
+
+        replay.end_frame();
+        assert!(replay.is_pressed(Button::A));
+        assert!(!replay.is_pressed(Button::Down));
+
+        replay.end_frame();
+        assert!(!replay.is_pressed(Button::A));
+        assert!(replay.is_pressed(Button::Down));
+    }
+}
@@ -0,0 +1,25 @@
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+#![allow(clippy::needless_arbitrary_self_type)]
+
+pub mod apu;
+pub mod audio;
+pub mod bitset;
+pub mod cartridge;
+pub mod cheat;
+pub mod clock;
+pub mod cpu;
+pub mod emulator;
+pub mod error;
+pub mod joypad;
+pub mod memory;
+pub mod model;
+pub mod ppu;
+pub mod sprite;
+pub mod state;
+pub mod symbols;
+pub mod timer;
+
+extern crate alloc;
+extern crate num;
+#[macro_use]
+extern crate num_derive;
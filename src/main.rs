@@ -1,11 +1,71 @@
-mod cpu;
-mod memory;
+use gameboy::emulator::Emulator;
+use gameboy::memory::Memory;
+use gameboy::model::Model;
+use std::env;
+use std::io::{self, Write};
+use std::process;
 
-extern crate num;
-#[macro_use]
-extern crate num_derive;
+const USAGE: &str = "usage: gameboy <rom> [--max-frames <count>]";
 
 fn main() {
-    let mut memory = memory::Memory::new();
-    let cpu = cpu::Cpu::new(&mut memory);
+    let mut rom_path = None;
+    let mut max_frames: Option<u64> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-frames" => {
+                let count = args.next().and_then(|count| count.parse().ok());
+                if count.is_none() {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+                max_frames = count;
+            }
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    let rom_path = rom_path.unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    });
+
+    let mut memory = Memory::from_path(&rom_path).unwrap_or_else(|error| {
+        eprintln!("failed to load {}: {}", rom_path, error);
+        process::exit(1);
+    });
+    let info = memory.cartridge_info();
+    eprintln!(
+        "{} ({}, {}KB ROM, {}KB RAM{})",
+        info.title,
+        info.mapper,
+        info.rom_size_kb,
+        info.ram_size_kb,
+        if info.battery { ", battery" } else { "" }
+    );
+    if !memory.header_checksum_valid() {
+        eprintln!("warning: {} has a bad header checksum", rom_path);
+    }
+    let mut emulator = Emulator::with_model(&mut memory, Model::Dmg);
+    // there's no boot ROM, so start from where it would have left off
+    emulator.cpu_mut().set_post_boot_state();
+    emulator.set_rom_path(&rom_path);
+
+    let mut stdout = io::stdout();
+    let mut frames = 0;
+    while max_frames.is_none_or(|max_frames| frames < max_frames) {
+        if let Err(error) = emulator.run_frame() {
+            eprintln!("emulation stopped: {}", error);
+            process::exit(1);
+        }
+        frames += 1;
+
+        // test ROMs print their results over the serial port
+        let output = emulator.take_serial_output();
+        if !output.is_empty() {
+            stdout.write_all(&output).unwrap();
+            stdout.flush().unwrap();
+        }
+    }
 }
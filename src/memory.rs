@@ -1,85 +1,2252 @@
+use crate::apu::{self, Apu};
+use crate::bitset::BitSet;
+use crate::cartridge::{self, CartridgeInfo, Mbc, RomLoadError};
+use crate::cheat::{Cheat, CheatParseError};
+use crate::joypad::Joypad;
+use crate::model::Model;
+use crate::ppu::Mode;
+use crate::sprite::{Sprite, BYTES_PER_SPRITE, SPRITE_COUNT};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::timer::{self, Timer};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ops::RangeInclusive;
+#[cfg(feature = "std")]
+use std::{fs, path::Path};
+
 // Offsets for various pieces of gameboy memory
-const ROM_BANK_0_START: u16 = 0x0000;
-const ROM_BANK_N_START: u16 = 0x4000;
-const TILE_RAM_START: u16 = 0x8000;
-const BACKGROUND_MAP_START: u16 = 0x9800;
-const CARTRIDGE_RAM_START: u16 = 0xA000;
-const WORKING_RAM_START: u16 = 0xC000;
-const ECHO_RAM_START: u16 = 0xE000;
-const OAM_START: u16 = 0xFE00;
-const UNUSED_START: u16 = 0xFEA0;
-const IO_REGISTERS: u16 = 0xFF00;
-const HIGH_RAM_START: u16 = 0xFF80;
-const INTERRUPT_ENABLE_REGISTER: u16 = 0xFFFF;
+pub const ROM_BANK_0_START: u16 = 0x0000;
+pub const ROM_BANK_N_START: u16 = 0x4000;
+pub const TILE_RAM_START: u16 = 0x8000;
+pub const BACKGROUND_MAP_START: u16 = 0x9800;
+pub const CARTRIDGE_RAM_START: u16 = 0xA000;
+pub const WORKING_RAM_START: u16 = 0xC000;
+pub const ECHO_RAM_START: u16 = 0xE000;
+pub const OAM_START: u16 = 0xFE00;
+pub const UNUSED_START: u16 = 0xFEA0;
+pub const IO_REGISTERS: u16 = 0xFF00;
+pub const HIGH_RAM_START: u16 = 0xFF80;
+pub const INTERRUPT_ENABLE_REGISTER: u16 = 0xFFFF;
+const ADDRESS_SPACE_SIZE: usize = 0x10000;
+// CGB has a second VRAM bank holding background map attributes and more tiles
+pub const VRAM_BANK_COUNT: usize = 2;
+const VRAM_SIZE: usize = (CARTRIDGE_RAM_START - TILE_RAM_START) as usize;
+// CGB has eight working RAM banks, where DMG only has the first two. Bank 0
+// is always at 0xC000 and SVBK picks the one at 0xD000
+pub const WRAM_BANK_COUNT: usize = 8;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_SWITCHABLE_START: u16 = WORKING_RAM_START + WRAM_BANK_SIZE as u16;
+const WRAM_BANK_SELECT: u8 = 0b0000_0111;
+
+// The region of the memory map an address falls in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemRegion {
+    RomBank0,
+    RomBankN,
+    TileRam,
+    BackgroundMap,
+    CartridgeRam,
+    WorkingRam,
+    EchoRam,
+    ObjectAttributeMemory,
+    Unused,
+    IoRegisters,
+    HighRam,
+    InterruptEnable,
+}
+
+pub fn classify(address: u16) -> MemRegion {
+    match address {
+        ROM_BANK_0_START..ROM_BANK_N_START => MemRegion::RomBank0,
+        ROM_BANK_N_START..TILE_RAM_START => MemRegion::RomBankN,
+        TILE_RAM_START..BACKGROUND_MAP_START => MemRegion::TileRam,
+        BACKGROUND_MAP_START..CARTRIDGE_RAM_START => MemRegion::BackgroundMap,
+        CARTRIDGE_RAM_START..WORKING_RAM_START => MemRegion::CartridgeRam,
+        WORKING_RAM_START..ECHO_RAM_START => MemRegion::WorkingRam,
+        ECHO_RAM_START..OAM_START => MemRegion::EchoRam,
+        OAM_START..UNUSED_START => MemRegion::ObjectAttributeMemory,
+        UNUSED_START..IO_REGISTERS => MemRegion::Unused,
+        IO_REGISTERS..HIGH_RAM_START => MemRegion::IoRegisters,
+        HIGH_RAM_START..INTERRUPT_ENABLE_REGISTER => MemRegion::HighRam,
+        INTERRUPT_ENABLE_REGISTER => MemRegion::InterruptEnable,
+    }
+}
+
+// Addresses of the I/O registers handled by peripherals
+const JOYPAD_REGISTER: u16 = 0xFF00;
+const SERIAL_DATA: u16 = 0xFF01;
+const SERIAL_CONTROL: u16 = 0xFF02;
+const INTERRUPT_FLAG: u16 = 0xFF0F;
+const DMA: u16 = 0xFF46;
+const KEY1: u16 = 0xFF4D;
+const BOOT_ROM_DISABLE: u16 = 0xFF50;
+const LCDC: u16 = 0xFF40;
+const LCD_STATUS: u16 = 0xFF41;
+const SCY: u16 = 0xFF42;
+const SCX: u16 = 0xFF43;
+const LY: u16 = 0xFF44;
+const LYC: u16 = 0xFF45;
+const BGP: u16 = 0xFF47;
+const OBP0: u16 = 0xFF48;
+const OBP1: u16 = 0xFF49;
+const WY: u16 = 0xFF4A;
+const WX: u16 = 0xFF4B;
+const VBK: u16 = 0xFF4F;
+const SVBK: u16 = 0xFF70;
+const HDMA1: u16 = 0xFF51;
+const HDMA2: u16 = 0xFF52;
+const HDMA3: u16 = 0xFF53;
+const HDMA4: u16 = 0xFF54;
+const HDMA5: u16 = 0xFF55;
+const BCPS: u16 = 0xFF68;
+const BCPD: u16 = 0xFF69;
+const OCPS: u16 = 0xFF6A;
+const OCPD: u16 = 0xFF6B;
+
+// Bits of the CGB speed switch register
+const KEY1_CURRENT_SPEED: u8 = 0b1000_0000;
+const KEY1_PREPARE_SWITCH: u8 = 0b0000_0001;
+
+// CGB palette RAM holds 8 palettes of 4 colors, each color two bytes of
+// little endian 15 bit RGB
+pub const PALETTE_RAM_SIZE: usize = 64;
+// Bits of the palette index registers
+const PALETTE_INDEX: u8 = 0b0011_1111;
+const PALETTE_UNUSED: u8 = 0b0100_0000;
+const PALETTE_AUTO_INCREMENT: u8 = 0b1000_0000;
+
+// OAM DMA copies one byte every machine cycle
+const DMA_LENGTH: u16 = UNUSED_START - OAM_START;
+const CYCLES_PER_DMA_BYTE: u16 = 4;
+
+// CGB VRAM DMA copies in blocks of 16 bytes, with HDMA5 holding the number
+// of blocks left minus one
+const HDMA_BLOCK_SIZE: u16 = 16;
+const HDMA_HBLANK_MODE: u8 = 0b1000_0000;
+const HDMA_LENGTH: u8 = 0b0111_1111;
+
+// Writing this to the serial control register starts a transfer
+const SERIAL_TRANSFER_START: u8 = 0x81;
+
+// Bits of the interrupt flag and interrupt enable registers
+pub const VBLANK_INTERRUPT: u8 = 0b0000_0001;
+pub const LCD_STAT_INTERRUPT: u8 = 0b0000_0010;
+pub const TIMER_INTERRUPT: u8 = 0b0000_0100;
+pub const SERIAL_INTERRUPT: u8 = 0b0000_1000;
+pub const JOYPAD_INTERRUPT: u8 = 0b0001_0000;
+
+// The interrupt sources, each with its own bit in IF and IE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    // highest priority first, which is the order the CPU services them in
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    pub fn bit(self: Self) -> u8 {
+        match self {
+            Interrupt::VBlank => VBLANK_INTERRUPT,
+            Interrupt::LcdStat => LCD_STAT_INTERRUPT,
+            Interrupt::Timer => TIMER_INTERRUPT,
+            Interrupt::Serial => SERIAL_INTERRUPT,
+            Interrupt::Joypad => JOYPAD_INTERRUPT,
+        }
+    }
+
+    // the address the CPU calls to handle it, 0x40 for VBlank and every 8
+    // bytes after that
+    pub fn vector(self: Self) -> u16 {
+        0x40 + 8 * self.bit().trailing_zeros() as u16
+    }
+}
+
+// Bits of the LCD status register
+const STAT_MODE: u8 = 0b0000_0011;
+const STAT_COINCIDENCE: u8 = 0b0000_0100;
+const STAT_UNUSED: u8 = 0b1000_0000;
+
+// Regions inside of the cartridge ROM that control the memory bank controller
+const RAM_ENABLE_START: u16 = 0x0000;
+const RAM_ENABLE_END: u16 = 0x2000;
+const ROM_BANK_NUMBER_START: u16 = 0x2000;
+const ROM_BANK_NUMBER_END: u16 = 0x4000;
+
+// A device the host plugs into part of the I/O range, like a link cable
+// partner or a printer, which takes over reads and writes to its addresses
+pub trait IoHandler {
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+}
+
+type MappedIo = (RangeInclusive<u16>, Box<dyn IoHandler>);
+type ReadWatch = (u16, Box<dyn FnMut(u16, u8)>);
+
+// a save state's memory section, read and checked in full before any of it
+// is applied so a bad state can't leave memory half loaded
+pub struct MemoryState {
+    rom_bank: usize,
+    ram_enabled: bool,
+    video_ram: [[u8; VRAM_SIZE]; VRAM_BANK_COUNT],
+    vram_bank: usize,
+    cartridge_ram: Vec<u8>,
+    working_ram: [u8; WRAM_BANK_SIZE * WRAM_BANK_COUNT],
+    wram_bank: u8,
+    object_attribute_memory: [u8; (UNUSED_START - OAM_START) as usize],
+    io_registers: [u8; (HIGH_RAM_START - IO_REGISTERS) as usize],
+    high_ram_start: [u8; (INTERRUPT_ENABLE_REGISTER - HIGH_RAM_START) as usize],
+    interrupt_enable_register: [u8; 1],
+    boot_rom_mapped: bool,
+    joypad: Joypad,
+    timer: Timer,
+    apu: Apu,
+    speed_switch_armed: bool,
+    double_speed: bool,
+    bg_palette_ram: [u8; PALETTE_RAM_SIZE],
+    obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    dma: Option<(u16, u16)>,
+    dma_cycles: u16,
+    hdma_source: u16,
+    hdma_destination: u16,
+    hdma_length: u8,
+    hdma_hblank: bool,
+}
 
 pub struct Memory {
+    // the full cartridge ROM, bank 0 is always mapped at 0x0000 and
+    // rom_bank is mapped at 0x4000
+    rom: Vec<u8>,
+    rom_bank: usize,
+    // None when no cartridge has been inserted, in which case the ROM
+    // area behaves like plain memory so programs can be written directly
+    mbc: Option<Mbc>,
+    // cartridges with a memory bank controller ignore their RAM until
+    // 0x0A is written to the RAM enable region
+    ram_enabled: bool,
+    // whether the header checksum matched, so front-ends can warn about it
+    header_checksum_valid: bool,
+    // the hardware revision, which decides what unmapped regions read as
+    model: Model,
     // All of the data that exists in the gameboy
-    rom_bank_0: [u8; (ROM_BANK_N_START - ROM_BANK_0_START) as usize],
-    rom_bank_n: [u8; (TILE_RAM_START - ROM_BANK_N_START) as usize],
-    tile_ram: [u8; (BACKGROUND_MAP_START - TILE_RAM_START) as usize],
-    background_map: [u8; (CARTRIDGE_RAM_START - BACKGROUND_MAP_START) as usize],
-    cartridge_ram: [u8; (WORKING_RAM_START - CARTRIDGE_RAM_START) as usize],
-    working_ram: [u8; (ECHO_RAM_START - WORKING_RAM_START) as usize],
-    echo_ram: [u8; (OAM_START - ECHO_RAM_START) as usize],
+    // tile data followed by the background maps, with the CPU seeing
+    // whichever bank VBK selects
+    video_ram: [[u8; VRAM_SIZE]; VRAM_BANK_COUNT],
+    vram_bank: usize,
+    // sized from the cartridge header, and empty if the cartridge has no RAM
+    cartridge_ram: Vec<u8>,
+    // every working RAM bank back to back, see working_ram_index
+    working_ram: [u8; WRAM_BANK_SIZE * WRAM_BANK_COUNT],
+    // the bank number last written to SVBK, where 0 selects bank 1
+    wram_bank: u8,
     object_attribute_memory: [u8; (UNUSED_START - OAM_START) as usize],
-    unused: [u8; (IO_REGISTERS - UNUSED_START) as usize],
     io_registers: [u8; (HIGH_RAM_START - IO_REGISTERS) as usize],
     high_ram_start: [u8; (INTERRUPT_ENABLE_REGISTER - HIGH_RAM_START) as usize],
     interrupt_enable_register: [u8; 1],
+    // Game Genie cheats patch ROM reads, Game Shark cheats are written into
+    // RAM once a frame
+    cheats: Vec<Cheat>,
+    // host devices mapped over I/O registers, which are consulted before the
+    // built in ones. Their state belongs to the host and isn't saved
+    io_handlers: Vec<MappedIo>,
+    // overlays the start of the cartridge ROM until a write to 0xFF50 unmaps it
+    boot_rom: Option<Vec<u8>>,
+    // Peripherals that are mapped into the I/O registers
+    joypad: Joypad,
+    timer: Timer,
+    apu: Apu,
+    // CGB speed switching, where STOP changes speed once the switch is armed
+    speed_switch_armed: bool,
+    double_speed: bool,
+    // CGB color palettes, reached a byte at a time through the index and
+    // data register pairs
+    bg_palette_ram: [u8; PALETTE_RAM_SIZE],
+    obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    // the CGB VRAM DMA, where the source and destination move on with each
+    // block copied. The destination is an offset into VRAM
+    hdma_source: u16,
+    hdma_destination: u16,
+    hdma_length: u8,
+    // whether an H-blank DMA is waiting for the next H-blank
+    hdma_hblank: bool,
+    // the source address and number of bytes copied of the OAM DMA in
+    // progress, if there is one
+    dma: Option<(u16, u16)>,
+    // cycles run since the last DMA byte was copied
+    dma_cycles: u16,
+    // every address read from and written to while tracking is turned on
+    track_accesses: bool,
+    reads: BitSet,
+    writes: BitSet,
+    // called with the address and value whenever the CPU reads a watched
+    // address. Reads only borrow memory, so the callbacks sit behind a
+    // RefCell to be callable from get_data
+    read_watches: RefCell<Vec<ReadWatch>>,
+    // kept alongside so reads can skip the RefCell when nothing is watched
+    has_read_watches: bool,
+    // every byte sent out over the serial port, which test ROMs use to
+    // report their results
+    serial_output: Vec<u8>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
         Memory {
-            rom_bank_0: [0; (ROM_BANK_N_START - ROM_BANK_0_START) as usize],
-            rom_bank_n: [0; (TILE_RAM_START - ROM_BANK_N_START) as usize],
-            tile_ram: [0; (BACKGROUND_MAP_START - TILE_RAM_START) as usize],
-            background_map: [0; (CARTRIDGE_RAM_START - BACKGROUND_MAP_START) as usize],
-            cartridge_ram: [0; (WORKING_RAM_START - CARTRIDGE_RAM_START) as usize],
-            working_ram: [0; (ECHO_RAM_START - WORKING_RAM_START) as usize],
-            echo_ram: [0; (OAM_START - ECHO_RAM_START) as usize],
+            rom: vec![0; (TILE_RAM_START - ROM_BANK_0_START) as usize],
+            rom_bank: 1,
+            mbc: None,
+            ram_enabled: false,
+            header_checksum_valid: true,
+            model: Model::default(),
+            video_ram: [[0; VRAM_SIZE]; VRAM_BANK_COUNT],
+            vram_bank: 0,
+            cartridge_ram: vec![0; (WORKING_RAM_START - CARTRIDGE_RAM_START) as usize],
+            working_ram: [0; WRAM_BANK_SIZE * WRAM_BANK_COUNT],
+            wram_bank: 0,
             object_attribute_memory: [0; (UNUSED_START - OAM_START) as usize],
-            unused: [0; (IO_REGISTERS - UNUSED_START) as usize],
             io_registers: [0; (HIGH_RAM_START - IO_REGISTERS) as usize],
             high_ram_start: [0; (INTERRUPT_ENABLE_REGISTER - HIGH_RAM_START) as usize],
-            interrupt_enable_register: [0; 1 as usize],
+            interrupt_enable_register: [0; 1],
+            cheats: Vec::new(),
+            io_handlers: Vec::new(),
+            boot_rom: None,
+            joypad: Joypad::new(),
+            timer: Timer::new(),
+            apu: Apu::new(),
+            speed_switch_armed: false,
+            double_speed: false,
+            bg_palette_ram: [0; PALETTE_RAM_SIZE],
+            obj_palette_ram: [0; PALETTE_RAM_SIZE],
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_length: HDMA_LENGTH,
+            hdma_hblank: false,
+            dma: None,
+            dma_cycles: 0,
+            track_accesses: false,
+            reads: BitSet::new(ADDRESS_SPACE_SIZE),
+            writes: BitSet::new(ADDRESS_SPACE_SIZE),
+            read_watches: RefCell::new(Vec::new()),
+            has_read_watches: false,
+            serial_output: Vec::new(),
+        }
+    }
+
+    // real hardware powers up with RAM full of noise, so tests that chase
+    // bugs around uninitialized memory can start every RAM region from a
+    // pattern other than zero
+    pub fn new_with_fill(pattern: u8) -> Self {
+        let mut memory = Memory::new();
+        for bank in memory.video_ram.iter_mut() {
+            bank.fill(pattern);
+        }
+        memory.cartridge_ram.fill(pattern);
+        memory.working_ram.fill(pattern);
+        memory.high_ram_start.fill(pattern);
+        memory
+    }
+
+    // starts working RAM from an image, anything past the end of the image
+    // is left zeroed
+    pub fn new_from_ram_image(working: &[u8]) -> Self {
+        let mut memory = Memory::new();
+        let len = working
+            .len()
+            .min((ECHO_RAM_START - WORKING_RAM_START) as usize);
+        memory.working_ram[..len].copy_from_slice(&working[..len]);
+        memory
+    }
+
+    // an empty machine of the given model, which every model specific
+    // behavior is decided by
+    pub fn with_model(model: Model) -> Self {
+        let mut memory = Memory::new();
+        memory.model = model;
+        memory
+    }
+
+    // puts the I/O registers into the state the boot ROM leaves them in,
+    // which ROMs started without a boot ROM rely on
+    pub fn set_post_boot_state(self: &mut Self) {
+        self.set_byte(SERIAL_CONTROL, 0x7E);
+        self.timer.set_div_counter(timer::DEFAULT_DIV_COUNTER);
+        self.set_tac(0xF8);
+        self.set_interrupt_flag(0xE1);
+
+        self.apu.set_post_boot_state();
+
+        self.set_lcdc(0x91);
+        self.set_bgp(0xFC);
+        // written directly, since writing DMA would start a transfer
+        self.io_registers[(DMA - IO_REGISTERS) as usize] = 0xFF;
+    }
+
+    pub fn with_ram_size(code: u8) -> Self {
+        let mut memory = Memory::new();
+        memory.cartridge_ram = vec![0; cartridge::ram_size(code)];
+        memory
+    }
+
+    pub fn model(self: &Self) -> Model {
+        self.model
+    }
+
+    pub fn set_model(self: &mut Self, model: Model) {
+        self.model = model;
+    }
+
+    #[cfg(feature = "std")]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, RomLoadError> {
+        Memory::from_rom(fs::read(path)?)
+    }
+
+    // a bad header checksum is only recorded, see load_rom to reject it
+    pub fn from_rom(rom: Vec<u8>) -> Result<Self, RomLoadError> {
+        Memory::load_rom(rom, false)
+    }
+
+    pub fn load_rom(mut rom: Vec<u8>, verify_checksum: bool) -> Result<Self, RomLoadError> {
+        if rom.len() < cartridge::HEADER_END {
+            return Err(RomLoadError::TooSmall);
         }
+
+        let header_checksum_valid =
+            cartridge::header_checksum(&rom) == rom[cartridge::HEADER_CHECKSUM];
+        if verify_checksum && !header_checksum_valid {
+            return Err(RomLoadError::BadHeaderChecksum);
+        }
+
+        let cartridge_type = rom[cartridge::CARTRIDGE_TYPE];
+        let mbc = Mbc::from_cartridge_type(cartridge_type)
+            .ok_or(RomLoadError::UnsupportedMapper(cartridge_type))?;
+
+        // real ROMs are always a power of two number of banks, so pad anything
+        // smaller out so that masking the bank number always stays in bounds
+        let rom_size = rom
+            .len()
+            .next_power_of_two()
+            .max((TILE_RAM_START - ROM_BANK_0_START) as usize);
+        rom.resize(rom_size, 0xFF);
+
+        let mut memory = Memory::with_ram_size(rom[cartridge::RAM_SIZE]);
+        memory.rom = rom;
+        memory.mbc = Some(mbc);
+        memory.header_checksum_valid = header_checksum_valid;
+        Ok(memory)
+    }
+
+    pub fn add_cheat(self: &mut Self, code: &str) -> Result<(), CheatParseError> {
+        self.cheats.push(Cheat::parse(code)?);
+        Ok(())
+    }
+
+    pub fn cheats(self: &Self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn clear_cheats(self: &mut Self) {
+        self.cheats.clear();
+    }
+
+    fn patch_rom_read(self: &Self, address: u16, byte: u8) -> u8 {
+        for cheat in &self.cheats {
+            if let Cheat::GameGenie {
+                address: patched,
+                value,
+                compare,
+            } = *cheat
+            {
+                if patched == address && compare.is_none_or(|compare| compare == byte) {
+                    return value;
+                }
+            }
+        }
+        byte
+    }
+
+    // called at the end of every frame. The bank isn't checked, so the
+    // value lands in whichever cartridge RAM bank is selected
+    pub fn apply_game_shark_cheats(self: &mut Self) {
+        for index in 0..self.cheats.len() {
+            if let Cheat::GameShark { address, value, .. } = self.cheats[index] {
+                self.set_byte(address, value);
+            }
+        }
+    }
+
+    // maps the boot ROM over the start of the cartridge, where it stays until
+    // the boot ROM itself writes to 0xFF50
+    pub fn load_boot_rom(self: &mut Self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    pub fn boot_rom_mapped(self: &Self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    pub fn header_checksum_valid(self: &Self) -> bool {
+        self.header_checksum_valid
+    }
+
+    pub fn cartridge_info(self: &Self) -> CartridgeInfo {
+        cartridge::cartridge_info(&self.rom)
+    }
+
+    // the bank mapped at 0x4000-0x7FFF
+    pub fn rom_bank(self: &Self) -> usize {
+        self.rom_bank
+    }
+
+    fn rom_bank_count(self: &Self) -> usize {
+        self.rom.len() / cartridge::ROM_BANK_SIZE
+    }
+
+    // selecting a bank past the end of the ROM wraps around the same way the
+    // hardware does, since the unused upper bits of the bank number are ignored
+    fn select_rom_bank(self: &mut Self, bank: usize) {
+        self.rom_bank = bank & (self.rom_bank_count() - 1);
+    }
+
+    fn write_to_mbc(self: &mut Self, address: u16, data: u8) {
+        match self.mbc {
+            None => self.rom[address as usize] = data,
+            Some(Mbc::RomOnly) => {}
+            Some(mbc) => {
+                if (RAM_ENABLE_START..RAM_ENABLE_END).contains(&address) {
+                    self.ram_enabled = data & 0x0F == 0x0A;
+                } else if (ROM_BANK_NUMBER_START..ROM_BANK_NUMBER_END).contains(&address) {
+                    self.select_rom_bank(mbc.rom_bank_number(data));
+                }
+            }
+        }
+    }
+
+    fn cartridge_ram_accessible(self: &Self) -> bool {
+        if self.cartridge_ram.is_empty() {
+            return false;
+        }
+
+        match self.mbc {
+            None | Some(Mbc::RomOnly) => true,
+            Some(_) => self.ram_enabled,
+        }
+    }
+
+    // a 2KB RAM chip is mirrored across the whole cartridge RAM area
+    fn cartridge_ram_index(self: &Self, address: u16) -> usize {
+        (address - CARTRIDGE_RAM_START) as usize % self.cartridge_ram.len()
+    }
+
+    // takes an offset from the start of working RAM. DMG always has bank 1
+    // at 0xD000, which is also what SVBK starts out selecting on CGB
+    fn working_ram_index(self: &Self, offset: u16) -> usize {
+        let switchable = WRAM_SWITCHABLE_START - WORKING_RAM_START;
+        if offset < switchable {
+            return offset as usize;
+        }
+        let bank = match self.model {
+            Model::Cgb => (self.wram_bank as usize).max(1),
+            _ => 1,
+        };
+        bank * WRAM_BANK_SIZE + (offset - switchable) as usize
     }
 
     pub fn get_data(self: &Self, address: u16) -> u8 {
+        if self.track_accesses {
+            self.reads.insert(address as usize);
+        }
+
+        let value = self.cpu_read(address);
+        // reads are far too common to do more than this check when nothing
+        // is being watched
+        if self.has_read_watches {
+            for (_, callback) in self
+                .read_watches
+                .borrow_mut()
+                .iter_mut()
+                .filter(|(watched, _)| *watched == address)
+            {
+                callback(address, value);
+            }
+        }
+        value
+    }
+
+    fn cpu_read(self: &Self, address: u16) -> u8 {
+        // the DMA owns the bus while it runs, leaving the CPU only high RAM
+        // and IE
+        if self.dma.is_some() && !(HIGH_RAM_START..=INTERRUPT_ENABLE_REGISTER).contains(&address) {
+            return 0xFF;
+        }
+
+        if self.ppu_owns(address) {
+            return 0xFF;
+        }
+
+        self.read(address)
+    }
+
+    // calls f with the address and the value read every time the CPU reads
+    // address, peeks by debuggers don't count
+    pub fn watch_read(self: &mut Self, address: u16, f: impl FnMut(u16, u8) + 'static) {
+        self.read_watches.get_mut().push((address, Box::new(f)));
+        self.has_read_watches = true;
+    }
+
+    pub fn clear_read_watches(self: &mut Self) {
+        self.read_watches.get_mut().clear();
+        self.has_read_watches = false;
+    }
+
+    // the cartridge ROM, boot ROM contents, cheats and debugging state stay
+    // with the session, only what the game can observe is saved
+    pub fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_u32(self.rom_bank as u32);
+        writer.write_bool(self.ram_enabled);
+        for bank in self.video_ram.iter() {
+            writer.write_bytes(bank);
+        }
+        writer.write_u8(self.vram_bank as u8);
+        writer.write_vec(&self.cartridge_ram);
+        writer.write_bytes(&self.working_ram);
+        writer.write_u8(self.wram_bank);
+        writer.write_bytes(&self.object_attribute_memory);
+        writer.write_bytes(&self.io_registers);
+        writer.write_bytes(&self.high_ram_start);
+        writer.write_bytes(&self.interrupt_enable_register);
+        writer.write_bool(self.boot_rom.is_some());
+        self.joypad.save_state(writer);
+        self.timer.save_state(writer);
+        self.apu.save_state(writer);
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_bool(self.double_speed);
+        writer.write_bytes(&self.bg_palette_ram);
+        writer.write_bytes(&self.obj_palette_ram);
+        let (source, copied) = self.dma.unwrap_or((0, 0));
+        writer.write_bool(self.dma.is_some());
+        writer.write_u16(source);
+        writer.write_u16(copied);
+        writer.write_u16(self.dma_cycles);
+        writer.write_u16(self.hdma_source);
+        writer.write_u16(self.hdma_destination);
+        writer.write_u8(self.hdma_length);
+        writer.write_bool(self.hdma_hblank);
+    }
+
+    pub fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        let state = self.read_state(reader)?;
+        self.apply_state(state);
+        Ok(())
+    }
+
+    // the peripherals are loaded into copies, so nothing here changes until
+    // apply_state
+    pub fn read_state(self: &Self, reader: &mut StateReader) -> Result<MemoryState, StateError> {
+        let rom_bank = reader.read_u32()? as usize;
+        if rom_bank >= self.rom_bank_count() {
+            return Err(StateError::InvalidValue);
+        }
+        let ram_enabled = reader.read_bool()?;
+        let mut video_ram = [[0; VRAM_SIZE]; VRAM_BANK_COUNT];
+        for bank in video_ram.iter_mut() {
+            reader.read_bytes(bank)?;
+        }
+        let vram_bank = reader.read_u8()? as usize;
+        if vram_bank >= VRAM_BANK_COUNT {
+            return Err(StateError::InvalidValue);
+        }
+        let cartridge_ram = reader.read_vec()?;
+        if cartridge_ram.len() != self.cartridge_ram.len() {
+            return Err(StateError::InvalidValue);
+        }
+        let mut working_ram = [0; WRAM_BANK_SIZE * WRAM_BANK_COUNT];
+        reader.read_bytes(&mut working_ram)?;
+        let wram_bank = reader.read_u8()?;
+        if wram_bank & !WRAM_BANK_SELECT != 0 {
+            return Err(StateError::InvalidValue);
+        }
+        let mut object_attribute_memory = [0; (UNUSED_START - OAM_START) as usize];
+        reader.read_bytes(&mut object_attribute_memory)?;
+        let mut io_registers = [0; (HIGH_RAM_START - IO_REGISTERS) as usize];
+        reader.read_bytes(&mut io_registers)?;
+        let mut high_ram_start = [0; (INTERRUPT_ENABLE_REGISTER - HIGH_RAM_START) as usize];
+        reader.read_bytes(&mut high_ram_start)?;
+        let mut interrupt_enable_register = [0; 1];
+        reader.read_bytes(&mut interrupt_enable_register)?;
+        let boot_rom_mapped = reader.read_bool()?;
+        let mut joypad = self.joypad.clone();
+        joypad.load_state(reader)?;
+        let mut timer = self.timer.clone();
+        timer.load_state(reader)?;
+        let mut apu = self.apu.clone();
+        apu.load_state(reader)?;
+        let speed_switch_armed = reader.read_bool()?;
+        let double_speed = reader.read_bool()?;
+        let mut bg_palette_ram = [0; PALETTE_RAM_SIZE];
+        reader.read_bytes(&mut bg_palette_ram)?;
+        let mut obj_palette_ram = [0; PALETTE_RAM_SIZE];
+        reader.read_bytes(&mut obj_palette_ram)?;
+        let dma_active = reader.read_bool()?;
+        let source = reader.read_u16()?;
+        let copied = reader.read_u16()?;
+        let dma_cycles = reader.read_u16()?;
+        let hdma_source = reader.read_u16()?;
+        let hdma_destination = reader.read_u16()?;
+        let hdma_length = reader.read_u8()?;
+        let hdma_hblank = reader.read_bool()?;
+        if hdma_destination as usize >= VRAM_SIZE || hdma_length & !HDMA_LENGTH != 0 {
+            return Err(StateError::InvalidValue);
+        }
+
+        Ok(MemoryState {
+            rom_bank,
+            ram_enabled,
+            video_ram,
+            vram_bank,
+            cartridge_ram,
+            working_ram,
+            wram_bank,
+            object_attribute_memory,
+            io_registers,
+            high_ram_start,
+            interrupt_enable_register,
+            boot_rom_mapped,
+            joypad,
+            timer,
+            apu,
+            speed_switch_armed,
+            double_speed,
+            bg_palette_ram,
+            obj_palette_ram,
+            dma: dma_active.then_some((source, copied)),
+            dma_cycles,
+            hdma_source,
+            hdma_destination,
+            hdma_length,
+            hdma_hblank,
+        })
+    }
+
+    pub fn apply_state(self: &mut Self, state: MemoryState) {
+        self.rom_bank = state.rom_bank;
+        self.ram_enabled = state.ram_enabled;
+        self.video_ram = state.video_ram;
+        self.vram_bank = state.vram_bank;
+        self.cartridge_ram = state.cartridge_ram;
+        self.working_ram = state.working_ram;
+        self.wram_bank = state.wram_bank;
+        self.object_attribute_memory = state.object_attribute_memory;
+        self.io_registers = state.io_registers;
+        self.high_ram_start = state.high_ram_start;
+        self.interrupt_enable_register = state.interrupt_enable_register;
+        // a boot ROM that has been unmapped can't come back
+        if !state.boot_rom_mapped {
+            self.boot_rom = None;
+        }
+        self.joypad = state.joypad;
+        self.timer = state.timer;
+        self.apu = state.apu;
+        self.speed_switch_armed = state.speed_switch_armed;
+        self.double_speed = state.double_speed;
+        self.bg_palette_ram = state.bg_palette_ram;
+        self.obj_palette_ram = state.obj_palette_ram;
+        self.dma = state.dma;
+        self.dma_cycles = state.dma_cycles;
+        self.hdma_source = state.hdma_source;
+        self.hdma_destination = state.hdma_destination;
+        self.hdma_length = state.hdma_length;
+        self.hdma_hblank = state.hdma_hblank;
+    }
+
+    // the PPU keeps VRAM while drawing and OAM while scanning or drawing.
+    // The PPU publishes its mode through STAT, which is where it's read from
+    fn ppu_owns(self: &Self, address: u16) -> bool {
+        let mode = self.io_registers[(LCD_STATUS - IO_REGISTERS) as usize] & STAT_MODE;
+        match address {
+            TILE_RAM_START..CARTRIDGE_RAM_START => mode == Mode::Drawing as u8,
+            OAM_START..UNUSED_START => mode == Mode::OamScan as u8 || mode == Mode::Drawing as u8,
+            _ => false,
+        }
+    }
+
+    // lets debuggers look at memory without it counting as a CPU access
+    pub fn peek(self: &Self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    // reads without any of the restrictions placed on the CPU
+    fn read(self: &Self, address: u16) -> u8 {
+        if let Some(byte) = self
+            .boot_rom
+            .as_ref()
+            .and_then(|boot_rom| boot_rom.get(address as usize))
+        {
+            return *byte;
+        }
+
         let offset;
         match address {
             ROM_BANK_0_START..ROM_BANK_N_START => {
                 offset = 0;
-                self.rom_bank_0[(address - offset) as usize]
+                self.patch_rom_read(address, self.rom[(address - offset) as usize])
             }
-            _ => panic!(),
+            ROM_BANK_N_START..TILE_RAM_START => {
+                offset = ROM_BANK_N_START;
+                self.patch_rom_read(
+                    address,
+                    self.rom
+                        [self.rom_bank * cartridge::ROM_BANK_SIZE + (address - offset) as usize],
+                )
+            }
+            TILE_RAM_START..CARTRIDGE_RAM_START => {
+                offset = TILE_RAM_START;
+                self.video_ram[self.vram_bank][(address - offset) as usize]
+            }
+            CARTRIDGE_RAM_START..WORKING_RAM_START => match self.cartridge_ram_accessible() {
+                true => self.cartridge_ram[self.cartridge_ram_index(address)],
+                false => 0xFF,
+            },
+            WORKING_RAM_START..ECHO_RAM_START => {
+                offset = WORKING_RAM_START;
+                self.working_ram[self.working_ram_index(address - offset)]
+            }
+            // echo ram mirrors the start of working ram, banks included
+            ECHO_RAM_START..OAM_START => {
+                offset = ECHO_RAM_START;
+                self.working_ram[self.working_ram_index(address - offset)]
+            }
+            OAM_START..UNUSED_START => {
+                offset = OAM_START;
+                self.object_attribute_memory[(address - offset) as usize]
+            }
+            UNUSED_START..IO_REGISTERS => self.model.unused_region_value(),
+            IO_REGISTERS..HIGH_RAM_START => self.read_io_register(address),
+            HIGH_RAM_START..INTERRUPT_ENABLE_REGISTER => {
+                offset = HIGH_RAM_START;
+                self.high_ram_start[(address - offset) as usize]
+            }
+            INTERRUPT_ENABLE_REGISTER => self.interrupt_enable_register[0],
+        }
+    }
+
+    // 16-bit values are stored little-endian, with the low byte at address
+    pub fn read_word(self: &Self, address: u16) -> u16 {
+        let low = self.get_data(address) as u16;
+        let high = self.get_data(address.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    pub fn write_word(self: &mut Self, address: u16, value: u16) {
+        self.set_byte(address, (value & 0xFF) as u8);
+        self.set_byte(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    // every address the pattern starts at, overlapping matches included.
+    // Scans what the bus decodes to without counting as CPU reads
+    pub fn find(self: &Self, needle: &[u8]) -> Vec<u16> {
+        if needle.is_empty() {
+            return Vec::new();
         }
+
+        let haystack: Vec<u8> = (0..=u16::MAX).map(|address| self.read(address)).collect();
+        haystack
+            .windows(needle.len())
+            .enumerate()
+            .filter(|(_, window)| *window == needle)
+            .map(|(address, _)| address as u16)
+            .collect()
     }
 
     pub fn set_byte(self: &mut Self, address: u16, data: u8) {
+        if self.track_accesses {
+            self.writes.insert(address as usize);
+        }
+
         let offset;
         match address {
-            ROM_BANK_0_START..ROM_BANK_N_START => {
-                offset = 0;
-                self.rom_bank_0[(address - offset) as usize] = data;
+            ROM_BANK_0_START..TILE_RAM_START => self.write_to_mbc(address, data),
+            TILE_RAM_START..CARTRIDGE_RAM_START => {
+                offset = TILE_RAM_START;
+                self.video_ram[self.vram_bank][(address - offset) as usize] = data;
+            }
+            CARTRIDGE_RAM_START..WORKING_RAM_START => {
+                if self.cartridge_ram_accessible() {
+                    let index = self.cartridge_ram_index(address);
+                    self.cartridge_ram[index] = data;
+                }
+            }
+            WORKING_RAM_START..ECHO_RAM_START => {
+                offset = WORKING_RAM_START;
+                let index = self.working_ram_index(address - offset);
+                self.working_ram[index] = data;
             }
-            _ => panic!(),
+            ECHO_RAM_START..OAM_START => {
+                offset = ECHO_RAM_START;
+                let index = self.working_ram_index(address - offset);
+                self.working_ram[index] = data;
+            }
+            OAM_START..UNUSED_START => {
+                offset = OAM_START;
+                self.object_attribute_memory[(address - offset) as usize] = data;
+            }
+            // nothing is connected here, so writes are dropped
+            UNUSED_START..IO_REGISTERS => {}
+            IO_REGISTERS..HIGH_RAM_START => self.write_io_register(address, data),
+            HIGH_RAM_START..INTERRUPT_ENABLE_REGISTER => {
+                offset = HIGH_RAM_START;
+                self.high_ram_start[(address - offset) as usize] = data;
+            }
+            INTERRUPT_ENABLE_REGISTER => self.interrupt_enable_register[0] = data,
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // maps a host device over part of the I/O registers. A later mapping
+    // takes priority where it overlaps an earlier one
+    pub fn map_io(self: &mut Self, range: RangeInclusive<u16>, handler: Box<dyn IoHandler>) {
+        assert!(
+            *range.start() >= IO_REGISTERS && *range.end() < HIGH_RAM_START,
+            "{:#06X}..={:#06X} is outside the I/O registers",
+            range.start(),
+            range.end()
+        );
+        self.io_handlers.push((range, handler));
+    }
 
-    #[test]
-    fn test_get_and_set_byte() {
-        let pc = 0x100;
-        let new_value = 10;
-        let mut memory = Memory::new();
-        assert_eq!(memory.get_data(pc), 0);
-        memory.set_byte(pc, new_value);
-        assert_eq!(memory.get_data(pc), new_value);
+    fn read_io_register(self: &Self, address: u16) -> u8 {
+        if let Some((_, handler)) = self
+            .io_handlers
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&address))
+        {
+            return handler.read(address);
+        }
+
+        match address {
+            JOYPAD_REGISTER => self.joypad.read(),
+            timer::DIV..=timer::TAC => self.timer.read(address),
+            apu::NR10..=apu::WAVE_RAM_END => self.apu.read(address),
+            apu::PCM12 | apu::PCM34 if self.model == Model::Cgb => self.apu.read(address),
+            apu::PCM12 | apu::PCM34 => 0xFF,
+            LCD_STATUS => self.io_registers[(address - IO_REGISTERS) as usize] | STAT_UNUSED,
+            KEY1 if self.model == Model::Cgb => {
+                let speed = if self.double_speed {
+                    KEY1_CURRENT_SPEED
+                } else {
+                    0
+                };
+                // the unused bits read as 1
+                speed | !(KEY1_CURRENT_SPEED | KEY1_PREPARE_SWITCH) | self.speed_switch_armed as u8
+            }
+            KEY1 => 0xFF,
+            BCPS | OCPS if self.model == Model::Cgb => {
+                self.io_registers[(address - IO_REGISTERS) as usize] | PALETTE_UNUSED
+            }
+            BCPD if self.model == Model::Cgb => self.bg_palette_ram[self.palette_index(BCPS)],
+            OCPD if self.model == Model::Cgb => self.obj_palette_ram[self.palette_index(OCPS)],
+            BCPS..=OCPD => 0xFF,
+            // only the bank bit is used
+            VBK if self.model == Model::Cgb => 0xFE | self.vram_bank as u8,
+            VBK => 0xFF,
+            SVBK if self.model == Model::Cgb => !WRAM_BANK_SELECT | self.wram_bank,
+            SVBK => 0xFF,
+            // bit 7 is clear while an H-blank DMA is still running
+            HDMA5 if self.model == Model::Cgb => match self.hdma_hblank {
+                true => self.hdma_length,
+                false => HDMA_HBLANK_MODE | self.hdma_length,
+            },
+            // the address registers are write only
+            HDMA1..=HDMA5 => 0xFF,
+            // every bit but the lowest is unused and reads as 1, and the
+            // lowest is set once the boot ROM has been unmapped
+            BOOT_ROM_DISABLE => match self.boot_rom {
+                Some(_) => 0xFE,
+                None => 0xFF,
+            },
+            _ => self.io_registers[(address - IO_REGISTERS) as usize],
+        }
+    }
+
+    fn write_io_register(self: &mut Self, address: u16, data: u8) {
+        if let Some((_, handler)) = self
+            .io_handlers
+            .iter_mut()
+            .rev()
+            .find(|(range, _)| range.contains(&address))
+        {
+            handler.write(address, data);
+            return;
+        }
+
+        match address {
+            JOYPAD_REGISTER => self.joypad.write(data),
+            DMA => {
+                self.io_registers[(address - IO_REGISTERS) as usize] = data;
+                self.dma = Some(((data as u16) << 8, 0));
+                self.dma_cycles = 0;
+            }
+            timer::DIV..=timer::TAC => self.timer.write(address, data),
+            apu::NR10..=apu::WAVE_RAM_END => self.apu.write(address, data),
+            // the channel outputs can only be read
+            apu::PCM12 | apu::PCM34 => {}
+            // the boot ROM can't be mapped back in once it's gone
+            BOOT_ROM_DISABLE => {
+                if data != 0 {
+                    self.boot_rom = None;
+                }
+            }
+            KEY1 => {
+                self.speed_switch_armed =
+                    self.model == Model::Cgb && data & KEY1_PREPARE_SWITCH != 0;
+            }
+            BCPS | OCPS if self.model == Model::Cgb => {
+                self.io_registers[(address - IO_REGISTERS) as usize] = data
+            }
+            BCPD if self.model == Model::Cgb => {
+                self.bg_palette_ram[self.palette_index(BCPS)] = data;
+                self.advance_palette_index(BCPS);
+            }
+            OCPD if self.model == Model::Cgb => {
+                self.obj_palette_ram[self.palette_index(OCPS)] = data;
+                self.advance_palette_index(OCPS);
+            }
+            // the palette registers don't exist on DMG
+            BCPS..=OCPD => {}
+            VBK => {
+                if self.model == Model::Cgb {
+                    self.vram_bank = (data & 0x01) as usize;
+                }
+            }
+            SVBK => {
+                if self.model == Model::Cgb {
+                    self.wram_bank = data & WRAM_BANK_SELECT;
+                }
+            }
+            HDMA1..=HDMA5 if self.model == Model::Cgb => self.write_hdma_register(address, data),
+            HDMA1..=HDMA5 => {}
+            // the mode and coincidence bits belong to the PPU
+            LCD_STATUS => {
+                let status = &mut self.io_registers[(address - IO_REGISTERS) as usize];
+                *status = (*status & (STAT_MODE | STAT_COINCIDENCE))
+                    | (data & !(STAT_MODE | STAT_COINCIDENCE));
+            }
+            SERIAL_CONTROL => {
+                self.io_registers[(address - IO_REGISTERS) as usize] = data;
+                if data & SERIAL_TRANSFER_START == SERIAL_TRANSFER_START {
+                    self.transfer_serial_byte();
+                }
+            }
+            _ => self.io_registers[(address - IO_REGISTERS) as usize] = data,
+        }
+    }
+
+    fn palette_index(self: &Self, index_register: u16) -> usize {
+        (self.io_registers[(index_register - IO_REGISTERS) as usize] & PALETTE_INDEX) as usize
+    }
+
+    // with auto increment set, each write to the data register moves the
+    // index on to the next byte, wrapping within palette RAM
+    fn advance_palette_index(self: &mut Self, index_register: u16) {
+        let index = &mut self.io_registers[(index_register - IO_REGISTERS) as usize];
+        if *index & PALETTE_AUTO_INCREMENT != 0 {
+            *index = PALETTE_AUTO_INCREMENT | (index.wrapping_add(1) & PALETTE_INDEX);
+        }
+    }
+
+    pub fn bg_palette_ram(self: &Self) -> &[u8; PALETTE_RAM_SIZE] {
+        &self.bg_palette_ram
+    }
+
+    pub fn obj_palette_ram(self: &Self) -> &[u8; PALETTE_RAM_SIZE] {
+        &self.obj_palette_ram
+    }
+
+    // there's never anything on the other end of the link cable, so the
+    // transfer finishes immediately
+    fn transfer_serial_byte(self: &mut Self) {
+        let data = self.io_registers[(SERIAL_DATA - IO_REGISTERS) as usize];
+        self.serial_output.push(data);
+        self.io_registers[(SERIAL_CONTROL - IO_REGISTERS) as usize] &= !0x80;
+        self.request_interrupt(SERIAL_INTERRUPT);
+    }
+
+    pub fn request_interrupt(self: &mut Self, interrupt: u8) {
+        self.io_registers[(INTERRUPT_FLAG - IO_REGISTERS) as usize] |= interrupt;
+    }
+
+    // clears the request once the CPU has started servicing it
+    pub fn acknowledge_interrupt(self: &mut Self, interrupt: Interrupt) {
+        self.io_registers[(INTERRUPT_FLAG - IO_REGISTERS) as usize] &= !interrupt.bit();
+    }
+
+    // the PPU reports its current line and mode through LY and STAT
+    pub fn set_ppu_state(self: &mut Self, line: u8, mode: u8) {
+        self.io_registers[(LY - IO_REGISTERS) as usize] = line;
+
+        let mut status = self.io_registers[(LCD_STATUS - IO_REGISTERS) as usize];
+        status &= !(STAT_MODE | STAT_COINCIDENCE);
+        status |= mode & STAT_MODE;
+        if line == self.io_registers[(LYC - IO_REGISTERS) as usize] {
+            status |= STAT_COINCIDENCE;
+        }
+        self.io_registers[(LCD_STATUS - IO_REGISTERS) as usize] = status;
+    }
+
+    pub fn take_serial_output(self: &mut Self) -> Vec<u8> {
+        core::mem::take(&mut self.serial_output)
+    }
+
+    // typed accessors for the I/O registers, going through the same decode
+    // path as the CPU
+    pub fn lcdc(self: &Self) -> u8 {
+        self.read(LCDC)
+    }
+
+    pub fn set_lcdc(self: &mut Self, value: u8) {
+        self.set_byte(LCDC, value);
+    }
+
+    pub fn stat(self: &Self) -> u8 {
+        self.read(LCD_STATUS)
+    }
+
+    pub fn set_stat(self: &mut Self, value: u8) {
+        self.set_byte(LCD_STATUS, value);
+    }
+
+    pub fn scy(self: &Self) -> u8 {
+        self.read(SCY)
+    }
+
+    pub fn set_scy(self: &mut Self, value: u8) {
+        self.set_byte(SCY, value);
+    }
+
+    pub fn scx(self: &Self) -> u8 {
+        self.read(SCX)
+    }
+
+    pub fn set_scx(self: &mut Self, value: u8) {
+        self.set_byte(SCX, value);
+    }
+
+    pub fn ly(self: &Self) -> u8 {
+        self.read(LY)
+    }
+
+    pub fn set_ly(self: &mut Self, value: u8) {
+        self.set_byte(LY, value);
+    }
+
+    pub fn lyc(self: &Self) -> u8 {
+        self.read(LYC)
+    }
+
+    pub fn set_lyc(self: &mut Self, value: u8) {
+        self.set_byte(LYC, value);
+    }
+
+    pub fn bgp(self: &Self) -> u8 {
+        self.read(BGP)
+    }
+
+    pub fn set_bgp(self: &mut Self, value: u8) {
+        self.set_byte(BGP, value);
+    }
+
+    pub fn obp0(self: &Self) -> u8 {
+        self.read(OBP0)
+    }
+
+    pub fn set_obp0(self: &mut Self, value: u8) {
+        self.set_byte(OBP0, value);
+    }
+
+    pub fn obp1(self: &Self) -> u8 {
+        self.read(OBP1)
+    }
+
+    pub fn set_obp1(self: &mut Self, value: u8) {
+        self.set_byte(OBP1, value);
+    }
+
+    pub fn wy(self: &Self) -> u8 {
+        self.read(WY)
+    }
+
+    pub fn set_wy(self: &mut Self, value: u8) {
+        self.set_byte(WY, value);
+    }
+
+    pub fn wx(self: &Self) -> u8 {
+        self.read(WX)
+    }
+
+    pub fn set_wx(self: &mut Self, value: u8) {
+        self.set_byte(WX, value);
+    }
+
+    pub fn interrupt_flag(self: &Self) -> u8 {
+        self.read(INTERRUPT_FLAG)
+    }
+
+    pub fn set_interrupt_flag(self: &mut Self, value: u8) {
+        self.set_byte(INTERRUPT_FLAG, value);
+    }
+
+    // unlike IF, every bit of IE can be written and read back, although
+    // only the low five enable anything
+    pub fn ie(self: &Self) -> u8 {
+        self.read(INTERRUPT_ENABLE_REGISTER)
+    }
+
+    pub fn set_ie(self: &mut Self, value: u8) {
+        self.set_byte(INTERRUPT_ENABLE_REGISTER, value);
+    }
+
+    // the interrupts that are both requested and enabled, only the low five
+    // bits of each register correspond to an interrupt
+    pub fn pending_interrupts(self: &Self) -> u8 {
+        self.interrupt_flag() & self.ie() & 0x1F
+    }
+
+    pub fn tac(self: &Self) -> u8 {
+        self.read(timer::TAC)
+    }
+
+    pub fn set_tac(self: &mut Self, value: u8) {
+        self.set_byte(timer::TAC, value);
+    }
+
+    // writes value across a range through the normal decode path, skipping
+    // the ROM region so the fill can't reconfigure the MBC
+    pub fn fill(self: &mut Self, start: u16, len: usize, value: u8) {
+        for offset in 0..len {
+            let address = start.wrapping_add(offset as u16);
+            if address >= TILE_RAM_START {
+                self.set_byte(address, value);
+            }
+        }
+    }
+
+    // writes a block of bytes through the normal decode path, so a block can
+    // cross from one region into the next
+    #[cfg(test)]
+    pub fn load_at(self: &mut Self, start: u16, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.set_byte(start.wrapping_add(offset as u16), byte);
+        }
+    }
+
+    // writes an 8x8 tile of color numbers (0-3) at the given index from
+    // 0x8000, each row becoming a byte of low bits then a byte of high bits
+    #[cfg(test)]
+    pub fn set_tile(self: &mut Self, index: u8, pixels: &[[u8; 8]; 8]) {
+        let start = TILE_RAM_START + index as u16 * 16;
+        for (y, row) in pixels.iter().enumerate() {
+            let (mut low, mut high) = (0, 0);
+            for (x, &color) in row.iter().enumerate() {
+                low |= (color & 0b01) << (7 - x);
+                high |= ((color & 0b10) >> 1) << (7 - x);
+            }
+            self.set_byte(start + y as u16 * 2, low);
+            self.set_byte(start + y as u16 * 2 + 1, high);
+        }
+    }
+
+    // points the tile at column x, row y of the first background map at a tile
+    #[cfg(test)]
+    pub fn set_bg_map_entry(self: &mut Self, x: u8, y: u8, tile: u8) {
+        self.set_byte(BACKGROUND_MAP_START + y as u16 * 32 + x as u16, tile);
+    }
+
+    // the PPU owns the video buses, so it reads VRAM and OAM directly
+    // rather than going through get_data
+    pub fn video_ram(self: &Self, address: u16) -> u8 {
+        self.video_ram_in_bank(0, address)
+    }
+
+    // the PPU reads both banks regardless of VBK, bank 1 holding the
+    // palette, flip and priority attributes of each background map entry
+    pub fn video_ram_in_bank(self: &Self, bank: usize, address: u16) -> u8 {
+        match address {
+            TILE_RAM_START..CARTRIDGE_RAM_START => {
+                self.video_ram[bank][(address - TILE_RAM_START) as usize]
+            }
+            _ => panic!("{:#06X} is not a video ram address", address),
+        }
+    }
+
+    pub fn object_attribute_memory(self: &Self) -> &[u8] {
+        &self.object_attribute_memory
+    }
+
+    pub fn oam_sprites(self: &Self) -> [Sprite; SPRITE_COUNT] {
+        let mut sprites = [Sprite::default(); SPRITE_COUNT];
+        for (sprite, entry) in sprites
+            .iter_mut()
+            .zip(self.object_attribute_memory.chunks(BYTES_PER_SPRITE))
+        {
+            *sprite = Sprite::from_bytes(entry);
+        }
+        sprites
+    }
+
+    pub fn set_oam_sprite(self: &mut Self, index: usize, sprite: Sprite) {
+        let start = index * BYTES_PER_SPRITE;
+        self.object_attribute_memory[start..start + BYTES_PER_SPRITE]
+            .copy_from_slice(&sprite.to_bytes());
+    }
+
+    pub fn joypad(self: &Self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn joypad_mut(self: &mut Self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    // debugging aid that records which addresses get read and written
+    pub fn set_access_tracking(self: &mut Self, enabled: bool) {
+        self.track_accesses = enabled;
+    }
+
+    // the addresses that have been read and written, in that order
+    pub fn access_map(self: &Self) -> (&BitSet, &BitSet) {
+        (&self.reads, &self.writes)
+    }
+
+    pub fn reset_access_map(self: &mut Self) {
+        self.reads.clear();
+        self.writes.clear();
+    }
+
+    pub fn timer(self: &Self) -> &Timer {
+        &self.timer
+    }
+
+    pub fn timer_mut(self: &mut Self) -> &mut Timer {
+        &mut self.timer
+    }
+
+    pub fn apu(self: &Self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(self: &mut Self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn double_speed(self: &Self) -> bool {
+        self.double_speed
+    }
+
+    // called when the CPU executes STOP, returning true if that switched speed
+    pub fn stop(self: &mut Self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn dma_active(self: &Self) -> bool {
+        self.dma.is_some()
+    }
+
+    pub fn step_dma(self: &mut Self, cycles: u8) {
+        let Some((source, mut copied)) = self.dma else {
+            return;
+        };
+
+        self.dma_cycles += cycles as u16;
+        while self.dma_cycles >= CYCLES_PER_DMA_BYTE && copied < DMA_LENGTH {
+            self.dma_cycles -= CYCLES_PER_DMA_BYTE;
+            self.object_attribute_memory[copied as usize] = self.read(source + copied);
+            copied += 1;
+        }
+
+        self.dma = match copied {
+            DMA_LENGTH => None,
+            _ => Some((source, copied)),
+        };
+    }
+
+    // the low nibble of both addresses is ignored, and the destination is
+    // always in VRAM
+    fn write_hdma_register(self: &mut Self, address: u16, data: u8) {
+        match address {
+            HDMA1 => self.hdma_source = (self.hdma_source & 0x00FF) | (data as u16) << 8,
+            HDMA2 => self.hdma_source = (self.hdma_source & 0xFF00) | (data & 0xF0) as u16,
+            HDMA3 => {
+                self.hdma_destination =
+                    (self.hdma_destination & 0x00FF) | ((data & 0x1F) as u16) << 8
+            }
+            HDMA4 => {
+                self.hdma_destination = (self.hdma_destination & 0xFF00) | (data & 0xF0) as u16
+            }
+            _ => {
+                // writing with bit 7 clear while an H-blank DMA runs stops it
+                if self.hdma_hblank && data & HDMA_HBLANK_MODE == 0 {
+                    self.hdma_hblank = false;
+                    return;
+                }
+
+                self.hdma_length = data & HDMA_LENGTH;
+                if data & HDMA_HBLANK_MODE != 0 {
+                    self.hdma_hblank = true;
+                } else {
+                    // a general DMA copies everything at once
+                    while self.copy_hdma_block() {}
+                }
+            }
+        }
+    }
+
+    // copies the next block, returning false once the transfer is finished
+    fn copy_hdma_block(self: &mut Self) -> bool {
+        for offset in 0..HDMA_BLOCK_SIZE {
+            let data = self.read(self.hdma_source.wrapping_add(offset));
+            let destination = (self.hdma_destination + offset) as usize % VRAM_SIZE;
+            self.video_ram[self.vram_bank][destination] = data;
+        }
+        self.hdma_source = self.hdma_source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.hdma_destination = (self.hdma_destination + HDMA_BLOCK_SIZE) % VRAM_SIZE as u16;
+
+        if self.hdma_length == 0 {
+            self.hdma_length = HDMA_LENGTH;
+            self.hdma_hblank = false;
+            return false;
+        }
+        self.hdma_length -= 1;
+        true
+    }
+
+    // called by the PPU as each visible line enters H-blank
+    pub fn hblank_started(self: &mut Self) {
+        if self.hdma_hblank {
+            self.copy_hdma_block();
+        }
+    }
+
+    pub fn step_timer(self: &mut Self, cycles: u8) {
+        if self.timer.step(cycles) {
+            self.request_interrupt(TIMER_INTERRUPT);
+        }
+    }
+
+    // runs after the timer so the frame sequencer sees the latest DIV
+    pub fn step_apu(self: &mut Self, cycles: u8) {
+        self.apu.step(cycles, self.timer.div_counter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    #[test]
+    fn test_get_and_set_byte() {
+        let pc = 0x100;
+        let new_value = 10;
+        let mut memory = Memory::new();
+        assert_eq!(memory.get_data(pc), 0);
+        memory.set_byte(pc, new_value);
+        assert_eq!(memory.get_data(pc), new_value);
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(0x0000), MemRegion::RomBank0);
+        assert_eq!(classify(0x4000), MemRegion::RomBankN);
+        assert_eq!(classify(0x8001), MemRegion::TileRam);
+        assert_eq!(classify(0x9C00), MemRegion::BackgroundMap);
+        assert_eq!(classify(0xBFFF), MemRegion::CartridgeRam);
+        assert_eq!(classify(0xC000), MemRegion::WorkingRam);
+        assert_eq!(classify(0xE123), MemRegion::EchoRam);
+        assert_eq!(classify(0xFE9F), MemRegion::ObjectAttributeMemory);
+        assert_eq!(classify(0xFEA0), MemRegion::Unused);
+        assert_eq!(classify(0xFF44), MemRegion::IoRegisters);
+        assert_eq!(classify(0xFF85), MemRegion::HighRam);
+        assert_eq!(classify(0xFFFF), MemRegion::InterruptEnable);
+    }
+
+    #[test]
+    fn test_word_is_little_endian() {
+        let mut memory = Memory::new();
+        memory.write_word(0xC000, 0xBEEF);
+
+        assert_eq!(memory.read_word(0xC000), 0xBEEF);
+        assert_eq!(memory.get_data(0xC000), 0xEF);
+        assert_eq!(memory.get_data(0xC001), 0xBE);
+    }
+
+    #[test]
+    fn test_game_genie_patches_rom_reads() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4A17] = 0x05;
+        rom[0x0150] = 0x07;
+        let mut memory = Memory::from_rom(rom).unwrap();
+
+        memory.add_cheat("3EA-17B-FAE").unwrap();
+        assert_eq!(memory.get_data(0x4A17), 0x3E);
+
+        // the compare value has to match for the patch to apply
+        memory.add_cheat("3E1-50F-FAE").unwrap();
+        assert_eq!(memory.get_data(0x0150), 0x07);
+
+        assert!(memory.add_cheat("not a code").is_err());
+        assert_eq!(memory.cheats().len(), 2);
+    }
+
+    #[test]
+    fn test_game_shark_writes_ram() {
+        let mut memory = Memory::new();
+        memory.add_cheat("01FF34C2").unwrap();
+        assert_eq!(memory.get_data(0xC234), 0x00);
+
+        memory.apply_game_shark_cheats();
+        assert_eq!(memory.get_data(0xC234), 0xFF);
+    }
+
+    #[test]
+    fn test_new_with_fill() {
+        let memory = Memory::new_with_fill(0xFF);
+
+        assert_eq!(memory.get_data(WORKING_RAM_START), 0xFF);
+        assert_eq!(memory.get_data(ECHO_RAM_START - 1), 0xFF);
+        assert_eq!(memory.get_data(HIGH_RAM_START), 0xFF);
+        assert_eq!(memory.get_data(TILE_RAM_START), 0xFF);
+        // registers aren't RAM and keep their usual values
+        assert_eq!(memory.get_data(INTERRUPT_FLAG), 0x00);
+    }
+
+    #[test]
+    fn test_new_from_ram_image() {
+        let memory = Memory::new_from_ram_image(&[0x12, 0x34, 0x56]);
+
+        assert_eq!(memory.get_data(WORKING_RAM_START), 0x12);
+        assert_eq!(memory.get_data(WORKING_RAM_START + 2), 0x56);
+        assert_eq!(memory.get_data(WORKING_RAM_START + 3), 0x00);
+    }
+
+    #[test]
+    fn test_find() {
+        let mut memory = Memory::new();
+        memory.load_at(0xC010, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        memory.load_at(0xD234, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        // overlapping matches
+        memory.load_at(0xC100, &[0x5A, 0x5A, 0x5A]);
+
+        // echo RAM mirrors both matches too
+        assert_eq!(
+            memory.find(&[0xDE, 0xAD, 0xBE, 0xEF]),
+            vec![0xC010, 0xD234, 0xE010, 0xF234]
+        );
+        assert_eq!(
+            memory.find(&[0x5A, 0x5A]),
+            vec![0xC100, 0xC101, 0xE100, 0xE101]
+        );
+        assert!(memory.find(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_fill_crosses_into_echo_ram() {
+        let mut memory = Memory::new();
+        memory.fill(ECHO_RAM_START - 0x10, 0x20, 0xA5);
+
+        for address in ECHO_RAM_START - 0x10..ECHO_RAM_START + 0x10 {
+            assert_eq!(memory.get_data(address), 0xA5);
+        }
+        // the echo half of the fill landed in the start of working RAM
+        assert_eq!(memory.get_data(WORKING_RAM_START), 0xA5);
+        assert_eq!(memory.get_data(WORKING_RAM_START + 0x0F), 0xA5);
+        assert_eq!(memory.get_data(WORKING_RAM_START + 0x10), 0x00);
+    }
+
+    #[test]
+    fn test_fill_skips_rom() {
+        let mut memory = Memory::new();
+        memory.fill(TILE_RAM_START - 2, 4, 0x01);
+
+        assert_eq!(memory.get_data(TILE_RAM_START - 1), 0x00);
+        assert_eq!(memory.get_data(TILE_RAM_START), 0x01);
+        assert_eq!(memory.get_data(TILE_RAM_START + 1), 0x01);
+    }
+
+    #[test]
+    fn test_load_at_crosses_regions() {
+        let program = [0x01, 0x02, 0x03, 0x04, 0x05];
+        let start = TILE_RAM_START - 2;
+        let mut memory = Memory::new();
+
+        memory.load_at(start, &program);
+
+        for (offset, &byte) in program.iter().enumerate() {
+            assert_eq!(memory.get_data(start + offset as u16), byte);
+        }
+        assert_eq!(memory.video_ram[0][..3], program[2..]);
+    }
+
+    #[test]
+    fn test_out_of_range_rom_bank_wraps() {
+        let bank_count = 4;
+        let mut rom = vec![0; bank_count * cartridge::ROM_BANK_SIZE];
+        for bank in 0..bank_count {
+            rom[bank * cartridge::ROM_BANK_SIZE] = bank as u8;
+        }
+        // MBC1 with a 64KB ROM
+        rom[cartridge::CARTRIDGE_TYPE] = 0x01;
+        rom[cartridge::ROM_SIZE] = 0x01;
+
+        let mut memory = Memory::from_rom(rom).unwrap();
+        memory.set_byte(ROM_BANK_NUMBER_START, 7);
+        assert_eq!(memory.get_data(ROM_BANK_N_START), 3);
+    }
+
+    #[test]
+    fn test_truncated_rom_is_too_small() {
+        let rom = vec![0; cartridge::HEADER_CHECKSUM];
+        assert!(matches!(Memory::from_rom(rom), Err(RomLoadError::TooSmall)));
+    }
+
+    #[test]
+    fn test_bad_header_checksum() {
+        let mut rom = vec![0; 2 * cartridge::ROM_BANK_SIZE];
+        rom[cartridge::HEADER_CHECKSUM] = cartridge::header_checksum(&rom).wrapping_add(1);
+
+        assert!(matches!(
+            Memory::load_rom(rom.clone(), true),
+            Err(RomLoadError::BadHeaderChecksum)
+        ));
+
+        // without verification the ROM still loads, but the mismatch is kept
+        let memory = Memory::load_rom(rom.clone(), false).unwrap();
+        assert!(!memory.header_checksum_valid());
+
+        rom[cartridge::HEADER_CHECKSUM] = cartridge::header_checksum(&rom);
+        assert!(Memory::load_rom(rom, true).unwrap().header_checksum_valid());
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        let mut rom = vec![0; 2 * cartridge::ROM_BANK_SIZE];
+        rom[cartridge::CARTRIDGE_TYPE] = 0xFC;
+
+        assert!(matches!(
+            Memory::from_rom(rom),
+            Err(RomLoadError::UnsupportedMapper(0xFC))
+        ));
+    }
+
+    #[test]
+    fn test_ram_size_from_header_code() {
+        let expected_sizes = [
+            (0x00, 0),
+            (0x01, 0x800),
+            (0x02, 0x2000),
+            (0x03, 0x8000),
+            (0x04, 0x20000),
+            (0x05, 0x10000),
+        ];
+
+        for (code, size) in expected_sizes {
+            assert_eq!(Memory::with_ram_size(code).cartridge_ram.len(), size);
+        }
+    }
+
+    #[test]
+    fn test_missing_cartridge_ram_reads_ff() {
+        let mut rom = vec![0; 2 * cartridge::ROM_BANK_SIZE];
+        rom[cartridge::CARTRIDGE_TYPE] = 0x01;
+        let mut memory = Memory::from_rom(rom).unwrap();
+
+        memory.set_byte(RAM_ENABLE_START, 0x0A);
+        memory.set_byte(CARTRIDGE_RAM_START, 0x42);
+        assert_eq!(memory.get_data(CARTRIDGE_RAM_START), 0xFF);
+    }
+
+    #[test]
+    fn test_cartridge_ram_enable() {
+        let mut rom = vec![0; 2 * cartridge::ROM_BANK_SIZE];
+        // MBC1 with 8KB of RAM
+        rom[cartridge::CARTRIDGE_TYPE] = 0x02;
+        rom[cartridge::RAM_SIZE] = 0x02;
+        let mut memory = Memory::from_rom(rom).unwrap();
+
+        assert_eq!(memory.get_data(CARTRIDGE_RAM_START), 0xFF);
+
+        memory.set_byte(RAM_ENABLE_START, 0x0A);
+        memory.set_byte(CARTRIDGE_RAM_START, 0x42);
+        assert_eq!(memory.get_data(CARTRIDGE_RAM_START), 0x42);
+
+        memory.set_byte(RAM_ENABLE_START, 0x00);
+        memory.set_byte(CARTRIDGE_RAM_START, 0x13);
+        assert_eq!(memory.get_data(CARTRIDGE_RAM_START), 0xFF);
+
+        memory.set_byte(RAM_ENABLE_START, 0x0A);
+        assert_eq!(memory.get_data(CARTRIDGE_RAM_START), 0x42);
+    }
+
+    #[test]
+    fn test_unused_region_ignores_writes() {
+        let mut memory = Memory::new();
+        memory.set_byte(UNUSED_START, 0x42);
+        assert_eq!(memory.get_data(UNUSED_START), 0x00);
+
+        memory.set_model(Model::Cgb);
+        memory.set_byte(IO_REGISTERS - 1, 0x42);
+        assert_eq!(memory.get_data(UNUSED_START), 0xFF);
+        assert_eq!(memory.get_data(IO_REGISTERS - 1), 0xFF);
+    }
+
+    #[test]
+    fn test_typed_io_accessors() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(0x91);
+        assert_eq!(memory.get_data(0xFF40), 0x91);
+
+        memory.set_byte(0xFF43, 0x12);
+        assert_eq!(memory.scx(), 0x12);
+
+        memory.set_wx(0x07);
+        assert_eq!(memory.get_data(0xFF4B), 0x07);
+    }
+
+    #[test]
+    fn test_timer_reload_requests_interrupt() {
+        let mut memory = Memory::new();
+        memory.timer_mut().set_div_counter(0);
+        memory.set_tac(0b101);
+        memory.set_byte(timer::TIMA, 0xFF);
+
+        memory.step_timer(16);
+        assert_eq!(memory.interrupt_flag() & TIMER_INTERRUPT, 0);
+
+        memory.step_timer(4);
+        assert_eq!(memory.interrupt_flag() & TIMER_INTERRUPT, TIMER_INTERRUPT);
+    }
+
+    #[test]
+    fn test_access_map() {
+        let mut memory = Memory::new();
+        memory.get_data(0xC000);
+        memory.set_access_tracking(true);
+
+        memory.get_data(0xC123);
+        memory.set_byte(0xFF85, 0x42);
+
+        let (reads, writes) = memory.access_map();
+        assert_eq!(reads.count(), 1);
+        assert!(reads.contains(0xC123));
+        assert_eq!(writes.count(), 1);
+        assert!(writes.contains(0xFF85));
+
+        memory.reset_access_map();
+        assert_eq!(memory.access_map().0.count(), 0);
+    }
+
+    #[test]
+    fn test_boot_rom_disable_register() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0x11;
+        let mut memory = Memory::from_rom(rom).unwrap();
+        memory.load_boot_rom(vec![0x31; 0x100]);
+
+        assert_eq!(memory.get_data(0x0000), 0x31);
+        assert_eq!(memory.get_data(BOOT_ROM_DISABLE), 0xFE);
+
+        memory.set_byte(BOOT_ROM_DISABLE, 0x01);
+        assert!(!memory.boot_rom_mapped());
+        assert_eq!(memory.get_data(0x0000), 0x11);
+        assert_eq!(memory.get_data(BOOT_ROM_DISABLE), 0xFF);
+    }
+
+    #[test]
+    fn test_oam_sprites() {
+        let mut memory = Memory::new();
+        let sprite = Sprite {
+            y: 0x20,
+            x: 0x18,
+            tile: 0x05,
+            flags: 0x30,
+        };
+        memory.set_oam_sprite(3, sprite);
+
+        let start = OAM_START + 3 * BYTES_PER_SPRITE as u16;
+        assert_eq!(memory.get_data(start), 0x20);
+        assert_eq!(memory.get_data(start + 1), 0x18);
+        assert_eq!(memory.get_data(start + 2), 0x05);
+        assert_eq!(memory.get_data(start + 3), 0x30);
+        assert_eq!(memory.oam_sprites()[3], sprite);
+        assert_eq!(memory.oam_sprites()[2], Sprite::default());
+    }
+
+    #[test]
+    fn test_reads_blocked_by_ppu_mode() {
+        let mut memory = Memory::new();
+        memory.set_byte(TILE_RAM_START, 0x42);
+        memory.set_byte(OAM_START, 0x24);
+        memory.set_byte(HIGH_RAM_START, 0x11);
+
+        memory.set_ppu_state(0, Mode::Drawing as u8);
+        assert_eq!(memory.get_data(TILE_RAM_START), 0xFF);
+        assert_eq!(memory.get_data(OAM_START), 0xFF);
+        assert_eq!(memory.get_data(HIGH_RAM_START), 0x11);
+
+        memory.set_ppu_state(0, Mode::OamScan as u8);
+        assert_eq!(memory.get_data(TILE_RAM_START), 0x42);
+        assert_eq!(memory.get_data(OAM_START), 0xFF);
+
+        memory.set_ppu_state(0, Mode::HBlank as u8);
+        assert_eq!(memory.get_data(OAM_START), 0x24);
+    }
+
+    #[test]
+    fn test_stat_mode_bits_are_read_only() {
+        let mut memory = Memory::new();
+        memory.set_ppu_state(0, Mode::Drawing as u8);
+
+        memory.set_stat(0b0100_0000);
+        assert_eq!(memory.stat() & 0b0100_0011, 0b0100_0011);
+    }
+
+    #[test]
+    fn test_stat_unused_bit_reads_set() {
+        let mut memory = Memory::new();
+        memory.set_stat(0b0111_1000);
+        memory.set_ppu_state(0, Mode::Drawing as u8);
+
+        memory.set_byte(LCD_STATUS, 0x00);
+
+        // LY and LYC are both 0, so the coincidence bit is set too
+        assert_eq!(
+            memory.get_data(LCD_STATUS),
+            STAT_UNUSED | STAT_COINCIDENCE | Mode::Drawing as u8
+        );
+    }
+
+    // remembers every write, and reads back the last value written anywhere
+    struct RecordingDevice {
+        writes: Rc<RefCell<Vec<(u16, u8)>>>,
+    }
+
+    impl IoHandler for RecordingDevice {
+        fn read(&self, address: u16) -> u8 {
+            self.writes
+                .borrow()
+                .last()
+                .map_or(address as u8, |&(_, value)| value)
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.writes.borrow_mut().push((address, value));
+        }
+    }
+
+    #[test]
+    fn test_map_io() {
+        let mut memory = Memory::new();
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        memory.map_io(
+            SERIAL_DATA..=SERIAL_CONTROL,
+            Box::new(RecordingDevice {
+                writes: writes.clone(),
+            }),
+        );
+
+        assert_eq!(memory.get_data(SERIAL_DATA), 0x01);
+        memory.set_byte(SERIAL_DATA, 0x42);
+        memory.set_byte(SERIAL_CONTROL, SERIAL_TRANSFER_START);
+        assert_eq!(memory.get_data(SERIAL_DATA), SERIAL_TRANSFER_START);
+        assert_eq!(
+            *writes.borrow(),
+            [(SERIAL_DATA, 0x42), (SERIAL_CONTROL, SERIAL_TRANSFER_START)]
+        );
+        // the built in serial port never saw the transfer
+        assert!(memory.take_serial_output().is_empty());
+
+        // registers outside the range are untouched
+        memory.set_byte(timer::TMA, 0x12);
+        assert_eq!(memory.get_data(timer::TMA), 0x12);
+        assert_eq!(writes.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_set_tile_encodes_rows() {
+        let mut memory = Memory::new();
+        let mut checkerboard = [[0; 8]; 8];
+        for (y, row) in checkerboard.iter_mut().enumerate() {
+            for (x, color) in row.iter_mut().enumerate() {
+                *color = if (x + y).is_multiple_of(2) { 1 } else { 2 };
+            }
+        }
+
+        memory.set_tile(2, &checkerboard);
+        memory.set_bg_map_entry(3, 1, 2);
+
+        let tile = TILE_RAM_START + 2 * 16;
+        assert_eq!(memory.video_ram(tile), 0b1010_1010);
+        assert_eq!(memory.video_ram(tile + 1), 0b0101_0101);
+        assert_eq!(memory.video_ram(tile + 2), 0b0101_0101);
+        assert_eq!(memory.video_ram(tile + 3), 0b1010_1010);
+        assert_eq!(memory.video_ram(tile + 16), 0x00);
+        assert_eq!(memory.video_ram(BACKGROUND_MAP_START + 32 + 3), 2);
+    }
+
+    #[test]
+    fn test_watch_read_reports_value() {
+        let mut memory = Memory::new();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let recorder = seen.clone();
+        memory.watch_read(JOYPAD_REGISTER, move |address, value| {
+            recorder.borrow_mut().push((address, value))
+        });
+
+        let value = memory.get_data(JOYPAD_REGISTER);
+        memory.get_data(JOYPAD_REGISTER + 1);
+        memory.peek(JOYPAD_REGISTER);
+
+        assert_eq!(*seen.borrow(), [(JOYPAD_REGISTER, value)]);
+
+        memory.clear_read_watches();
+        memory.get_data(JOYPAD_REGISTER);
+        assert_eq!(seen.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_ie_keeps_every_bit() {
+        let mut memory = Memory::new();
+        memory.set_byte(INTERRUPT_ENABLE_REGISTER, 0x1F);
+        assert_eq!(memory.get_data(INTERRUPT_ENABLE_REGISTER), 0x1F);
+        assert_eq!(memory.ie(), 0x1F);
+
+        memory.set_ie(0xFF);
+        assert_eq!(memory.get_data(INTERRUPT_ENABLE_REGISTER), 0xFF);
+        memory.request_interrupt(TIMER_INTERRUPT);
+        assert_eq!(memory.pending_interrupts(), TIMER_INTERRUPT);
+
+        // IE stays reachable while OAM DMA has the bus
+        memory.set_byte(DMA, 0xC0);
+        assert_eq!(memory.get_data(INTERRUPT_ENABLE_REGISTER), 0xFF);
+    }
+
+    #[test]
+    fn test_post_boot_state() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+
+        assert_eq!(memory.get_data(apu::NR52), 0xF1);
+        assert_eq!(memory.get_data(apu::NR51), 0xF3);
+        assert_eq!(memory.lcdc(), 0x91);
+        assert_eq!(memory.bgp(), 0xFC);
+        assert_eq!(memory.tac(), 0xF8);
+        assert_eq!(memory.interrupt_flag(), 0xE1);
+        assert_eq!(memory.get_data(timer::DIV), 0xAB);
+        assert_eq!(memory.get_data(DMA), 0xFF);
+        assert!(!memory.dma_active());
+    }
+
+    #[test]
+    fn test_palette_ram_auto_increment() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+        let color: u16 = 0x2D6B;
+
+        // color 1 of background palette 2
+        memory.set_byte(BCPS, PALETTE_AUTO_INCREMENT | 0x12);
+        memory.set_byte(BCPD, color as u8);
+        memory.set_byte(BCPD, (color >> 8) as u8);
+
+        assert_eq!(memory.bg_palette_ram()[0x12], 0x6B);
+        assert_eq!(memory.bg_palette_ram()[0x13], 0x2D);
+        assert_eq!(memory.get_data(BCPS), 0xC0 | 0x14);
+        assert_eq!(memory.obj_palette_ram(), &[0; PALETTE_RAM_SIZE]);
+
+        memory.set_byte(BCPS, 0x12);
+        assert_eq!(memory.get_data(BCPD), 0x6B);
+        memory.set_byte(BCPS, 0x13);
+        assert_eq!(memory.get_data(BCPD), 0x2D);
+    }
+
+    #[test]
+    fn test_vbk_selects_vram_bank() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+        let address = BACKGROUND_MAP_START + 0x21;
+
+        memory.set_byte(address, 0x11);
+        memory.set_byte(VBK, 0x01);
+        memory.set_byte(address, 0x22);
+
+        assert_eq!(memory.get_data(VBK), 0xFF);
+        assert_eq!(memory.get_data(address), 0x22);
+        memory.set_byte(VBK, 0x00);
+        assert_eq!(memory.get_data(VBK), 0xFE);
+        assert_eq!(memory.get_data(address), 0x11);
+
+        assert_eq!(memory.video_ram_in_bank(0, address), 0x11);
+        assert_eq!(memory.video_ram_in_bank(1, address), 0x22);
+    }
+
+    #[test]
+    fn test_svbk_selects_wram_bank() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+
+        memory.set_byte(SVBK, 2);
+        memory.set_byte(WRAM_SWITCHABLE_START, 0x22);
+        memory.set_byte(SVBK, 3);
+        memory.set_byte(WRAM_SWITCHABLE_START, 0x33);
+
+        assert_eq!(memory.get_data(WRAM_SWITCHABLE_START), 0x33);
+        memory.set_byte(SVBK, 2);
+        assert_eq!(memory.get_data(WRAM_SWITCHABLE_START), 0x22);
+        assert_eq!(memory.get_data(SVBK), 0xFA);
+        // echo RAM follows the selected bank
+        assert_eq!(
+            memory.get_data(ECHO_RAM_START + (WRAM_SWITCHABLE_START - WORKING_RAM_START)),
+            0x22
+        );
+    }
+
+    #[test]
+    fn test_svbk_zero_selects_bank_one() {
+        let mut memory = Memory::new();
+        memory.set_byte(WRAM_SWITCHABLE_START, 0x11);
+        memory.set_model(Model::Cgb);
+
+        memory.set_byte(SVBK, 0);
+        assert_eq!(memory.get_data(WRAM_SWITCHABLE_START), 0x11);
+        memory.set_byte(SVBK, 1);
+        assert_eq!(memory.get_data(WRAM_SWITCHABLE_START), 0x11);
+        memory.set_byte(SVBK, 4);
+        assert_eq!(memory.get_data(WRAM_SWITCHABLE_START), 0x00);
+        // bank 0 doesn't move
+        memory.set_byte(WORKING_RAM_START, 0x44);
+        memory.set_byte(SVBK, 5);
+        assert_eq!(memory.get_data(WORKING_RAM_START), 0x44);
+    }
+
+    #[test]
+    fn test_general_hdma_copies_to_vram() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+        for offset in 0..0x40 {
+            memory.set_byte(WORKING_RAM_START + offset, offset as u8 ^ 0x5A);
+        }
+
+        memory.set_byte(HDMA1, 0xC0);
+        memory.set_byte(HDMA2, 0x00);
+        memory.set_byte(HDMA3, 0x81);
+        memory.set_byte(HDMA4, 0x00);
+        memory.set_byte(HDMA5, 0x03);
+
+        for offset in 0..0x40 {
+            assert_eq!(
+                memory.video_ram(0x8100 + offset),
+                offset as u8 ^ 0x5A,
+                "offset {:#X}",
+                offset
+            );
+        }
+        assert_eq!(memory.video_ram(0x8140), 0x00);
+        assert_eq!(memory.get_data(HDMA5), 0xFF);
+    }
+
+    #[test]
+    fn test_hblank_hdma_copies_a_block_per_hblank() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+        memory.fill(WORKING_RAM_START, 0x20, 0x77);
+
+        memory.set_byte(HDMA1, 0xC0);
+        memory.set_byte(HDMA2, 0x00);
+        memory.set_byte(HDMA3, 0x00);
+        memory.set_byte(HDMA4, 0x00);
+        memory.set_byte(HDMA5, HDMA_HBLANK_MODE | 0x01);
+        assert_eq!(memory.get_data(HDMA5), 0x01);
+        assert_eq!(memory.video_ram(TILE_RAM_START), 0x00);
+
+        memory.hblank_started();
+        assert_eq!(memory.get_data(HDMA5), 0x00);
+        assert_eq!(memory.video_ram(TILE_RAM_START + 15), 0x77);
+        assert_eq!(memory.video_ram(TILE_RAM_START + 16), 0x00);
+
+        memory.hblank_started();
+        assert_eq!(memory.get_data(HDMA5), 0xFF);
+        assert_eq!(memory.video_ram(TILE_RAM_START + 31), 0x77);
+
+        memory.hblank_started();
+        assert_eq!(memory.video_ram(TILE_RAM_START + 32), 0x00);
+    }
+
+    #[test]
+    fn test_hblank_hdma_can_be_stopped() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+
+        memory.set_byte(HDMA5, HDMA_HBLANK_MODE | 0x05);
+        memory.hblank_started();
+        memory.set_byte(HDMA5, 0x00);
+
+        assert_eq!(memory.get_data(HDMA5), HDMA_HBLANK_MODE | 0x04);
+    }
+
+    #[test]
+    fn test_vbk_ignored_on_dmg() {
+        let mut memory = Memory::new();
+        memory.set_byte(VBK, 0x01);
+        memory.set_byte(TILE_RAM_START, 0x33);
+
+        assert_eq!(memory.get_data(VBK), 0xFF);
+        assert_eq!(memory.video_ram_in_bank(0, TILE_RAM_START), 0x33);
+        assert_eq!(memory.video_ram_in_bank(1, TILE_RAM_START), 0x00);
+    }
+
+    #[test]
+    fn test_palette_index_wraps() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+
+        memory.set_byte(OCPS, PALETTE_AUTO_INCREMENT | PALETTE_INDEX);
+        memory.set_byte(OCPD, 0x7F);
+        memory.set_byte(OCPD, 0x11);
+
+        assert_eq!(memory.obj_palette_ram()[PALETTE_RAM_SIZE - 1], 0x7F);
+        assert_eq!(memory.obj_palette_ram()[0], 0x11);
+    }
+
+    #[test]
+    fn test_nr52_reports_active_channels() {
+        let mut memory = Memory::new();
+        memory.set_byte(apu::NR52, 0x80);
+        memory.set_byte(apu::NR12, 0xF0);
+        memory.set_byte(apu::NR14, 0x80);
+        assert_eq!(memory.get_data(apu::NR52), 0xF1);
+        assert_eq!(memory.apu().active_channels(), [true, false, false, false]);
+
+        memory.set_byte(apu::NR52, 0x00);
+        assert_eq!(memory.get_data(apu::NR52), 0x70);
+        assert_eq!(memory.get_data(apu::NR12), 0x00);
+    }
+
+    #[test]
+    fn test_pcm_registers_only_on_cgb() {
+        let mut memory = Memory::new();
+        memory.set_byte(apu::NR52, 0x80);
+        memory.set_byte(apu::NR11, 0x80);
+        memory.set_byte(apu::NR12, 0xF0);
+        memory.set_byte(apu::NR14, 0x80);
+        assert_eq!(memory.get_data(apu::PCM12), 0xFF);
+
+        memory.set_model(Model::Cgb);
+        memory.set_byte(apu::PCM12, 0x00);
+        assert_eq!(memory.get_data(apu::PCM12), 0x0F);
+        assert_eq!(memory.get_data(apu::PCM34), 0x00);
+    }
+
+    #[test]
+    fn test_dma_copies_one_byte_per_machine_cycle() {
+        let mut memory = Memory::new();
+        for offset in 0..DMA_LENGTH {
+            memory.set_byte(WORKING_RAM_START + offset, offset as u8 + 1);
+        }
+
+        memory.set_byte(DMA, 0xC0);
+        memory.step_dma(10 * CYCLES_PER_DMA_BYTE as u8 + 3);
+
+        let oam = memory.object_attribute_memory();
+        assert_eq!(oam[..10], [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert!(oam[10..].iter().all(|&byte| byte == 0));
+
+        // the leftover cycles count towards the next byte
+        memory.step_dma(1);
+        assert_eq!(memory.object_attribute_memory()[10], 11);
+    }
+
+    #[test]
+    fn test_dma_progress_survives_save_state() {
+        let mut memory = Memory::new();
+        memory.fill(WORKING_RAM_START, DMA_LENGTH as usize, 0x77);
+        memory.set_byte(DMA, 0xC0);
+        memory.step_dma(80);
+
+        let mut writer = StateWriter::new();
+        memory.save_state(&mut writer);
+        let state = writer.finish();
+
+        let mut restored = Memory::new();
+        restored
+            .load_state(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert!(restored.dma_active());
+        assert_eq!(restored.object_attribute_memory()[19], 0x77);
+        assert_eq!(restored.object_attribute_memory()[20], 0x00);
+
+        for _ in 0..3 {
+            restored.step_dma(200);
+        }
+        assert!(!restored.dma_active());
+        assert!(restored
+            .object_attribute_memory()
+            .iter()
+            .all(|&byte| byte == 0x77));
+    }
+
+    #[test]
+    fn test_reads_blocked_during_dma() {
+        let mut memory = Memory::new();
+        memory.set_byte(0xC000, 0x42);
+        memory.set_byte(0xC09F, 0x13);
+        memory.set_byte(HIGH_RAM_START, 0x24);
+
+        memory.set_byte(DMA, 0xC0);
+        memory.step_dma(8);
+        assert!(memory.dma_active());
+        assert_eq!(memory.get_data(0xC000), 0xFF);
+        assert_eq!(memory.get_data(HIGH_RAM_START), 0x24);
+
+        memory.step_dma(152);
+        memory.step_dma(255);
+        memory.step_dma(225);
+        assert!(!memory.dma_active());
+        assert_eq!(memory.get_data(0xC000), 0x42);
+        assert_eq!(memory.get_data(OAM_START), 0x42);
+        assert_eq!(memory.get_data(UNUSED_START - 1), 0x13);
+    }
+
+    #[test]
+    fn test_serial_transfer() {
+        let mut memory = Memory::new();
+        memory.set_byte(SERIAL_DATA, b'O');
+        memory.set_byte(SERIAL_CONTROL, SERIAL_TRANSFER_START);
+        memory.set_byte(SERIAL_DATA, b'K');
+        memory.set_byte(SERIAL_CONTROL, SERIAL_TRANSFER_START);
+
+        assert_eq!(memory.take_serial_output(), b"OK");
+        assert_eq!(memory.get_data(SERIAL_CONTROL) & 0x80, 0);
+        assert!(memory.take_serial_output().is_empty());
     }
 }
@@ -0,0 +1,21 @@
+// The hardware revision being emulated, for the places where revisions
+// behave differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+    // the Super Game Boy, which is a DMG as far as the cartridge can tell
+    // apart from what the boot ROM leaves in the registers
+    Sgb,
+}
+
+impl Model {
+    // the value read back from the unused 0xFEA0-0xFEFF region
+    pub fn unused_region_value(self: Self) -> u8 {
+        match self {
+            Model::Dmg | Model::Sgb => 0x00,
+            Model::Cgb => 0xFF,
+        }
+    }
+}
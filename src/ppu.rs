@@ -0,0 +1,1105 @@
+use crate::memory::{self, Memory};
+use crate::model::Model;
+use crate::sprite::{BYTES_PER_SPRITE, SPRITE_COUNT};
+use crate::state::{StateError, StateReader, StateWriter};
+use alloc::format;
+use alloc::vec::Vec;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// Bits of the LCDC register
+const LCDC_ENABLE: u8 = 0b1000_0000;
+const LCDC_BACKGROUND_ENABLE: u8 = 0b0000_0001;
+const LCDC_SPRITE_ENABLE: u8 = 0b0000_0010;
+const LCDC_SPRITE_SIZE: u8 = 0b0000_0100;
+const LCDC_BACKGROUND_MAP: u8 = 0b0000_1000;
+const LCDC_TILE_DATA: u8 = 0b0001_0000;
+const LCDC_WINDOW_ENABLE: u8 = 0b0010_0000;
+const LCDC_WINDOW_MAP: u8 = 0b0100_0000;
+
+// WX holds the window's x position plus 7
+const WINDOW_X_OFFSET: u8 = 7;
+
+// Base addresses for the two tile data addressing modes
+const UNSIGNED_TILE_DATA_START: u16 = 0x8000;
+const SIGNED_TILE_DATA_START: u16 = 0x9000;
+
+// Base addresses of the two background tile maps
+const TILE_MAP_0_START: u16 = 0x9800;
+const TILE_MAP_1_START: u16 = 0x9C00;
+const TILE_MAP_WIDTH: u16 = 32;
+
+// Bits of the sprite attribute byte
+const SPRITE_Y_FLIP: u8 = 0b0100_0000;
+const SPRITE_X_FLIP: u8 = 0b0010_0000;
+const SPRITE_PALETTE: u8 = 0b0001_0000;
+// set when the sprite should only show over background color 0
+const SPRITE_BEHIND_BACKGROUND: u8 = 0b1000_0000;
+
+// on CGB, bit 7 of a tile's attribute byte in VRAM bank 1 draws it over
+// every sprite. Line colors keep the flag in the same bit
+const TILE_PRIORITY: u8 = 0b1000_0000;
+const COLOR_MASK: u8 = 0b11;
+
+const BYTES_PER_TILE: u16 = 16;
+
+// OAM stores sprite positions offset so they can be partially off screen
+const SPRITE_Y_OFFSET: i16 = 16;
+const SPRITE_X_OFFSET: i16 = 8;
+
+// The gray level shown for each shade, from lightest to darkest
+pub const DEFAULT_SHADE_RAMP: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+// the hardware only draws the first 10 sprites it finds on a line
+const DEFAULT_SPRITE_LIMIT: u8 = 10;
+
+// Timing of each scanline, in dots (one dot per cycle)
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+// drawing takes at least this long, and is stretched by the fetcher
+// stalling for fine scrolling, the window and sprites
+const DRAWING_DOTS: u16 = 172;
+const WINDOW_PENALTY_DOTS: u16 = 6;
+const SPRITE_PENALTY_DOTS: u16 = 6;
+// the first sprite over a background tile also waits for up to this many
+// dots of that tile's fetch to finish
+const SPRITE_TILE_PENALTY_DOTS: u16 = 5;
+// lines 144-153 make up vertical blank
+const LINES_PER_FRAME: u8 = 154;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+// A snapshot of where the PPU is within the frame, for debuggers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpuDebugState {
+    pub mode: Mode,
+    pub ly: u8,
+    // dots since the start of the line
+    pub dot: u16,
+    // how many pixels of the line have been pushed out. Lines are rendered
+    // all at once when drawing ends, so this is where a fetcher pushing a
+    // pixel every dot of mode 3 would be
+    pub fetcher_x: u8,
+}
+
+#[derive(Clone)]
+pub struct Ppu {
+    // the shade (0-3) of every pixel on the screen
+    framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // how many sprites can be drawn on a single line, None removes the
+    // limit which avoids the flicker games use to work around it
+    sprite_limit: Option<u8>,
+    // the gray level each shade is converted to RGB as
+    shade_ramp: [u8; 4],
+    // the line being drawn and how many dots into it we are
+    line: u8,
+    dot: u16,
+    mode: Mode,
+    // how long drawing lasts on the current line
+    drawing_dots: u16,
+    // the row of the window to draw next, which only advances on lines the
+    // window was actually drawn on so it can't be derived from LY
+    window_line: u8,
+    // the lines whose pixels changed while drawing the frame in progress,
+    // and what that was for the last finished frame
+    changed_lines: [bool; SCREEN_HEIGHT],
+    dirty_lines: [bool; SCREEN_HEIGHT],
+    // the background or window color number (0-3) under each pixel of the
+    // line being drawn, before the palette, which sprite priority checks
+    line_colors: [u8; SCREEN_WIDTH],
+    // the OAM indices of the sprites picked for the last rendered line, in
+    // priority order
+    scanline_sprites: Vec<u8>,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            sprite_limit: Some(DEFAULT_SPRITE_LIMIT),
+            shade_ramp: DEFAULT_SHADE_RAMP,
+            line: 0,
+            dot: 0,
+            mode: Mode::OamScan,
+            drawing_dots: DRAWING_DOTS,
+            window_line: 0,
+            // the host hasn't seen any of the first frame yet
+            changed_lines: [true; SCREEN_HEIGHT],
+            dirty_lines: [false; SCREEN_HEIGHT],
+            line_colors: [0; SCREEN_WIDTH],
+            scanline_sprites: Vec::new(),
+        }
+    }
+
+    pub fn mode(self: &Self) -> Mode {
+        self.mode
+    }
+
+    pub fn debug_state(self: &Self) -> PpuDebugState {
+        let fetcher_x = match self.mode {
+            Mode::Drawing => self.dot - OAM_SCAN_DOTS,
+            Mode::HBlank if self.dot >= OAM_SCAN_DOTS => SCREEN_WIDTH as u16,
+            _ => 0,
+        };
+        PpuDebugState {
+            mode: self.mode,
+            ly: self.line,
+            dot: self.dot,
+            fetcher_x: fetcher_x.min(SCREEN_WIDTH as u16) as u8,
+        }
+    }
+
+    pub fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_u8(self.line);
+        writer.write_u16(self.dot);
+        writer.write_u8(self.mode as u8);
+        writer.write_u16(self.drawing_dots);
+        writer.write_u8(self.window_line);
+        writer.write_bytes(&self.framebuffer);
+    }
+
+    pub fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.line = reader.read_u8()?;
+        self.dot = reader.read_u16()?;
+        self.mode = match reader.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            _ => return Err(StateError::InvalidValue),
+        };
+        self.drawing_dots = reader.read_u16()?;
+        self.window_line = reader.read_u8()?;
+        reader.read_bytes(&mut self.framebuffer)
+    }
+
+    // advances the PPU by the given number of cycles, returning true if a
+    // frame was completed and the PPU has entered vertical blank
+    pub fn step(self: &mut Self, memory: &mut Memory, cycles: u8) -> bool {
+        if memory.lcdc() & LCDC_ENABLE == 0 {
+            // the PPU sits at the start of the frame while the LCD is off
+            self.line = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+            memory.set_ppu_state(self.line, self.mode as u8);
+            return false;
+        }
+
+        let mut frame_ready = false;
+        for _ in 0..cycles {
+            frame_ready |= self.advance_dot(memory);
+        }
+        frame_ready
+    }
+
+    // advances a single dot, for debugging that needs to see every mode
+    // change as it happens
+    pub fn step_dot(self: &mut Self, memory: &mut Memory) -> bool {
+        self.step(memory, 1)
+    }
+
+    fn advance_dot(self: &mut Self, memory: &mut Memory) -> bool {
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.line = (self.line + 1) % LINES_PER_FRAME;
+        }
+        if self.dot == OAM_SCAN_DOTS && (self.line as usize) < SCREEN_HEIGHT {
+            self.drawing_dots = self.drawing_length(memory);
+        }
+
+        let mode = if self.line as usize >= SCREEN_HEIGHT {
+            Mode::VBlank
+        } else if self.dot < OAM_SCAN_DOTS {
+            Mode::OamScan
+        } else if self.dot < OAM_SCAN_DOTS + self.drawing_dots {
+            Mode::Drawing
+        } else {
+            Mode::HBlank
+        };
+
+        let mut frame_ready = false;
+        if mode != self.mode {
+            match mode {
+                // the line is finished once drawing ends
+                Mode::HBlank => {
+                    self.render_scanline(memory, self.line);
+                    memory.hblank_started();
+                }
+                Mode::VBlank => {
+                    self.window_line = 0;
+                    self.dirty_lines = self.changed_lines;
+                    self.changed_lines = [false; SCREEN_HEIGHT];
+                    memory.request_interrupt(memory::VBLANK_INTERRUPT);
+                    frame_ready = true;
+                }
+                _ => {}
+            }
+            self.mode = mode;
+        }
+
+        memory.set_ppu_state(self.line, self.mode as u8);
+        frame_ready
+    }
+
+    // how many dots drawing the current line takes, which decides when
+    // H-blank and everything triggered by it starts
+    fn drawing_length(self: &Self, memory: &Memory) -> u16 {
+        let scroll_x = memory.scx();
+        // the first tile's pixels that are scrolled off are still fetched
+        let mut dots = DRAWING_DOTS + (scroll_x % 8) as u16;
+
+        let lcdc = memory.lcdc();
+        if lcdc & LCDC_BACKGROUND_ENABLE != 0
+            && lcdc & LCDC_WINDOW_ENABLE != 0
+            && self.line >= memory.wy()
+            && (memory.wx() as usize) < SCREEN_WIDTH + WINDOW_X_OFFSET as usize
+        {
+            dots += WINDOW_PENALTY_DOTS;
+        }
+
+        // sprites aren't fetched at all while they're turned off
+        if lcdc & LCDC_SPRITE_ENABLE == 0 {
+            return dots;
+        }
+
+        let oam = memory.object_attribute_memory();
+        let mut stalled_tiles = Vec::new();
+        for index in self.select_sprites(memory, self.line) {
+            let x = oam[index * BYTES_PER_SPRITE + 1] as u16;
+            // sprites entirely past the right edge are never fetched
+            if x >= SCREEN_WIDTH as u16 + SPRITE_X_OFFSET as u16 {
+                continue;
+            }
+            dots += SPRITE_PENALTY_DOTS;
+
+            let pixel = x + scroll_x as u16;
+            let tile = pixel / 8;
+            if !stalled_tiles.contains(&tile) {
+                stalled_tiles.push(tile);
+                dots += SPRITE_TILE_PENALTY_DOTS.saturating_sub(pixel % 8);
+            }
+        }
+
+        dots
+    }
+
+    pub fn set_sprite_limit(self: &mut Self, limit: Option<u8>) {
+        self.sprite_limit = limit;
+    }
+
+    // lets users brighten the darker shades or flatten the contrast of the
+    // RGB output. The shades in the framebuffer are left alone
+    pub fn set_shade_ramp(self: &mut Self, ramp: [u8; 4]) {
+        self.shade_ramp = ramp;
+    }
+
+    fn shade_rgb(self: &Self, shade: u8) -> [u8; 3] {
+        let level = self.shade_ramp[shade as usize];
+        [level, level, level]
+    }
+
+    // the shade of every pixel, 0 to 3, for front-ends with their own palette
+    pub fn framebuffer_indices(self: &Self) -> &[u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
+        &self.framebuffer
+    }
+
+    // fills out with 4 bytes of opaque RGBA for every pixel
+    pub fn framebuffer_rgba(self: &Self, out: &mut [u8]) {
+        assert_eq!(
+            out.len(),
+            self.framebuffer.len() * 4,
+            "the RGBA buffer must hold {}x{} pixels",
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT
+        );
+        for (pixel, &shade) in out.chunks_exact_mut(4).zip(self.framebuffer.iter()) {
+            let [r, g, b] = self.shade_rgb(shade);
+            pixel.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+    }
+
+    // the OAM index of every sprite selected for the most recently rendered
+    // line, after the sprite limit, with the one drawn on top first
+    pub fn scanline_sprites(self: &Self) -> Vec<u8> {
+        self.scanline_sprites.clone()
+    }
+
+    // which lines of the framebuffer differ from the frame before, so
+    // renderers can skip uploading the rest. Updated at each VBlank
+    pub fn dirty_lines(self: &Self) -> &[bool; SCREEN_HEIGHT] {
+        &self.dirty_lines
+    }
+
+    // the framebuffer as a binary PPM image, for screenshots and for
+    // comparing frames in tests without pulling in an image library
+    pub fn export_frame_ppm(self: &Self) -> Vec<u8> {
+        let header = format!("P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT);
+        let mut image = Vec::with_capacity(header.len() + self.framebuffer.len() * 3);
+        image.extend_from_slice(header.as_bytes());
+        for &shade in self.framebuffer.iter() {
+            image.extend_from_slice(&self.shade_rgb(shade));
+        }
+        image
+    }
+
+    pub fn render_scanline(self: &mut Self, memory: &Memory, line: u8) {
+        let start = line as usize * SCREEN_WIDTH;
+        let mut previous = [0; SCREEN_WIDTH];
+        previous.copy_from_slice(&self.framebuffer[start..start + SCREEN_WIDTH]);
+
+        self.framebuffer[start..start + SCREEN_WIDTH].fill(0);
+        self.line_colors.fill(0);
+        self.render_background(memory, line);
+        self.render_window(memory, line);
+        self.render_sprites(memory, line);
+
+        self.changed_lines[line as usize] |=
+            previous[..] != self.framebuffer[start..start + SCREEN_WIDTH];
+    }
+
+    // LCDC bit 4 picks between indexing tiles from 0x8000 with an unsigned
+    // index, or from 0x9000 with a signed index covering 0x8800-0x97FF
+    fn tile_data_address(lcdc: u8, tile_index: u8) -> u16 {
+        match lcdc & LCDC_TILE_DATA {
+            0 => SIGNED_TILE_DATA_START
+                .wrapping_add_signed(tile_index as i8 as i16 * BYTES_PER_TILE as i16),
+            _ => UNSIGNED_TILE_DATA_START + tile_index as u16 * BYTES_PER_TILE,
+        }
+    }
+
+    fn render_background(self: &mut Self, memory: &Memory, line: u8) {
+        let lcdc = memory.lcdc();
+        if lcdc & LCDC_BACKGROUND_ENABLE == 0 {
+            return;
+        }
+
+        let map_start = match lcdc & LCDC_BACKGROUND_MAP {
+            0 => TILE_MAP_0_START,
+            _ => TILE_MAP_1_START,
+        };
+        let palette = memory.bgp();
+        let y = line.wrapping_add(memory.scy());
+        let scroll_x = memory.scx();
+
+        for screen_x in 0..SCREEN_WIDTH {
+            let x = (screen_x as u8).wrapping_add(scroll_x);
+            let color = Ppu::map_color(memory, lcdc, map_start, x, y);
+
+            let shade = (palette >> (color * 2)) & 0b11;
+            self.framebuffer[line as usize * SCREEN_WIDTH + screen_x] = shade;
+            self.line_colors[screen_x] = color | Ppu::map_priority(memory, map_start, x, y);
+        }
+    }
+
+    fn render_window(self: &mut Self, memory: &Memory, line: u8) {
+        let lcdc = memory.lcdc();
+        if lcdc & LCDC_BACKGROUND_ENABLE == 0 || lcdc & LCDC_WINDOW_ENABLE == 0 {
+            return;
+        }
+
+        let window_y = memory.wy();
+        let window_x = memory.wx() as usize;
+        if line < window_y || window_x >= SCREEN_WIDTH + WINDOW_X_OFFSET as usize {
+            return;
+        }
+
+        let map_start = match lcdc & LCDC_WINDOW_MAP {
+            0 => TILE_MAP_0_START,
+            _ => TILE_MAP_1_START,
+        };
+        let palette = memory.bgp();
+        let y = self.window_line;
+
+        let left = window_x.saturating_sub(WINDOW_X_OFFSET as usize);
+        for screen_x in left..SCREEN_WIDTH {
+            let x = (screen_x + WINDOW_X_OFFSET as usize - window_x) as u8;
+            let color = Ppu::map_color(memory, lcdc, map_start, x, y);
+
+            let shade = (palette >> (color * 2)) & 0b11;
+            self.framebuffer[line as usize * SCREEN_WIDTH + screen_x] = shade;
+            self.line_colors[screen_x] = color | Ppu::map_priority(memory, map_start, x, y);
+        }
+
+        self.window_line += 1;
+    }
+
+    // the color number (0-3) at a pixel of the 256x256 map starting at map_start
+    fn map_color(memory: &Memory, lcdc: u8, map_start: u16, x: u8, y: u8) -> u8 {
+        let map_address = map_start + (y / 8) as u16 * TILE_MAP_WIDTH + (x / 8) as u16;
+        let tile_index = memory.video_ram(map_address);
+
+        let address = Ppu::tile_data_address(lcdc, tile_index) + (y % 8) as u16 * 2;
+        let low = memory.video_ram(address);
+        let high = memory.video_ram(address + 1);
+        let bit = 7 - (x % 8);
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    // the CGB tile priority flag of the map entry covering a pixel
+    fn map_priority(memory: &Memory, map_start: u16, x: u8, y: u8) -> u8 {
+        if memory.model() != Model::Cgb {
+            return 0;
+        }
+        let map_address = map_start + (y / 8) as u16 * TILE_MAP_WIDTH + (x / 8) as u16;
+        memory.video_ram_in_bank(1, map_address) & TILE_PRIORITY
+    }
+
+    // whether a sprite pixel with these attributes shows over the background
+    // or window pixel at x
+    fn sprite_visible(self: &Self, memory: &Memory, attributes: u8, x: usize) -> bool {
+        let background = self.line_colors[x];
+        if background & COLOR_MASK == 0 {
+            return true;
+        }
+        // with LCDC bit 0 clear a CGB gives every sprite priority
+        if memory.model() == Model::Cgb && memory.lcdc() & LCDC_BACKGROUND_ENABLE == 0 {
+            return true;
+        }
+        attributes & SPRITE_BEHIND_BACKGROUND == 0 && background & TILE_PRIORITY == 0
+    }
+
+    fn sprite_height(self: &Self, memory: &Memory) -> i16 {
+        match memory.lcdc() & LCDC_SPRITE_SIZE {
+            0 => 8,
+            _ => 16,
+        }
+    }
+
+    // returns the OAM index of every sprite that will be drawn on the line,
+    // in the order the hardware finds them
+    fn select_sprites(self: &Self, memory: &Memory, line: u8) -> Vec<usize> {
+        let oam = memory.object_attribute_memory();
+        let height = self.sprite_height(memory);
+        let mut sprites = Vec::new();
+
+        for index in 0..SPRITE_COUNT {
+            if let Some(limit) = self.sprite_limit {
+                if sprites.len() == limit as usize {
+                    break;
+                }
+            }
+
+            let top = oam[index * BYTES_PER_SPRITE] as i16 - SPRITE_Y_OFFSET;
+            if (top..top + height).contains(&(line as i16)) {
+                sprites.push(index);
+            }
+        }
+
+        sprites
+    }
+
+    fn render_sprites(self: &mut Self, memory: &Memory, line: u8) {
+        self.scanline_sprites.clear();
+        if memory.lcdc() & LCDC_SPRITE_ENABLE == 0 {
+            return;
+        }
+
+        let oam = memory.object_attribute_memory();
+        let height = self.sprite_height(memory);
+        let mut sprites = self.select_sprites(memory, line);
+
+        // the sprite with the smallest x coordinate wins, with ties going to
+        // the lowest OAM index. The winner claims the pixel even when it ends
+        // up hidden behind the background, so lower sprites can't show there
+        sprites.sort_by_key(|&index| oam[index * BYTES_PER_SPRITE + 1]);
+        let mut claimed = [false; SCREEN_WIDTH];
+        self.scanline_sprites
+            .extend(sprites.iter().map(|&index| index as u8));
+
+        for &index in sprites.iter() {
+            let entry = &oam[index * BYTES_PER_SPRITE..(index + 1) * BYTES_PER_SPRITE];
+            let top = entry[0] as i16 - SPRITE_Y_OFFSET;
+            let left = entry[1] as i16 - SPRITE_X_OFFSET;
+            // tall sprites ignore the low bit of the tile index, so the top
+            // half is the even tile and the bottom half the odd one after it
+            let tile = match height {
+                16 => entry[2] & 0xFE,
+                _ => entry[2],
+            } as u16;
+            let attributes = entry[3];
+
+            let mut row = line as i16 - top;
+            if attributes & SPRITE_Y_FLIP != 0 {
+                row = height - 1 - row;
+            }
+
+            let palette = match attributes & SPRITE_PALETTE {
+                0 => memory.obp0(),
+                _ => memory.obp1(),
+            };
+
+            let address = memory::TILE_RAM_START + tile * BYTES_PER_TILE + row as u16 * 2;
+            let low = memory.video_ram(address);
+            let high = memory.video_ram(address + 1);
+
+            for column in 0..8 {
+                let x = left + column;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) {
+                    continue;
+                }
+
+                let bit = match attributes & SPRITE_X_FLIP {
+                    0 => 7 - column,
+                    _ => column,
+                };
+                let color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+
+                // color 0 is transparent for sprites
+                let x = x as usize;
+                if color == 0 || claimed[x] {
+                    continue;
+                }
+                claimed[x] = true;
+
+                if self.sprite_visible(memory, attributes, x) {
+                    let shade = (palette >> (color * 2)) & 0b11;
+                    self.framebuffer[line as usize * SCREEN_WIDTH + x] = shade;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_timing_across_line_boundary() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_ENABLE);
+        let mut ppu = Ppu::new();
+
+        let mut transitions = Vec::new();
+        let mut mode = ppu.debug_state().mode;
+        for _ in 0..DOTS_PER_LINE + 1 {
+            ppu.step_dot(&mut memory);
+            let state = ppu.debug_state();
+            if state.mode != mode {
+                transitions.push((state.ly, state.dot, state.mode));
+                mode = state.mode;
+            }
+        }
+
+        // 80 dots of OAM scan, 172 of drawing and the rest in HBlank
+        assert_eq!(
+            transitions,
+            [
+                (0, 80, Mode::Drawing),
+                (0, 252, Mode::HBlank),
+                (1, 0, Mode::OamScan)
+            ]
+        );
+        assert_eq!(memory.ly(), 1);
+    }
+
+    #[test]
+    fn test_fetcher_x_follows_drawing() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_ENABLE);
+        let mut ppu = Ppu::new();
+
+        ppu.step(&mut memory, 90);
+        assert_eq!(ppu.debug_state().fetcher_x, 10);
+        ppu.step(&mut memory, 200);
+        assert_eq!(ppu.debug_state().mode, Mode::HBlank);
+        assert_eq!(ppu.debug_state().fetcher_x, SCREEN_WIDTH as u8);
+    }
+
+    fn run_frame(ppu: &mut Ppu, memory: &mut Memory) {
+        while !ppu.step(memory, 4) {}
+    }
+
+    #[test]
+    fn test_dirty_lines_marks_changed_scanline() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_ENABLE | LCDC_BACKGROUND_ENABLE | LCDC_TILE_DATA);
+        memory.set_bgp(0xE4);
+        let mut ppu = Ppu::new();
+
+        run_frame(&mut ppu, &mut memory);
+        assert!(ppu.dirty_lines().iter().all(|&dirty| dirty));
+        run_frame(&mut ppu, &mut memory);
+        assert!(ppu.dirty_lines().iter().all(|&dirty| !dirty));
+
+        // the top left map entry now uses tile 1, which only has pixels on
+        // its third row
+        let mut tile = [[0; 8]; 8];
+        tile[2] = [1; 8];
+        memory.set_tile(1, &tile);
+        memory.set_bg_map_entry(0, 0, 1);
+        run_frame(&mut ppu, &mut memory);
+
+        let dirty: Vec<usize> = (0..SCREEN_HEIGHT)
+            .filter(|&line| ppu.dirty_lines()[line])
+            .collect();
+        assert_eq!(dirty, [2]);
+    }
+
+    #[test]
+    fn test_framebuffer_formats() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_ENABLE | LCDC_BACKGROUND_ENABLE | LCDC_TILE_DATA);
+        memory.set_bgp(0xE4);
+        // the first row of tile 0 is colors 2, 1, 0, 0, 0, 0, 0, 0
+        memory.set_byte(memory::TILE_RAM_START, 0b0100_0000);
+        memory.set_byte(memory::TILE_RAM_START + 1, 0b1000_0000);
+        let mut ppu = Ppu::new();
+        run_frame(&mut ppu, &mut memory);
+
+        assert_eq!(ppu.framebuffer_indices()[..3], [2, 1, 0]);
+
+        let mut rgba = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        ppu.framebuffer_rgba(&mut rgba);
+        assert_eq!(rgba[..4], [0x55, 0x55, 0x55, 0xFF]);
+        assert_eq!(rgba[4..8], [0xAA, 0xAA, 0xAA, 0xFF]);
+        assert_eq!(rgba[8..12], [0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_shade_ramp_sets_rgb_levels() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_ENABLE | LCDC_BACKGROUND_ENABLE | LCDC_TILE_DATA);
+        memory.set_bgp(0xE4);
+        // every row of tile 0 is colors 0, 1, 2, 3, 0, 0, 0, 0
+        memory.set_tile(0, &[[0, 1, 2, 3, 0, 0, 0, 0]; 8]);
+        let mut ppu = Ppu::new();
+        ppu.set_shade_ramp([0xE0, 0xB0, 0x80, 0x40]);
+        run_frame(&mut ppu, &mut memory);
+
+        let mut rgba = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        ppu.framebuffer_rgba(&mut rgba);
+        assert_eq!(rgba[..4], [0xE0, 0xE0, 0xE0, 0xFF]);
+        assert_eq!(rgba[4..8], [0xB0, 0xB0, 0xB0, 0xFF]);
+        assert_eq!(rgba[8..12], [0x80, 0x80, 0x80, 0xFF]);
+        assert_eq!(rgba[12..16], [0x40, 0x40, 0x40, 0xFF]);
+
+        let image = ppu.export_frame_ppm();
+        let header_len = "P6\n160 144\n255\n".len();
+        assert_eq!(image[header_len + 9..header_len + 12], [0x40; 3]);
+        // the shades themselves don't change
+        assert_eq!(ppu.framebuffer_indices()[..4], [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_export_frame_ppm() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_ENABLE | LCDC_BACKGROUND_ENABLE | LCDC_TILE_DATA);
+        memory.set_bgp(0xE4);
+        // the first row of tile 0 is colors 3, 2, 1, 0, 3, 2, 1, 0
+        memory.set_byte(memory::TILE_RAM_START, 0b1010_1010);
+        memory.set_byte(memory::TILE_RAM_START + 1, 0b1100_1100);
+        let mut ppu = Ppu::new();
+        run_frame(&mut ppu, &mut memory);
+
+        let image = ppu.export_frame_ppm();
+        let header = b"P6\n160 144\n255\n";
+        assert_eq!(&image[..header.len()], header);
+        assert_eq!(image.len(), header.len() + SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+
+        let pixels = &image[header.len()..];
+        assert_eq!(pixels[0..3], [0x00, 0x00, 0x00]);
+        assert_eq!(pixels[3..6], [0x55, 0x55, 0x55]);
+        assert_eq!(pixels[9..12], [0xFF, 0xFF, 0xFF]);
+        // the second row of the tile is empty
+        let second_line = SCREEN_WIDTH * 3;
+        assert_eq!(pixels[second_line..second_line + 3], [0xFF, 0xFF, 0xFF]);
+    }
+
+    // places `count` sprites side by side on the first line, all using a
+    // tile that is entirely color 3
+    fn place_sprites(memory: &mut Memory, count: usize) {
+        let tile = 1;
+        for offset in 0..BYTES_PER_TILE {
+            memory.set_byte(
+                memory::TILE_RAM_START + tile * BYTES_PER_TILE + offset,
+                0xFF,
+            );
+        }
+        memory.set_obp0(0xE4);
+        memory.set_lcdc(memory.lcdc() | LCDC_SPRITE_ENABLE);
+
+        for index in 0..count {
+            let address = memory::OAM_START + (index * BYTES_PER_SPRITE) as u16;
+            memory.set_byte(address, SPRITE_Y_OFFSET as u8);
+            memory.set_byte(address + 1, (SPRITE_X_OFFSET as usize + index * 8) as u8);
+            memory.set_byte(address + 2, tile as u8);
+            memory.set_byte(address + 3, 0);
+        }
+    }
+
+    // runs the PPU for whole frames and then checks the top left corner of
+    // the framebuffer against a reference of palette indices `width` pixels
+    // wide, naming the first pixel that differs
+    fn assert_frame_matches(memory: &mut Memory, frames: usize, reference: &[u8], width: usize) {
+        let mut ppu = Ppu::new();
+        let dots = DOTS_PER_LINE as usize * LINES_PER_FRAME as usize;
+        for _ in 0..frames * dots / 4 {
+            ppu.step(memory, 4);
+        }
+
+        let framebuffer = ppu.framebuffer_indices();
+        for (index, &expected) in reference.iter().enumerate() {
+            let (x, y) = (index % width, index / width);
+            let actual = framebuffer[y * SCREEN_WIDTH + x];
+            assert_eq!(
+                actual, expected,
+                "pixel ({}, {}) is {} but the reference has {}",
+                x, y, actual, expected
+            );
+        }
+    }
+
+    fn drawn_pixels(ppu: &Ppu, line: usize) -> usize {
+        ppu.framebuffer_indices()[line * SCREEN_WIDTH..(line + 1) * SCREEN_WIDTH]
+            .iter()
+            .filter(|&&shade| shade != 0)
+            .count()
+    }
+
+    #[test]
+    fn test_mode_timing() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        memory.set_lcdc(LCDC_ENABLE);
+
+        ppu.step(&mut memory, (OAM_SCAN_DOTS - 1) as u8);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.step(&mut memory, 1);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        assert_eq!(memory.stat() & 0b11, Mode::Drawing as u8);
+        ppu.step(&mut memory, DRAWING_DOTS as u8);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+    }
+
+    // counts the dots the first line spends drawing
+    fn measure_drawing(memory: &mut Memory) -> u16 {
+        let mut ppu = Ppu::new();
+        memory.set_lcdc(memory.lcdc() | LCDC_ENABLE);
+        let mut dots = 0;
+        for _ in 0..DOTS_PER_LINE - 1 {
+            ppu.step_dot(memory);
+            if ppu.mode() == Mode::Drawing {
+                dots += 1;
+            }
+        }
+        dots
+    }
+
+    #[test]
+    fn test_fine_scroll_lengthens_drawing() {
+        let mut memory = Memory::new();
+        assert_eq!(measure_drawing(&mut memory), DRAWING_DOTS);
+
+        memory.set_scx(11);
+        assert_eq!(measure_drawing(&mut memory), DRAWING_DOTS + 3);
+    }
+
+    #[test]
+    fn test_window_lengthens_drawing() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_BACKGROUND_ENABLE | LCDC_WINDOW_ENABLE);
+        memory.set_wx(WINDOW_X_OFFSET + 20);
+
+        assert_eq!(
+            measure_drawing(&mut memory),
+            DRAWING_DOTS + WINDOW_PENALTY_DOTS
+        );
+    }
+
+    #[test]
+    fn test_sprites_lengthen_drawing() {
+        let mut memory = Memory::new();
+        memory.set_lcdc(LCDC_SPRITE_ENABLE);
+        // two sprites over the first tile, which only stalls for its fetch
+        // once, and one far enough into the next that its fetch is done
+        for (index, x) in [8, 9, 22].into_iter().enumerate() {
+            let address = memory::OAM_START + (index * BYTES_PER_SPRITE) as u16;
+            memory.set_byte(address, SPRITE_Y_OFFSET as u8);
+            memory.set_byte(address + 1, x);
+        }
+
+        assert_eq!(
+            measure_drawing(&mut memory),
+            DRAWING_DOTS + 3 * SPRITE_PENALTY_DOTS + SPRITE_TILE_PENALTY_DOTS
+        );
+    }
+
+    #[test]
+    fn test_disabled_sprites_are_skipped() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        place_sprites(&mut memory, 2);
+        assert!(measure_drawing(&mut memory) > DRAWING_DOTS);
+
+        memory.set_lcdc(memory.lcdc() & !LCDC_SPRITE_ENABLE);
+        ppu.render_scanline(&memory, 0);
+
+        assert_eq!(drawn_pixels(&ppu, 0), 0);
+        assert!(ppu.scanline_sprites().is_empty());
+        assert_eq!(measure_drawing(&mut memory), DRAWING_DOTS);
+    }
+
+    #[test]
+    fn test_vblank_requests_interrupt() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        memory.set_lcdc(LCDC_ENABLE);
+
+        let mut frames = 0;
+        for _ in 0..SCREEN_HEIGHT * DOTS_PER_LINE as usize / 4 {
+            if ppu.step(&mut memory, 4) {
+                frames += 1;
+            }
+        }
+
+        assert_eq!(frames, 1);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert_eq!(memory.ly(), SCREEN_HEIGHT as u8);
+        assert_eq!(memory.interrupt_flag() & memory::VBLANK_INTERRUPT, 1);
+    }
+
+    #[test]
+    fn test_single_tile_background_matches_reference() {
+        #[rustfmt::skip]
+        const REFERENCE: [u8; 16 * 8] = [
+            3, 3, 1, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 2, 2, 1, 1, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0,
+            3, 3, 1, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 2, 2, 1, 1, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0,
+            3, 3, 1, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 2, 2, 1, 1, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0,
+            3, 3, 1, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 2, 2, 1, 1, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+
+        let mut memory = Memory::new();
+        memory.set_bgp(0xE4);
+        // tile 1 alternates between two striped rows, the rest of the map
+        // is left on the blank tile 0
+        for row in 0..8 {
+            let (low, high) = match row % 2 {
+                0 => (0xF0, 0xCC),
+                _ => (0x0F, 0x33),
+            };
+            memory.set_byte(0x8010 + row * 2, low);
+            memory.set_byte(0x8010 + row * 2 + 1, high);
+        }
+        memory.set_byte(TILE_MAP_0_START, 1);
+        memory.set_lcdc(LCDC_ENABLE | LCDC_BACKGROUND_ENABLE | LCDC_TILE_DATA);
+
+        assert_frame_matches(&mut memory, 1, &REFERENCE, 16);
+    }
+
+    #[test]
+    fn test_signed_tile_data_address() {
+        let lcdc = LCDC_BACKGROUND_ENABLE;
+        assert_eq!(Ppu::tile_data_address(lcdc, 0x80), 0x8800);
+        assert_eq!(Ppu::tile_data_address(lcdc, 0xFF), 0x8FF0);
+        assert_eq!(Ppu::tile_data_address(lcdc, 0x00), 0x9000);
+        assert_eq!(Ppu::tile_data_address(lcdc, 0x7F), 0x97F0);
+    }
+
+    #[test]
+    fn test_unsigned_tile_data_address() {
+        let lcdc = LCDC_BACKGROUND_ENABLE | LCDC_TILE_DATA;
+        assert_eq!(Ppu::tile_data_address(lcdc, 0x00), 0x8000);
+        assert_eq!(Ppu::tile_data_address(lcdc, 0x80), 0x8800);
+        assert_eq!(Ppu::tile_data_address(lcdc, 0xFF), 0x8FF0);
+    }
+
+    #[test]
+    fn test_background_uses_signed_tile_data() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        memory.set_lcdc(LCDC_BACKGROUND_ENABLE);
+        memory.set_bgp(0xE4);
+        // the first tile on the map is tile 0x80, which is solid color 1
+        memory.set_byte(TILE_MAP_0_START, 0x80);
+        for row in 0..8 {
+            memory.set_byte(0x8800 + row * 2, 0xFF);
+        }
+
+        ppu.render_scanline(&memory, 0);
+
+        assert_eq!(ppu.framebuffer_indices()[0..8], [1; 8]);
+        assert_eq!(ppu.framebuffer_indices()[8], 0);
+    }
+
+    #[test]
+    fn test_window_line_only_advances_when_drawn() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        let lcdc = LCDC_BACKGROUND_ENABLE | LCDC_TILE_DATA | LCDC_WINDOW_MAP;
+        memory.set_bgp(0xE4);
+        memory.set_wy(0);
+        memory.set_wx(WINDOW_X_OFFSET);
+
+        // tile 1 is solid color 1 and tile 2 is solid color 2
+        for row in 0..8 {
+            memory.set_byte(0x8010 + row * 2, 0xFF);
+            memory.set_byte(0x8020 + row * 2 + 1, 0xFF);
+        }
+        // the first row of the window map uses tile 1 and the third tile 2
+        memory.set_byte(TILE_MAP_1_START, 1);
+        memory.set_byte(TILE_MAP_1_START + 2 * TILE_MAP_WIDTH, 2);
+
+        memory.set_lcdc(lcdc | LCDC_WINDOW_ENABLE);
+        for line in 0..4 {
+            ppu.render_scanline(&memory, line);
+        }
+        memory.set_lcdc(lcdc);
+        for line in 4..20 {
+            ppu.render_scanline(&memory, line);
+        }
+        memory.set_lcdc(lcdc | LCDC_WINDOW_ENABLE);
+        ppu.render_scanline(&memory, 20);
+
+        // the window picks up at its fifth row rather than at LY - WY
+        assert_eq!(ppu.framebuffer_indices()[20 * SCREEN_WIDTH], 1);
+        assert_eq!(ppu.framebuffer_indices()[10 * SCREEN_WIDTH], 0);
+    }
+
+    #[test]
+    fn test_tall_sprite_masks_tile_index() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        memory.set_lcdc(LCDC_SPRITE_ENABLE | LCDC_SPRITE_SIZE);
+        memory.set_obp0(0xE4);
+
+        // tile 2 is solid color 1 and tile 3 is solid color 2
+        for row in 0..8 {
+            memory.set_byte(memory::TILE_RAM_START + 2 * BYTES_PER_TILE + row * 2, 0xFF);
+            memory.set_byte(
+                memory::TILE_RAM_START + 3 * BYTES_PER_TILE + row * 2 + 1,
+                0xFF,
+            );
+        }
+        memory.set_byte(memory::OAM_START, SPRITE_Y_OFFSET as u8);
+        memory.set_byte(memory::OAM_START + 1, SPRITE_X_OFFSET as u8);
+        memory.set_byte(memory::OAM_START + 2, 3);
+
+        ppu.render_scanline(&memory, 0);
+        ppu.render_scanline(&memory, 8);
+
+        assert_eq!(ppu.framebuffer_indices()[0], 1);
+        assert_eq!(ppu.framebuffer_indices()[8 * SCREEN_WIDTH], 2);
+    }
+
+    #[test]
+    fn test_sprite_limit_caps_sprites_per_line() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        place_sprites(&mut memory, 12);
+
+        ppu.render_scanline(&memory, 0);
+
+        assert_eq!(drawn_pixels(&ppu, 0), 10 * 8);
+        assert_eq!(ppu.framebuffer_indices()[10 * 8], 0);
+    }
+
+    // draws a solid color 3 sprite at the top left with the priority bit set
+    // over a background whose first tile is solid `background`
+    fn render_priority_sprite(background: u8) -> Ppu {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        memory.set_lcdc(LCDC_TILE_DATA | LCDC_BACKGROUND_ENABLE | LCDC_SPRITE_ENABLE);
+        memory.set_bgp(0xE4);
+        memory.set_obp0(0xE4);
+
+        memory.set_tile(0, &[[background; 8]; 8]);
+        memory.set_tile(1, &[[3; 8]; 8]);
+        memory.set_bg_map_entry(0, 0, 0);
+
+        memory.set_byte(memory::OAM_START, SPRITE_Y_OFFSET as u8);
+        memory.set_byte(memory::OAM_START + 1, SPRITE_X_OFFSET as u8);
+        memory.set_byte(memory::OAM_START + 2, 1);
+        memory.set_byte(memory::OAM_START + 3, SPRITE_BEHIND_BACKGROUND);
+
+        ppu.render_scanline(&memory, 0);
+        ppu
+    }
+
+    #[test]
+    fn test_priority_sprite_hides_behind_background() {
+        let ppu = render_priority_sprite(2);
+        assert_eq!(ppu.framebuffer_indices()[0], 2);
+    }
+
+    #[test]
+    fn test_priority_sprite_shows_over_background_color_0() {
+        let ppu = render_priority_sprite(0);
+        assert_eq!(ppu.framebuffer_indices()[0], 3);
+    }
+
+    #[test]
+    fn test_hidden_sprite_still_covers_lower_sprites() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        memory.set_lcdc(LCDC_TILE_DATA | LCDC_BACKGROUND_ENABLE | LCDC_SPRITE_ENABLE);
+        memory.set_bgp(0xE4);
+        memory.set_obp0(0xE4);
+
+        memory.set_tile(0, &[[1; 8]; 8]);
+        memory.set_tile(1, &[[3; 8]; 8]);
+        // the first sprite wins the pixel but sits behind the background,
+        // so the second one must not show through it
+        for (index, flags) in [SPRITE_BEHIND_BACKGROUND, 0].into_iter().enumerate() {
+            let address = memory::OAM_START + (index * BYTES_PER_SPRITE) as u16;
+            memory.set_byte(address, SPRITE_Y_OFFSET as u8);
+            memory.set_byte(address + 1, SPRITE_X_OFFSET as u8);
+            memory.set_byte(address + 2, 1);
+            memory.set_byte(address + 3, flags);
+        }
+
+        ppu.render_scanline(&memory, 0);
+
+        assert_eq!(ppu.framebuffer_indices()[0], 1);
+    }
+
+    #[test]
+    fn test_scanline_sprites_follow_limit_and_x_order() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        place_sprites(&mut memory, 12);
+        // move sprite 3 left of everything and give sprite 5 the same x as
+        // sprite 4, which wins the tie by having the lower index
+        memory.set_byte(memory::OAM_START + 3 * BYTES_PER_SPRITE as u16 + 1, 0);
+        memory.set_byte(
+            memory::OAM_START + 5 * BYTES_PER_SPRITE as u16 + 1,
+            (SPRITE_X_OFFSET + 4 * 8) as u8,
+        );
+
+        ppu.render_scanline(&memory, 0);
+
+        assert_eq!(ppu.scanline_sprites(), [3, 0, 1, 2, 4, 5, 6, 7, 8, 9]);
+        ppu.render_scanline(&memory, 100);
+        assert!(ppu.scanline_sprites().is_empty());
+    }
+
+    #[test]
+    fn test_sprite_limit_disabled() {
+        let mut memory = Memory::new();
+        let mut ppu = Ppu::new();
+        ppu.set_sprite_limit(None);
+        place_sprites(&mut memory, 12);
+
+        ppu.render_scanline(&memory, 0);
+
+        assert_eq!(drawn_pixels(&ppu, 0), 12 * 8);
+    }
+}
@@ -0,0 +1,28 @@
+// OAM holds this many sprites, each described by this many bytes
+pub const SPRITE_COUNT: usize = 40;
+pub const BYTES_PER_SPRITE: usize = 4;
+
+// A single OAM entry. The coordinates are stored as the hardware keeps them,
+// offset by 16 vertically and 8 horizontally from the screen position
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sprite {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
+}
+
+impl Sprite {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Sprite {
+            y: bytes[0],
+            x: bytes[1],
+            tile: bytes[2],
+            flags: bytes[3],
+        }
+    }
+
+    pub fn to_bytes(self: &Self) -> [u8; BYTES_PER_SPRITE] {
+        [self.y, self.x, self.tile, self.flags]
+    }
+}
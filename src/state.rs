@@ -0,0 +1,229 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+// Save states are a flat list of fields that every component writes and
+// reads back in the same fixed order, behind a short header so states from
+// another version are rejected rather than misread
+const MAGIC: &[u8; 4] = b"GBST";
+const VERSION: u8 = 1;
+
+// Reasons a save state can't be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    // the data isn't a save state, or is one from another version
+    BadHeader,
+    // the data ends before every field has been read
+    Truncated,
+    // a field holds a value the emulator can't be put into, like a cartridge
+    // RAM size that doesn't match the loaded cartridge
+    InvalidValue,
+    // the data carries on after the last field
+    TrailingData,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadHeader => write!(f, "not a save state for this version"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::InvalidValue => write!(f, "save state holds an invalid value"),
+            StateError::TrailingData => write!(f, "save state has data after its last field"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateError {}
+
+#[cfg(feature = "std")]
+impl From<StateError> for std::io::Error {
+    fn from(error: StateError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        StateWriter { data }
+    }
+
+    pub fn write_u8(self: &mut Self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(self: &mut Self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(self: &mut Self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(self: &mut Self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(self: &mut Self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // for blocks whose size is fixed, so the reader already knows the length
+    pub fn write_bytes(self: &mut Self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // for blocks whose size varies, which are prefixed with their length
+    pub fn write_vec(self: &mut Self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn finish(self: Self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        let header_len = MAGIC.len() + 1;
+        if data.len() < header_len || &data[..MAGIC.len()] != MAGIC || data[MAGIC.len()] != VERSION
+        {
+            return Err(StateError::BadHeader);
+        }
+
+        Ok(StateReader {
+            data,
+            position: header_len,
+        })
+    }
+
+    fn take(self: &mut Self, len: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .ok_or(StateError::Truncated)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(self: &mut Self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(self: &mut Self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue),
+        }
+    }
+
+    pub fn read_u16(self: &mut Self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(self: &mut Self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(self: &mut Self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes(self: &mut Self, destination: &mut [u8]) -> Result<(), StateError> {
+        destination.copy_from_slice(self.take(destination.len())?);
+        Ok(())
+    }
+
+    pub fn read_vec(self: &mut Self) -> Result<Vec<u8>, StateError> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // checks that every byte of the state has been read
+    pub fn finish(self: Self) -> Result<(), StateError> {
+        if self.position != self.data.len() {
+            return Err(StateError::TrailingData);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bytes(&[1, 2]);
+        writer.write_vec(&[3, 4, 5]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        let mut bytes = [0; 2];
+        reader.read_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2]);
+        assert_eq!(reader.read_vec(), Ok(vec![3, 4, 5]));
+        assert_eq!(reader.read_u8(), Err(StateError::Truncated));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_finish_rejects_leftover_data() {
+        let mut writer = StateWriter::new();
+        writer.write_u16(0x1234);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        reader.read_u8().unwrap();
+        assert_eq!(reader.finish(), Err(StateError::TrailingData));
+    }
+
+    #[test]
+    fn test_rejects_other_data() {
+        assert!(matches!(
+            StateReader::new(b"GBST"),
+            Err(StateError::BadHeader)
+        ));
+        assert!(matches!(
+            StateReader::new(b"GBSV\x01"),
+            Err(StateError::BadHeader)
+        ));
+        assert!(matches!(
+            StateReader::new(&[b'G', b'B', b'S', b'T', VERSION + 1]),
+            Err(StateError::BadHeader)
+        ));
+    }
+}
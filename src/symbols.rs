@@ -0,0 +1,64 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+
+// Labels loaded from an RGBDS style .sym file, where every line looks like
+// `BB:AAAA Label` with the bank and address in hex
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    labels: BTreeMap<(u8, u16), String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            labels: BTreeMap::new(),
+        }
+    }
+
+    // comments start with ';', and lines that don't parse are skipped
+    pub fn load(self: &mut Self, sym: &str) {
+        for line in sym.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut fields = line.split_whitespace();
+            let (Some(location), Some(label)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let Some((bank, address)) = location.split_once(':') else {
+                continue;
+            };
+            if let (Ok(bank), Ok(address)) = (
+                u8::from_str_radix(bank, 16),
+                u16::from_str_radix(address, 16),
+            ) {
+                self.labels.insert((bank, address), label.to_string());
+            }
+        }
+    }
+
+    pub fn get(self: &Self, bank: u8, address: u16) -> Option<&str> {
+        self.labels.get(&(bank, address)).map(String::as_str)
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn clear(self: &mut Self) {
+        self.labels.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let mut symbols = SymbolTable::new();
+        symbols.load("; File generated by rgblink\n00:0150 Start\n02:4000 BankedRoutine ; comment\nbad line\n");
+
+        assert_eq!(symbols.get(0x00, 0x0150), Some("Start"));
+        assert_eq!(symbols.get(0x02, 0x4000), Some("BankedRoutine"));
+        assert_eq!(symbols.get(0x01, 0x4000), None);
+    }
+}
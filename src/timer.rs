@@ -0,0 +1,294 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Addresses of the timer registers
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+
+// Bits of the TAC register
+const TAC_ENABLE: u8 = 0b0000_0100;
+const TAC_CLOCK_SELECT: u8 = 0b0000_0011;
+// the unused upper bits of TAC always read as 1
+const TAC_UNUSED_BITS: u8 = 0b1111_1000;
+
+// the value of the internal counter on a DMG once the boot ROM hands over
+// to the cartridge, which leaves DIV reading 0xAB
+pub const DEFAULT_DIV_COUNTER: u16 = 0xABCC;
+
+// after overflowing, TIMA reads 0 for one machine cycle before TMA is loaded
+const RELOAD_DELAY: u8 = 4;
+
+#[derive(Clone)]
+pub struct Timer {
+    // DIV is the upper byte of this counter, which ticks every cycle
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // cycles left until TIMA is reloaded from TMA, Some only while TIMA
+    // reads 0 after overflowing
+    reload_delay: Option<u8>,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            counter: DEFAULT_DIV_COUNTER,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reload_delay: None,
+        }
+    }
+
+    pub fn div_counter(self: &Self) -> u16 {
+        self.counter
+    }
+
+    // pins the internal counter so DIV-derived behavior is reproducible
+    pub fn set_div_counter(self: &mut Self, value: u16) {
+        self.counter = value;
+    }
+
+    pub fn read(self: &Self, address: u16) -> u8 {
+        match address {
+            DIV => (self.counter >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => TAC_UNUSED_BITS | self.tac,
+            _ => panic!("{:#06X} is not a timer register", address),
+        }
+    }
+
+    pub fn write(self: &mut Self, address: u16, value: u8) {
+        match address {
+            // any write to DIV clears the whole counter, which can tick TIMA
+            // if the bit it watches was set
+            DIV => self.set_counter(0),
+            // writes that land while the reload is pending are lost
+            TIMA => {
+                if self.reload_delay.is_none() {
+                    self.tima = value;
+                }
+            }
+            TMA => self.tma = value,
+            TAC => {
+                let enabled = self.timer_signal();
+                self.tac = value & !TAC_UNUSED_BITS;
+                if enabled && !self.timer_signal() {
+                    self.increment_tima();
+                }
+            }
+            _ => panic!("{:#06X} is not a timer register", address),
+        }
+    }
+
+    pub fn save_state(self: &Self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tac);
+        // a pending reload always has at least one cycle left
+        writer.write_u8(self.reload_delay.unwrap_or(0));
+    }
+
+    pub fn load_state(self: &mut Self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.read_u16()?;
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()?;
+        self.reload_delay = match reader.read_u8()? {
+            0 => None,
+            delay => Some(delay),
+        };
+        Ok(())
+    }
+
+    // runs the timer for the given number of cycles, returning true if TIMA
+    // was reloaded and the timer interrupt should be requested
+    pub fn step(self: &mut Self, cycles: u8) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles {
+            interrupt |= self.step_reload();
+            self.set_counter(self.counter.wrapping_add(1));
+        }
+        interrupt
+    }
+
+    fn step_reload(self: &mut Self) -> bool {
+        let Some(delay) = self.reload_delay else {
+            return false;
+        };
+
+        if delay > 1 {
+            self.reload_delay = Some(delay - 1);
+            return false;
+        }
+        self.reload_delay = None;
+        self.tima = self.tma;
+        true
+    }
+
+    // TIMA ticks on the falling edge of the counter bit selected by TAC
+    fn counter_bit(self: &Self) -> u16 {
+        match self.tac & TAC_CLOCK_SELECT {
+            0b00 => 1 << 9,
+            0b01 => 1 << 3,
+            0b10 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+
+    fn timer_signal(self: &Self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.counter & self.counter_bit() != 0
+    }
+
+    fn set_counter(self: &mut Self, value: u16) {
+        let before = self.timer_signal();
+        self.counter = value;
+        if before && !self.timer_signal() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(self: &mut Self) {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflowed {
+            self.reload_delay = Some(RELOAD_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_div_follows_counter() {
+        let mut timer = Timer::new();
+        assert_eq!(timer.read(DIV), 0xAB);
+
+        timer.set_div_counter(0x12F0);
+        timer.step(0x20);
+        assert_eq!(timer.div_counter(), 0x1310);
+        assert_eq!(timer.read(DIV), 0x13);
+
+        timer.write(DIV, 0x55);
+        assert_eq!(timer.read(DIV), 0x00);
+    }
+
+    #[test]
+    fn test_tima_counts_at_selected_rate() {
+        let mut timer = Timer::new();
+        timer.set_div_counter(0);
+        // enabled, ticking every 16 cycles
+        timer.write(TAC, 0b101);
+
+        timer.step(16 * 3);
+        assert_eq!(timer.read(TIMA), 3);
+    }
+
+    #[test]
+    fn test_tima_cadence_for_each_frequency() {
+        for (clock_select, period) in [(0b00, 1024), (0b01, 16), (0b10, 64), (0b11, 256)] {
+            let mut timer = Timer::new();
+            timer.set_div_counter(0);
+            timer.write(TAC, TAC_ENABLE | clock_select);
+
+            for _ in 0..period - 1 {
+                timer.step(1);
+            }
+            assert_eq!(timer.read(TIMA), 0, "TAC {:#04b}", clock_select);
+            timer.step(1);
+            assert_eq!(timer.read(TIMA), 1, "TAC {:#04b}", clock_select);
+
+            for _ in 0..period {
+                timer.step(1);
+            }
+            assert_eq!(timer.read(TIMA), 2, "TAC {:#04b}", clock_select);
+        }
+    }
+
+    #[test]
+    fn test_div_write_ticks_tima_when_selected_bit_is_set() {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0b101);
+
+        // bit 3 is the one watched at this frequency
+        timer.set_div_counter(0b1000);
+        timer.write(DIV, 0x00);
+        assert_eq!(timer.read(TIMA), 1);
+
+        timer.set_div_counter(0b0111);
+        timer.write(DIV, 0x00);
+        assert_eq!(timer.read(TIMA), 1);
+    }
+
+    #[test]
+    fn test_tac_write_ticks_tima_when_signal_falls() {
+        let mut timer = Timer::new();
+        timer.set_div_counter(0b1000);
+        timer.write(TAC, 0b101);
+
+        // bit 5 is clear, so switching to it drops the signal
+        timer.write(TAC, 0b110);
+        assert_eq!(timer.read(TIMA), 1);
+
+        // so does turning the timer off while the selected bit is set
+        timer.set_div_counter(0b10_0000);
+        timer.write(TAC, 0b010);
+        assert_eq!(timer.read(TIMA), 2);
+    }
+
+    #[test]
+    fn test_disabled_timer_does_not_count() {
+        let mut timer = Timer::new();
+        timer.set_div_counter(0);
+        timer.write(TAC, 0b001);
+
+        timer.step(255);
+        assert_eq!(timer.read(TIMA), 0);
+    }
+
+    #[test]
+    fn test_tima_overflow_requests_interrupt() {
+        let mut timer = Timer::new();
+        timer.set_div_counter(0);
+        timer.write(TAC, 0b101);
+        timer.write(TMA, 0x80);
+        timer.write(TIMA, 0xFF);
+
+        assert!(!timer.step(16));
+        // TIMA reads 0 for a machine cycle before it's reloaded
+        assert_eq!(timer.read(TIMA), 0x00);
+        assert!(!timer.step(3));
+        assert_eq!(timer.read(TIMA), 0x00);
+
+        assert!(timer.step(1));
+        assert_eq!(timer.read(TIMA), 0x80);
+    }
+
+    #[test]
+    fn test_tima_write_during_reload_is_ignored() {
+        let mut timer = Timer::new();
+        timer.set_div_counter(0);
+        timer.write(TAC, 0b101);
+        timer.write(TMA, 0x80);
+        timer.write(TIMA, 0xFF);
+
+        timer.step(16);
+        timer.write(TIMA, 0x42);
+        assert_eq!(timer.read(TIMA), 0x00);
+
+        assert!(timer.step(4));
+        assert_eq!(timer.read(TIMA), 0x80);
+    }
+}
@@ -0,0 +1,18 @@
+// Exercises the core through APIs that are available without std, so that
+// `cargo test --no-default-features` checks the restricted configuration
+use gameboy::emulator::Emulator;
+use gameboy::memory::Memory;
+
+#[test]
+fn test_core_runs_without_std() {
+    let mut rom = vec![0; 0x8000];
+    // LD A,'!'; LDH (SB),A; LD A,0x81; LDH (SC),A; JR -2
+    let program = [0x3E, b'!', 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+
+    let mut memory = Memory::from_rom(rom).unwrap();
+    let mut emulator = Emulator::new(&mut memory);
+    emulator.run_frame().unwrap();
+
+    assert_eq!(emulator.take_serial_output(), b"!");
+}
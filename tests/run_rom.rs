@@ -0,0 +1,42 @@
+// the command line front-end needs std
+#![cfg(feature = "std")]
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+const ENTRY_POINT: usize = 0x100;
+
+// a ROM that prints "Hi" over the serial port and then spins forever
+fn serial_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    let program = [
+        0x3E, b'H', // LD A,'H'
+        0xE0, 0x01, // LDH (SB),A
+        0x3E, 0x81, // LD A,0x81
+        0xE0, 0x02, // LDH (SC),A
+        0x3E, b'i', // LD A,'i'
+        0xE0, 0x01, // LDH (SB),A
+        0x3E, 0x81, // LD A,0x81
+        0xE0, 0x02, // LDH (SC),A
+        0x18, 0xFE, // JR -2
+    ];
+    rom[ENTRY_POINT..ENTRY_POINT + program.len()].copy_from_slice(&program);
+    rom
+}
+
+#[test]
+fn test_runs_rom_and_prints_serial_output() {
+    let path = env::temp_dir().join(format!("gameboy-serial-{}.gb", std::process::id()));
+    fs::write(&path, serial_rom()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_gameboy"))
+        .arg(&path)
+        .args(["--max-frames", "2"])
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Hi");
+}